@@ -1,39 +1,123 @@
-use tokio::sync::Mutex;
+use std::{borrow::Cow, time::Instant};
 
+use reqwest::StatusCode;
+
+use rusqlite::Connection;
+use tokio::sync::{Mutex, MutexGuard};
+
+use secrecy::{ExposeSecret, SecretString};
 use serde::Serialize;
+use similar::{ChangeTag, TextDiff};
+use zeroize::Zeroizing;
 use tauri::State;
-use tauri_plugin_log::log::{debug, trace};
+use tauri_plugin_log::log::{debug, error, trace};
 
 use crate::{AppState, crypt, sync};
-use crate::crypt::NoteData;
+use crate::crypt::{AttachmentData, AttachmentMetadata, CryptError, NoteData, RecoveryKeyKind};
 use crate::db;
-use crate::db::schema::{Note, User};
+use crate::db::schema::{Attachment, AttachmentChunk, MekRotation, Note, NoteHistory, NoteKey, User};
 
-///Convert any error to string for frontend
+///Convert any error to string for frontend, `code` is stable and can be matched on
 #[derive(Debug, Serialize)]
 pub struct CommandError {
+    code: Cow<'static, str>,
     message: String,
 }
 
+impl CommandError {
+    fn new(message: &str) -> Self {
+        CommandError {
+            code: Cow::Borrowed("error"),
+            message: message.to_string(),
+        }
+    }
+}
+
 impl From<Box<dyn std::error::Error>> for CommandError {
     fn from(err: Box<dyn std::error::Error>) -> Self {
+        if let Some(crypt_error) = err.downcast_ref::<CryptError>() {
+            return CommandError::from(*crypt_error);
+        }
+
+        //Server errors with a body keep their code
+        match err.downcast_ref::<shared::ApiError>() {
+            Some(api_error) => CommandError {
+                code: Cow::Owned(api_error.code.clone()),
+                message: api_error.message.clone(),
+            },
+            None => CommandError::new(&err.to_string())
+        }
+    }
+}
+
+impl From<CryptError> for CommandError {
+    fn from(err: CryptError) -> Self {
+        CommandError {
+            code: Cow::Borrowed(err.code()),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<shared::UsernameError> for CommandError {
+    fn from(err: shared::UsernameError) -> Self {
         CommandError {
+            code: Cow::Borrowed(err.code()),
             message: err.to_string(),
         }
     }
 }
 
+/// Authentication failures the frontend has to react to get their own code
+fn auth_error(err: Box<dyn std::error::Error>) -> CommandError {
+    let code = if sync::is_status(&*err, StatusCode::PRECONDITION_REQUIRED) {
+        "totp_required"
+    } else if sync::is_status(&*err, StatusCode::UNAUTHORIZED) {
+        "invalid_credentials"
+    } else if sync::is_status(&*err, StatusCode::TOO_MANY_REQUESTS) {
+        "too_many_attempts"
+    } else {
+        return CommandError::from(err);
+    };
+
+    CommandError {
+        code: Cow::Borrowed(code),
+        message: err.to_string(),
+    }
+}
+
+/// Fetch the metadata of the instance and remember it.
+/// Incompatible instances are refused before any credential is sent to them.
+async fn fetch_instance(database: &Mutex<Connection>, instance: &str) -> Result<shared::InstanceInfo, CommandError> {
+    let info = sync::instance_info(instance.to_string()).await?;
+
+    if !info.is_compatible() {
+        return Err(CommandError {
+            code: Cow::Borrowed("incompatible_instance"),
+            message: format!("This instance uses protocol versions {} to {}, this app only supports version {}. Update the app or the server.",
+                info.protocol_min, info.protocol_max, shared::PROTOCOL_VERSION),
+        });
+    }
+
+    let conn = database.lock().await;
+    db::operations::save_instance(&conn, instance.to_string(), info.clone())?;
+
+    Ok(info)
+}
+
 #[derive(Debug, Serialize)]
 pub struct FilteredUser {
     pub id: u32,
     pub username: String,
+    pub recovery_backed_up: bool,
 }
 
 impl From<User> for FilteredUser {
     fn from(user: User) -> Self{
         FilteredUser {
             id: user.id.unwrap(),
-            username: user.username
+            username: user.username,
+            recovery_backed_up: user.recovery_backed_up
         }
     }
 }
@@ -55,6 +139,42 @@ impl From<Note> for NoteMetadata {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct NoteSnapshot {
+    pub id: u32,
+    pub id_note: u32,
+    pub title: String,
+    pub updated_at: i64,
+    pub taken_at: i64,
+}
+
+impl From<NoteHistory> for NoteSnapshot {
+    fn from(snapshot: NoteHistory) -> Self {
+        NoteSnapshot {
+            id: snapshot.id.unwrap(),
+            id_note: snapshot.id_note,
+            title: snapshot.title,
+            updated_at: snapshot.updated_at,
+            taken_at: snapshot.taken_at
+        }
+    }
+}
+
+/// Line of a diff between a snapshot and the current version, `tag` is equal, delete or insert
+#[derive(Serialize)]
+pub struct DiffLine {
+    pub tag: &'static str,
+    pub text: Zeroizing<String>,
+}
+
+/// Lock the state for a command of the user, the lock timeout counts from the last one
+async fn active_state<'a>(state: &'a State<'_, Mutex<AppState>>) -> MutexGuard<'a, AppState> {
+    let mut state = state.lock().await;
+    state.last_activity = Instant::now();
+
+    state
+}
+
 #[tauri::command]
 pub async fn init(state: State<'_, Mutex<AppState>>) -> Result<(), CommandError>  {
     let state = state.lock().await;
@@ -68,42 +188,337 @@ pub async fn init(state: State<'_, Mutex<AppState>>) -> Result<(), CommandError>
 
 #[tauri::command]
 pub async fn create_note(state: State<'_, Mutex<AppState>>, title: String) -> Result<(), CommandError> {
-    let state = state.lock().await;
+    let state = active_state(&state).await;
 
     let conn = state.database.lock().await;
 
-    let user = state.user.clone().unwrap();
+    let user = state.user.as_ref().unwrap();
 
-    db::operations::create_note(&conn, user.id.unwrap(), title, user.master_encryption_key).unwrap();
+    db::operations::create_note(&conn, user.id.unwrap(), title, user.mek()?)?;
 
     Ok(())
 }
 
 #[tauri::command]
 pub async fn get_note(state: State<'_, Mutex<AppState>>, id: u32) -> Result<NoteData, CommandError> {
-    let state = state.lock().await;
+    let state = active_state(&state).await;
 
     let conn = state.database.lock().await;
     
-    let note = db::operations::get_note(&conn, id, state.user.clone().unwrap().master_encryption_key).unwrap();
+    let note = db::operations::get_note(&conn, id, state.user.as_ref().unwrap().mek()?)?;
 
     Ok(note)
 }
 
 #[tauri::command]
 pub async fn edit_note(state: State<'_, Mutex<AppState>>, note: NoteData) -> Result<(), CommandError> {
-    let state = state.lock().await;
+    let state = active_state(&state).await;
 
     let conn = state.database.lock().await;
 
-    db::operations::update_note(&conn, note, state.user.clone().unwrap().master_encryption_key).unwrap();
+    db::operations::update_note(&conn, note, state.user.as_ref().unwrap().mek()?)?;
 
     Ok(())
 }
 
+/// Local snapshots of a note, most recent first
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_note_snapshots(state: State<'_, Mutex<AppState>>, id_note: u32) -> Result<Vec<NoteSnapshot>, CommandError> {
+    let state = active_state(&state).await;
+
+    let conn = state.database.lock().await;
+
+    let snapshots = db::operations::get_note_snapshots(&conn, id_note)?;
+
+    Ok(snapshots.into_iter().map(NoteSnapshot::from).collect())
+}
+
+/// Decrypted content of a snapshot, to preview it
+#[tauri::command]
+pub async fn get_note_snapshot(state: State<'_, Mutex<AppState>>, id: u32) -> Result<NoteData, CommandError> {
+    let state = active_state(&state).await;
+
+    let conn = state.database.lock().await;
+
+    Ok(db::operations::get_note_snapshot(&conn, id, state.user.as_ref().unwrap().mek()?)?)
+}
+
+/// Line diff from a snapshot to the current version of its note
+#[tauri::command]
+pub async fn diff_note_snapshot(state: State<'_, Mutex<AppState>>, id: u32) -> Result<Vec<DiffLine>, CommandError> {
+    let state = active_state(&state).await;
+
+    let conn = state.database.lock().await;
+    let mek = state.user.as_ref().unwrap().mek()?;
+
+    let snapshot = db::operations::get_note_snapshot(&conn, id, mek)?;
+    let current = db::operations::get_note(&conn, snapshot.id, mek)?;
+
+    let diff = TextDiff::from_lines(snapshot.content.as_str(), current.content.as_str());
+
+    let lines = diff.iter_all_changes().map(|change| DiffLine {
+        tag: match change.tag() {
+            ChangeTag::Equal => "equal",
+            ChangeTag::Delete => "delete",
+            ChangeTag::Insert => "insert"
+        },
+        text: Zeroizing::new(change.value().to_string())
+    }).collect();
+
+    Ok(lines)
+}
+
+/// Replace a note with one of its snapshots, return the restored note
+#[tauri::command]
+pub async fn restore_note_snapshot(state: State<'_, Mutex<AppState>>, id: u32) -> Result<NoteData, CommandError> {
+    let state = active_state(&state).await;
+
+    let mut conn = state.database.lock().await;
+
+    Ok(db::operations::restore_note_snapshot(&mut conn, id, state.user.as_ref().unwrap().mek()?)?)
+}
+
+/// Server id of a local note and the credentials of the user, for what is only kept by the server
+async fn note_source(state: &AppState, id_note: u32) -> Result<(u64, String, Vec<u8>, String), CommandError> {
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    let id_note_server = {
+        let conn = state.database.lock().await;
+        Note::select(&conn, id_note)?.and_then(|note| note.id_server)
+    };
+
+    match id_note_server {
+        Some(id) => Ok((id, user.username.clone(), token, instance)),
+        None => Err(CommandError::new("Note has never been synced"))
+    }
+}
+
+/// Previous versions of a note, most recent first
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_note_revisions(state: State<'_, Mutex<AppState>>, id_note: u32) -> Result<Vec<shared::NoteRevision>, CommandError> {
+    let state = active_state(&state).await;
+
+    let (id_note_server, username, token, instance) = note_source(&state, id_note).await?;
+
+    Ok(sync::select_note_revisions(username, token, id_note_server, instance).await?)
+}
+
+/// Decrypted content of a previous version of a note
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_note_revision(state: State<'_, Mutex<AppState>>, id_note: u32, id_revision: u64) -> Result<NoteData, CommandError> {
+    let state = active_state(&state).await;
+
+    let (id_note_server, username, token, instance) = note_source(&state, id_note).await?;
+
+    let revision = sync::select_note_revision(username, token, id_note_server, id_revision, instance).await?;
+
+    let mek = state.user.as_ref().unwrap().mek()?;
+    let conn = state.database.lock().await;
+
+    Ok(db::operations::decrypt_note_revision(&conn, id_note, revision, mek)?)
+}
+
+/// Replace a note with one of its previous versions, saved as a new edit so the current version becomes a revision
+#[tauri::command(rename_all = "snake_case")]
+pub async fn restore_note_revision(state: State<'_, Mutex<AppState>>, id_note: u32, id_revision: u64) -> Result<(), CommandError> {
+    let state = active_state(&state).await;
+
+    let (id_note_server, username, token, instance) = note_source(&state, id_note).await?;
+
+    let revision = sync::select_note_revision(username, token, id_note_server, id_revision, instance).await?;
+
+    let mek = state.user.as_ref().unwrap().mek()?;
+    let conn = state.database.lock().await;
+
+    let note = db::operations::decrypt_note_revision(&conn, id_note, revision, mek)?;
+
+    db::operations::update_note(&conn, note, mek)?;
+
+    debug!("note restored from revision {id_revision}");
+
+    Ok(())
+}
+
+/// Encrypt a note with a new key and share it with the users returned by `change`, return them.
+/// Only the owner of a synced note can change its shares.
+async fn update_note_shares(state: &AppState, id_note: u32, change: impl FnOnce(&mut Vec<shared::NoteShare>) -> Result<(), CommandError>) -> Result<Vec<shared::NoteShare>, CommandError> {
+    let (id_note_server, username, token, instance) = note_source(state, id_note).await?;
+
+    let user = state.user.as_ref().unwrap();
+    let mek = user.mek()?;
+
+    let (note, content, version, identity) = {
+        let conn = state.database.lock().await;
+
+        let note = Note::select(&conn, id_note)?.ok_or(CommandError::new("Note not found"))?;
+
+        //The new key is computed from the content known by the server
+        if !note.synched {
+            return Err(CommandError::new("Note must be synced before its shares change"));
+        }
+
+        let key = NoteKey::select(&conn, id_note)?;
+
+        if key.as_ref().is_some_and(|key| key.owner.is_some()) {
+            return Err(CommandError::new("Only the owner of a note can share it"));
+        }
+
+        let identity = db::operations::get_identity(&conn, user.id.unwrap())?
+            .ok_or(CommandError::new("Identity not created yet, the account must be synced first"))?;
+        let content = db::operations::get_note(&conn, id_note, mek)?.content;
+
+        (note, content, key.map_or(1, |key| key.version + 1), identity)
+    };
+
+    let mut shares = sync::select_note_shares(username.clone(), token.clone(), id_note_server, instance.clone()).await?;
+    change(&mut shares)?;
+
+    //Attachment keys are wrapped with the master encryption key, users the note is shared with couldn't read them
+    if !shares.is_empty() {
+        let conn = state.database.lock().await;
+
+        if !Attachment::select_all_from_note(&conn, id_note)?.is_empty() {
+            return Err(CommandError::new("A note with attachments can't be shared"));
+        }
+    }
+
+    //Revisions are kept by encrypting them with the new key, the server deletes the others
+    let listed = sync::select_note_revisions(username.clone(), token.clone(), id_note_server, instance.clone()).await?;
+    let mut revisions = Vec::with_capacity(listed.len());
+
+    for revision in listed {
+        revisions.push(sync::select_note_revision(username.clone(), token.clone(), id_note_server, revision.id, instance.clone()).await?);
+    }
+
+    //Users no longer in the shares don't get the new key
+    let key = crypt::create_note_key();
+    let mut grants = Vec::with_capacity(shares.len());
+
+    for share in &shares {
+        let recipient = sync::public_identity(username.clone(), token.clone(), &share.username, instance.clone()).await?;
+        let (encrypted_key, key_nonce) = crypt::share_note_key(&key, &identity, &recipient, id_note_server, version, mek)?;
+
+        grants.push(shared::ShareGrant {
+            username: recipient.username,
+            permission: share.permission,
+            encrypted_key,
+            key_nonce
+        });
+    }
+
+    let revisions = {
+        let conn = state.database.lock().await;
+
+        let mut rekeyed = Vec::with_capacity(revisions.len());
+
+        for revision in revisions {
+            rekeyed.extend(db::operations::reencrypt_note_revision(&conn, id_note, revision, &key, mek)?);
+        }
+
+        rekeyed
+    };
+
+    let (content, nonce) = crypt::encrypt_note(&content, &key)?;
+    let (encrypted_key, key_nonce) = crypt::wrap_key(&key, mek)?;
+
+    let rekey = shared::NoteRekey {
+        username,
+        token,
+        title: note.title,
+        content,
+        nonce,
+        updated_at: note.updated_at,
+        version,
+        encrypted_key,
+        key_nonce,
+        grants,
+        revisions: Some(revisions)
+    };
+
+    let rekeyed = sync::rekey_note(rekey, id_note_server, instance).await;
+
+    let rekeyed = rekeyed.map_err(|e| match sync::is_status(&*e, StatusCode::CONFLICT) {
+        true => CommandError {
+            code: Cow::Borrowed("note_conflict"),
+            message: "Note has been modified on another device, try again after the next sync".to_string(),
+        },
+        false => e.into()
+    })?;
+
+    let mut conn = state.database.lock().await;
+    db::operations::save_rekeyed_note(&mut conn, id_note, rekeyed)?;
+
+    Ok(shares)
+}
+
+/// Users a note is shared with
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_note_shares(state: State<'_, Mutex<AppState>>, id_note: u32) -> Result<Vec<shared::NoteShare>, CommandError> {
+    let state = active_state(&state).await;
+
+    let (id_note_server, username, token, instance) = note_source(&state, id_note).await?;
+
+    Ok(sync::select_note_shares(username, token, id_note_server, instance).await?)
+}
+
+/// Share a note with another user or change its permission, return the users it is shared with.
+/// The key of the note is renewed, the user gets it on its next sync.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn share_note(state: State<'_, Mutex<AppState>>, id_note: u32, username: String, permission: shared::SharePermission) -> Result<Vec<shared::NoteShare>, CommandError> {
+    let username = shared::normalize_username(&username)?;
+
+    let state = active_state(&state).await;
+
+    if state.user.as_ref().is_some_and(|user| user.username == username) {
+        return Err(CommandError::new("A note can't be shared with its owner"));
+    }
+
+    let shares = update_note_shares(&state, id_note, |shares| {
+        shares.retain(|share| share.username != username);
+        shares.push(shared::NoteShare { username, permission });
+
+        Ok(())
+    }).await?;
+
+    debug!("note {id_note} shared");
+
+    Ok(shares)
+}
+
+/// Stop sharing a note with a user, return the users it is still shared with.
+/// The note is encrypted with a new key so the user can't decrypt its next versions, the copy it already has can't be taken back.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn unshare_note(state: State<'_, Mutex<AppState>>, id_note: u32, username: String) -> Result<Vec<shared::NoteShare>, CommandError> {
+    let username = shared::normalize_username(&username)?;
+
+    let state = active_state(&state).await;
+
+    let shares = update_note_shares(&state, id_note, |shares| {
+        let count = shares.len();
+        shares.retain(|share| share.username != username);
+
+        match shares.len() < count {
+            true => Ok(()),
+            false => Err(CommandError::new("Note is not shared with this user"))
+        }
+    }).await?;
+
+    debug!("note {id_note} unshared");
+
+    Ok(shares)
+}
+
 #[tauri::command(rename_all = "snake_case")]
 pub async fn get_all_notes_metadata(state: State<'_, Mutex<AppState>>, id_user: u32) -> Result<Vec<NoteMetadata>, CommandError> {    
-    let state = state.lock().await;
+    let state = active_state(&state).await;
 
     let conn = state.database.lock().await;
 
@@ -114,20 +529,240 @@ pub async fn get_all_notes_metadata(state: State<'_, Mutex<AppState>>, id_user:
     Ok(notes_metadata)
 }
 
+/// Encrypt a file and attach it to the note, it is uploaded with the next sync
+#[tauri::command(rename_all = "snake_case")]
+pub async fn add_attachment(state: State<'_, Mutex<AppState>>, id_note: u32, name: String, media_type: String, data: Vec<u8>) -> Result<AttachmentData, CommandError> {
+    let data = Zeroizing::new(data);
+
+    let state = active_state(&state).await;
+
+    //Refused early when the last known usage shows it can't be uploaded, the server checks it again
+    if let Some(usage) = &state.usage {
+        if usage.quota.max_bytes.is_some_and(|max| usage.bytes + data.len() as u64 > max) {
+            return Err(CommandError {
+                code: Cow::Borrowed("quota_exceeded"),
+                message: "This attachment doesn't fit in the storage quota of the account".to_string(),
+            });
+        }
+    }
+
+    let mut conn = state.database.lock().await;
+
+    let metadata = AttachmentMetadata { name, media_type, size: data.len() as u64 };
+
+    Ok(db::operations::add_attachment(&mut conn, id_note, metadata, &data, state.user.as_ref().unwrap().mek()?)?)
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    #[serde(flatten)]
+    usage: shared::Usage,
+    near_quota: bool,
+}
+
+/// Storage used by the account on its instance and its quota
 #[tauri::command]
-pub async fn create_user(state: State<'_, Mutex<AppState>>, username: String) -> Result<(), CommandError> {
-    let mut state = state.lock().await;
+pub async fn get_usage(state: State<'_, Mutex<AppState>>) -> Result<UsageReport, CommandError> {
+    let mut state = active_state(&state).await;
+
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    let usage = sync::usage(user.username.clone(), token, instance).await?;
+    let near_quota = usage.is_near_quota();
+
+    state.usage = Some(usage);
+
+    Ok(UsageReport { usage, near_quota })
+}
+
+#[derive(Serialize)]
+pub struct SafetyNumber {
+    username: String,
+    fingerprint: String, //Fingerprint of the other user
+    safety_number: String, //Same number on both sides when nobody swapped the keys
+}
+
+/// Fingerprint of the identity of the user, it is created with the first sync
+#[tauri::command]
+pub async fn get_fingerprint(state: State<'_, Mutex<AppState>>) -> Result<String, CommandError> {
+    let state = active_state(&state).await;
+
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let conn = state.database.lock().await;
+
+    match db::operations::get_identity(&conn, user.id.unwrap())? {
+        Some(identity) => Ok(crypt::fingerprint(&user.username, &identity.ed25519_public, &identity.x25519_public)),
+        None => Err(CommandError::new("Identity not created yet, the account must be synced first"))
+    }
+}
+
+/// Safety number shared with another user, both read it to each other to verify their keys out of band
+#[tauri::command]
+pub async fn get_safety_number(state: State<'_, Mutex<AppState>>, username: String) -> Result<SafetyNumber, CommandError> {
+    let username = shared::normalize_username(&username)?;
+
+    let state = active_state(&state).await;
+
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    let own = {
+        let conn = state.database.lock().await;
+        db::operations::get_identity(&conn, user.id.unwrap())?
+    };
+    let own = own.ok_or(CommandError::new("Identity not created yet, the account must be synced first"))?;
+
+    let contact = sync::public_identity(user.username.clone(), token, &username, instance).await?;
+
+    let own_fingerprint = crypt::fingerprint(&user.username, &own.ed25519_public, &own.x25519_public);
+    let fingerprint = crypt::fingerprint(&contact.username, &contact.ed25519_public, &contact.x25519_public);
+
+    Ok(SafetyNumber {
+        safety_number: crypt::safety_number(&user.username, &own_fingerprint, &contact.username, &fingerprint),
+        username: contact.username,
+        fingerprint,
+    })
+}
+
+/// Attachments of a note, the list is refreshed from the server when the user is logged in
+#[tauri::command(rename_all = "snake_case")]
+pub async fn get_attachments(state: State<'_, Mutex<AppState>>, id_note: u32) -> Result<Vec<AttachmentData>, CommandError> {
+    let state = active_state(&state).await;
+
+    let user = state.user.as_ref().unwrap();
+
+    let id_note_server = {
+        let conn = state.database.lock().await;
+        Note::select(&conn, id_note)?.and_then(|note| note.id_server)
+    };
+
+    if let (Some(id_note_server), Some(token), Some(instance)) = (id_note_server, user.token.clone(), user.instance.clone()) {
+        let attachments = match sync::select_attachments(user.username.clone(), token, id_note_server, instance).await {
+            Ok(attachments) => Some(attachments),
+            Err(e) => {
+                //Offline, only the attachments already known are listed
+                error!("attachments could not be fetched: {e}");
+                None
+            }
+        };
+
+        if let Some(attachments) = attachments {
+            let mut conn = state.database.lock().await;
+            db::operations::merge_attachments(&mut conn, attachments)?;
+        }
+    }
+
+    let conn = state.database.lock().await;
+
+    Ok(db::operations::get_attachments(&conn, id_note, user.mek()?)?)
+}
+
+/// Decrypted content of an attachment, missing chunks are downloaded and kept locally
+#[tauri::command]
+pub async fn get_attachment(state: State<'_, Mutex<AppState>>, id: u32) -> Result<tauri::ipc::Response, CommandError> {
+    let state = active_state(&state).await;
+
+    let user = state.user.as_ref().unwrap();
+
+    let missing: Vec<AttachmentChunk> = {
+        let conn = state.database.lock().await;
+        AttachmentChunk::select_all(&conn, id)?.into_iter().filter(|chunk| chunk.data.is_none()).collect()
+    };
+
+    if !missing.is_empty() {
+        let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+            (Some(t), Some(i)) => (t, i),
+            _ => return Err(CommandError::new("User must be logged in to download the attachment"))
+        };
+
+        //Each chunk is stored as soon as it is received so an interrupted download isn't started again
+        for mut chunk in missing {
+            let data = sync::download_attachment_chunk(user.username.clone(), token.clone(), &chunk.hash, instance.clone()).await?;
+            chunk.data = Some(data);
+
+            let conn = state.database.lock().await;
+            chunk.update(&conn)?;
+        }
+
+        debug!("attachment {id} downloaded");
+    }
+
+    let conn = state.database.lock().await;
+    let data = db::operations::read_attachment(&conn, id, user.mek()?)?;
+
+    Ok(tauri::ipc::Response::new(data.to_vec()))
+}
+
+/// Delete an attachment, on the server as well once it has been uploaded
+#[tauri::command]
+pub async fn delete_attachment(state: State<'_, Mutex<AppState>>, id: u32) -> Result<(), CommandError> {
+    let state = active_state(&state).await;
+
+    let user = state.user.as_ref().unwrap();
+
+    let attachment = {
+        let conn = state.database.lock().await;
+        Attachment::select(&conn, id)?
+    };
+
+    let Some(attachment) = attachment else {
+        return Err(CommandError::new("Attachment not found"));
+    };
+
+    if let Some(id_server) = attachment.id_server {
+        let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+            (Some(t), Some(i)) => (t, i),
+            _ => return Err(CommandError::new("User must be logged in to delete the attachment"))
+        };
+
+        sync::delete_attachment(user.username.clone(), token, id_server, instance).await?;
+    }
+
+    let conn = state.database.lock().await;
+    Attachment::delete(&conn, id)?;
+
+    Ok(())
+}
+
+/// Create a local user and return its recovery key, it is only shown once and must be confirmed with `confirm_recovery_key`
+#[tauri::command(rename_all = "snake_case")]
+pub async fn create_user(state: State<'_, Mutex<AppState>>, username: String, unlock_secret: SecretString) -> Result<Zeroizing<String>, CommandError> {
+    let username = shared::normalize_username(&username)?;
+
+    let mut state = active_state(&state).await;
 
     let user = {
         let conn = state.database.lock().await;
-        db::operations::create_user(&conn, username).unwrap()
+        db::operations::create_user(&conn, username, &unlock_secret)?
     };
 
+    let recovery_key = Zeroizing::new(user.pending_recovery_keys[0].recovery_key.expose_secret().to_string());
+
     state.user = Some(user);
+    state.usage = None;
 
     debug!("user created");
     
-    Ok(())
+    Ok(recovery_key)
 }
 
 #[tauri::command]
@@ -145,7 +780,7 @@ pub async fn get_users(state: State<'_, Mutex<AppState>>) -> Result<Vec<Filtered
 
 #[tauri::command]
 pub async fn test(state: State<'_, Mutex<AppState>>) -> Result<(), CommandError> {
-    let state = state.lock().await;
+    let _state = state.lock().await;
     
     Ok(())
 }
@@ -158,81 +793,403 @@ pub async fn set_user(state: State<'_, Mutex<AppState>>, username: String) -> Re
         let conn = state.database.lock().await;
         match db::operations::get_user(&conn, username).unwrap() {
             Some(u) => u,
-            None => return Err(CommandError { message: "User doesn't exist".to_string() })
+            None => return Err(CommandError::new("User doesn't exist"))
         }
     };
 
     state.user = Some(user);
+    state.usage = None;
 
     Ok(())
 }
 
+/// Check an instance entered by the user, return its metadata (registration policy, limits...)
+#[tauri::command]
+pub async fn check_instance(state: State<'_, Mutex<AppState>>, instance: Option<String>) -> Result<shared::InstanceInfo, CommandError> {
+    let state = state.lock().await;
+
+    fetch_instance(&state.database, &sync::instance_url(instance)).await
+}
+
+/// Metadata remembered from the last check of the instance, without contacting it
+#[tauri::command]
+pub async fn get_instance(state: State<'_, Mutex<AppState>>, instance: Option<String>) -> Result<Option<shared::InstanceInfo>, CommandError> {
+    let state = state.lock().await;
+
+    let conn = state.database.lock().await;
+    let instance = db::operations::get_instance(&conn, &sync::instance_url(instance))?;
+
+    Ok(instance.map(|i| i.info))
+}
+
+/// Create the account on the server and return its recovery key, it is only shown once and must be confirmed with `confirm_recovery_key`
 #[tauri::command(rename_all = "snake_case")]
-pub async fn sync_create_account(state: State<'_, Mutex<AppState>>, username: String, password: String, instance: Option<String>) -> Result<(), CommandError> {
+pub async fn sync_create_account(state: State<'_, Mutex<AppState>>, username: String, password: SecretString, invite_code: Option<String>, instance: Option<String>) -> Result<Zeroizing<String>, CommandError> {
     trace!("create account command received");
+
+    let username = shared::normalize_username(&username)?;
+
+    let instance = sync::instance_url(instance);
     
-    let mut state = state.lock().await;
+    let mut state = active_state(&state).await;
+
+    fetch_instance(&state.database, &instance).await?;
     
-    let conn = state.database.lock().await;
-    let user = db::operations::get_user(&conn, username).unwrap().unwrap();
-    let account = crypt::create_account(password, state.user.clone().unwrap().master_encryption_key);
+    let (user, account) = {
+        let conn = state.database.lock().await;
+        let user = db::operations::get_user(&conn, username)?.ok_or(CommandError::new("User doesn't exist"))?;
+        let selected = state.user.as_ref().ok_or(CommandError::new("No user selected"))?;
+        let account = crypt::create_account(&password, selected.mek()?, &crypt::parse_kdf(&user.kdf_recovery_data)?)?;
+
+        (user, account)
+    };
+    let recovery_key = account.recovery_key_auth.clone();
+    let kdf_password = account.kdf_password.to_string();
     
     trace!("create account: start creating");
-    sync::create_account(user, account, instance).await;
+    let token = sync::create_account(&user, account, &password, invite_code, instance.clone()).await.map_err(auth_error)?;
     
     debug!("account has been created");
 
-    //TODO: send back recovery key to frontend
+    let returned_key = Zeroizing::new(recovery_key.expose_secret().to_string());
+
+    let AppState { database, user, .. } = &mut *state;
+    let user = user.as_mut().ok_or(CommandError::new("No user selected"))?;
+
+    db::operations::add_pending_recovery_key(user, RecoveryKeyKind::Auth, recovery_key);
+    user.kdf_password = Some(kdf_password);
+    user.token = Some(token);
+    user.instance = Some(instance);
+
+    let conn = database.lock().await;
+    db::operations::update_user(&conn, user);
     
-    Ok(())
+    Ok(returned_key)
 }
 
 #[tauri::command(rename_all = "snake_case")]
-pub async fn sync_login(state: State<'_, Mutex<AppState>>, username: String, password: String, instance: Option<String>) -> Result<bool, CommandError> {
+pub async fn sync_login(state: State<'_, Mutex<AppState>>, username: String, password: SecretString, totp_code: Option<String>, instance: Option<String>) -> Result<bool, CommandError> {
     trace!("login command received");
 
-    let mut state = state.lock().await;
+    let username = shared::normalize_username(&username)?;
 
-    let instance = match instance {
-        Some(i) => i,
-        None => "http://localhost:3000".to_string()
-    };
+    let mut state = active_state(&state).await;
+
+    let instance = sync::instance_url(instance);
 
-    let login_data = sync::login(username.clone(), password.clone(), instance.clone()).await;
+    fetch_instance(&state.database, &instance).await?;
+
+    let login_data = sync::login(username.clone(), &password, totp_code, instance.clone()).await.map_err(auth_error)?;
 
     debug!("account has been logged in");
 
-    let mut user = {
-        let conn = state.database.lock().await;
-        match db::operations::get_user(&conn, username).unwrap() {
-            Some(u) => u,
-            None => return Err(CommandError { message: "User doesn't exist".to_string() })
-        }
+    let AppState { database, user, .. } = &mut *state;
+
+    //User must be selected and unlocked to store the master encryption key
+    let user = match user {
+        Some(u) if u.username == username => u,
+        _ => return Err(CommandError::new("User doesn't exist"))
     };
 
     trace!("get user = ok");
 
-    //TODO: if !user.has_mek() then do not decrypt mek?
-    //TODO: handle if user account not created locally?
+    let mek = crypt::decrypt_mek(&password, login_data.encrypted_mek_password, login_data.salt_data, login_data.mek_password_nonce, &login_data.kdf_password)?;
+
+    trace!("master encryption key decrypted");
+
+    let mut kdf_password = login_data.kdf_password;
+
+    //Instance requires stronger key derivation: derive the password again, unless a rotation is using the current parameters
+    let has_rotation = {
+        let conn = database.lock().await;
+        MekRotation::select(&conn, user.id.unwrap())?.is_some()
+    };
+
+    if kdf_password.is_weaker_than(&login_data.min_kdf) && !has_rotation {
+        let upgraded = sync::upgrade_kdf(username.clone(), login_data.token.clone(), &password, &mek, &login_data.min_kdf, instance.clone()).await;
 
-    let mek = crypt::decrypt_mek(password, login_data.encrypted_mek_password, login_data.salt_data, login_data.mek_password_nonce);
+        match upgraded {
+            Ok(()) => {
+                debug!("key derivation parameters upgraded");
+                kdf_password = login_data.min_kdf;
+            },
+            Err(e) => error!("key derivation parameters upgrade failed: {e}")
+        }
+    }
 
-    trace!("mek encrypted");
+    //Master encryption key is different (rotated on another device or local user created before login), local notes must use the new one
+    if user.mek()?.expose_secret() != mek.expose_secret() {
+        let conn = database.lock().await;
+        db::operations::reencrypt_notes(&conn, user.id.unwrap(), user.mek()?, &mek)?;
+        db::operations::rewrap_identity_keys(&conn, user.id.unwrap(), user.mek()?, &mek)?;
+    }
     
-    user.master_encryption_key = mek;
+    db::operations::set_user_mek(user, mek)?;
     user.token = Some(login_data.token.clone());
     user.instance = Some(instance.clone());
-
-    state.user = Some(user.clone());
-
-    trace!("state modified");
+    user.kdf_password = Some(kdf_password.to_string());
 
     {
-        let conn = state.database.lock().await;
+        let conn = database.lock().await;
         db::operations::update_user(&conn, user);
     }
 
     trace!("user modified");
 
     Ok(true)
+}
+
+/// Rotate the master encryption key, every note is re-encrypted and other devices have to login again.
+/// Return the new recovery key.
+#[tauri::command]
+pub async fn rotate_mek(state: State<'_, Mutex<AppState>>, password: SecretString) -> Result<Zeroizing<String>, CommandError> {
+    let mut state = active_state(&state).await;
+
+    let (id_user, username, token, instance) = match state.user.as_ref() {
+        Some(User { id: Some(id), username, token: Some(t), instance: Some(i), .. }) => (*id, username.clone(), t.clone(), i.clone()),
+        Some(_) => return Err(CommandError::new("User must be logged in to rotate the master encryption key")),
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    {
+        let conn = state.database.lock().await;
+
+        if MekRotation::select(&conn, id_user)?.is_some() {
+            return Err(CommandError::new("A master encryption key rotation is already in progress"));
+        }
+    }
+
+    //The server checks the password before the new key is wrapped with it
+    let id_rotation = sync::start_mek_rotation(username, token, &password, instance).await.map_err(auth_error)?;
+
+    let recovery_key = {
+        let AppState { database, user, .. } = &mut *state;
+        let mut conn = database.lock().await;
+        let user = user.as_mut().unwrap();
+
+        //The rotation and the recovery key flag are saved together
+        let tx = conn.transaction().map_err(|e| CommandError::new(&e.to_string()))?;
+
+        let recovery_key = db::operations::create_mek_rotation(&tx, user, &password, id_rotation)?;
+        let returned_key = Zeroizing::new(recovery_key.expose_secret().to_string());
+
+        db::operations::add_pending_recovery_key(user, RecoveryKeyKind::Data, recovery_key);
+        user.update(&tx)?;
+
+        tx.commit().map_err(|e| CommandError::new(&e.to_string()))?;
+
+        returned_key
+    };
+
+    //If it fails, the sync service will resume it
+    if let Err(e) = sync::resume_mek_rotation(&mut state).await {
+        error!("master encryption key rotation interrupted: {e}");
+    }
+
+    Ok(recovery_key)
+}
+
+/// Delete the account of the selected user on its instance and return the deletion receipt.
+/// With `keep_local` the local profile and its notes are kept but detached from the instance, otherwise they are deleted too.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn delete_account(state: State<'_, Mutex<AppState>>, password: SecretString, keep_local: bool) -> Result<shared::DeletionReceipt, CommandError> {
+    let mut state = active_state(&state).await;
+
+    let AppState { database, user, usage, .. } = &mut *state;
+
+    let selected_user = match user.as_mut() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (selected_user.token.clone(), selected_user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    //Shared notes are encrypted again with the master encryption key when the profile is detached
+    if keep_local {
+        selected_user.mek()?;
+    }
+
+    let receipt = sync::delete_account(selected_user.username.clone(), token, &password, instance).await.map_err(auth_error)?;
+
+    debug!("account has been deleted");
+
+    *usage = None;
+
+    let mut conn = database.lock().await;
+
+    if keep_local {
+        db::operations::detach_user(&mut conn, selected_user)?;
+    } else {
+        db::operations::delete_user(&mut conn, selected_user)?;
+        *user = None;
+    }
+
+    Ok(receipt)
+}
+
+/// Start the two-factor authentication enrollment, the otpauth uri is shown as a QR code.
+/// It is enabled once `totp_confirm` is called with a code of the authenticator app.
+#[tauri::command]
+pub async fn totp_enroll(state: State<'_, Mutex<AppState>>) -> Result<shared::TotpEnrollment, CommandError> {
+    let state = active_state(&state).await;
+
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    Ok(sync::totp_enroll(user.username.clone(), token, instance).await?)
+}
+
+/// Enable two-factor authentication, return the backup codes. They are only shown once.
+#[tauri::command]
+pub async fn totp_confirm(state: State<'_, Mutex<AppState>>, code: String) -> Result<Zeroizing<Vec<String>>, CommandError> {
+    let state = active_state(&state).await;
+
+    let user = match state.user.as_ref() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    let (token, instance) = match (user.token.clone(), user.instance.clone()) {
+        (Some(t), Some(i)) => (t, i),
+        _ => return Err(CommandError::new("User must be logged in"))
+    };
+
+    let codes = sync::totp_confirm(user.username.clone(), token, code, instance).await.map_err(auth_error)?;
+
+    Ok(Zeroizing::new(codes))
+}
+
+/// Decrypt the master encryption key of the selected user with its unlock secret
+#[tauri::command(rename_all = "snake_case")]
+pub async fn unlock(state: State<'_, Mutex<AppState>>, unlock_secret: SecretString) -> Result<(), CommandError> {
+    let mut state = active_state(&state).await;
+    let AppState { database, user, .. } = &mut *state;
+
+    let user = match user.as_mut() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    db::operations::unlock_user(&mut *database.lock().await, user, &unlock_secret)?;
+
+    Ok(())
+}
+
+/// Wipe the master encryption key from memory, `unlock` must be called to read notes again
+#[tauri::command]
+pub async fn lock(state: State<'_, Mutex<AppState>>) -> Result<(), CommandError> {
+    let mut state = state.lock().await;
+
+    if let Some(user) = state.user.as_mut() {
+        db::operations::lock_user(user);
+    }
+
+    Ok(())
+}
+
+/// Set the inactivity delay before the app is locked, None to never lock automatically
+#[tauri::command]
+pub async fn set_lock_timeout(state: State<'_, Mutex<AppState>>, seconds: Option<u64>) -> Result<(), CommandError> {
+    let mut state = active_state(&state).await;
+
+    let AppState { database, user, .. } = &mut *state;
+
+    let user = match user {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    user.lock_timeout = seconds;
+
+    let conn = database.lock().await;
+    db::operations::update_user(&conn, user);
+
+    Ok(())
+}
+
+/// Positions (starting at 0) of the words of the pending recovery key the user has to type back
+#[tauri::command]
+pub async fn recovery_key_challenge(state: State<'_, Mutex<AppState>>, kind: RecoveryKeyKind) -> Result<Vec<usize>, CommandError> {
+    let mut state = active_state(&state).await;
+
+    let user = match state.user.as_mut() {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    Ok(db::operations::recovery_key_challenge(user, kind)?)
+}
+
+/// Check the words asked by `recovery_key_challenge`, the recovery key is then marked as backed up
+#[tauri::command]
+pub async fn confirm_recovery_key(state: State<'_, Mutex<AppState>>, kind: RecoveryKeyKind, words: Vec<SecretString>) -> Result<(), CommandError> {
+    let mut state = active_state(&state).await;
+
+    let AppState { database, user, .. } = &mut *state;
+
+    let user = match user {
+        Some(u) => u,
+        None => return Err(CommandError::new("No user selected"))
+    };
+
+    db::operations::confirm_recovery_key(user, kind, &words)?;
+
+    let conn = database.lock().await;
+    db::operations::update_user(&conn, user);
+
+    Ok(())
+}
+
+/// Check that a recovery key typed by the user is a valid BIP-39 phrase
+#[tauri::command(rename_all = "snake_case")]
+pub async fn validate_recovery_key(recovery_key: SecretString) -> Result<(), CommandError> {
+    crypt::parse_recovery_key(&recovery_key)?;
+
+    Ok(())
+}
+
+/// Rebuild a local user when the local database and the password are lost, only the data recovery key is needed, with the second factor when it is enabled.
+/// Every note is downloaded from the server.
+#[tauri::command(rename_all = "snake_case")]
+pub async fn recover_user(state: State<'_, Mutex<AppState>>, username: String, recovery_key: SecretString, unlock_secret: SecretString, totp_code: Option<String>, instance: Option<String>) -> Result<(), CommandError> {
+    trace!("recover user command received");
+
+    let username = shared::normalize_username(&username)?;
+
+    let recovery_key = crypt::parse_recovery_key(&recovery_key)?;
+
+    let instance = sync::instance_url(instance);
+
+    let mut state = active_state(&state).await;
+
+    fetch_instance(&state.database, &instance).await?;
+
+    let (recovery, mek, login) = sync::recover(username.clone(), &recovery_key, totp_code, instance.clone()).await.map_err(auth_error)?;
+
+    debug!("account has been recovered");
+
+    let user = {
+        let conn = state.database.lock().await;
+        db::operations::restore_user(&conn, username, &unlock_secret, mek, recovery, login, instance)?
+    };
+
+    state.user = Some(user);
+    state.usage = None;
+
+    //A restored user has no cursor yet, every note is downloaded
+    sync::service::receive_latest_notes(&state).await?;
+
+    debug!("notes have been restored");
+
+    Ok(())
 }
\ No newline at end of file
@@ -1,34 +1,126 @@
-use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce, aead::{Aead, Payload}, aes::Aes256};
+use std::{error::Error, fmt};
+
+use aes_gcm::{AeadCore, Aes256Gcm, Key, KeyInit, Nonce, aead::{Aead, Payload, consts::U12}, aes::Aes256};
 use argon2::{
-    Argon2, password_hash::{
+    Algorithm, Argon2, Params, Version, password_hash::{
         self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::{OsRng, RngCore}
     }
 };
 use bip39::Language;
 use chrono::{DateTime, NaiveDateTime, Utc};
+use secrecy::{ExposeSecret, SecretBox, SecretString};
 use serde::{Deserialize, Serialize};
 use serde_json::from_slice;
-use shared::LoginRequest;
+use ed25519_dalek::Signer;
+use sha2::{Digest, Sha256};
+use shared::{KdfAlgorithm, KdfParams, LoginRequest, REDACTED};
+use shared::opaque::{
+    CONTEXT, Suite,
+    opaque_ke::{
+        ClientLogin, ClientLoginFinishParameters, ClientRegistration, ClientRegistrationFinishParameters, CredentialResponse, Identifiers,
+        RegistrationResponse,
+    },
+};
 use tauri_plugin_log::log::{trace, debug, info};
+use zeroize::Zeroizing;
 
 use crate::db::schema;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Master encryption key, wiped from memory on drop and never cloned implicitly
+pub type MasterKey = SecretBox<[u8; 32]>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptError {
+    /// A key could not be decrypted, the password, recovery key or unlock secret is wrong
+    WrongKey,
+    /// A salt is not valid base64 (e.g. corrupted value sent by the server)
+    MalformedSalt,
+    /// A note or its nonce has been modified or is corrupted
+    TamperedCiphertext,
+    /// A decrypted note is not valid UTF-8
+    InvalidUtf8,
+    /// Argon2 refused to hash (invalid params or output length)
+    KeyDerivation,
+    /// Stored key derivation parameters can't be read
+    MalformedKdfParams,
+    /// Encryption or key generation failed
+    Encryption,
+    /// A recovery key typed by the user is not a valid BIP-39 phrase (unknown word, wrong length or checksum)
+    InvalidRecoveryKey,
+    /// Words typed to confirm a recovery key don't match it
+    RecoveryKeyMismatch,
+    /// Public keys of a user are malformed or not signed by its identity
+    InvalidIdentity,
+}
+
+impl CryptError {
+    /// Stable identifier sent to the frontend, must not change between versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            CryptError::WrongKey => "wrong_key",
+            CryptError::MalformedSalt => "malformed_salt",
+            CryptError::TamperedCiphertext => "tampered_ciphertext",
+            CryptError::InvalidUtf8 => "invalid_utf8",
+            CryptError::KeyDerivation => "key_derivation",
+            CryptError::MalformedKdfParams => "malformed_kdf_params",
+            CryptError::Encryption => "encryption",
+            CryptError::InvalidRecoveryKey => "invalid_recovery_key",
+            CryptError::RecoveryKeyMismatch => "recovery_key_mismatch",
+            CryptError::InvalidIdentity => "invalid_identity",
+        }
+    }
+}
+
+impl fmt::Display for CryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CryptError::WrongKey => "Wrong password or key",
+            CryptError::MalformedSalt => "Malformed salt",
+            CryptError::TamperedCiphertext => "Data is corrupted or has been tampered with",
+            CryptError::InvalidUtf8 => "Decrypted data is not valid UTF-8",
+            CryptError::KeyDerivation => "Key derivation failed",
+            CryptError::MalformedKdfParams => "Malformed key derivation parameters",
+            CryptError::Encryption => "Encryption failed",
+            CryptError::InvalidRecoveryKey => "Invalid recovery key",
+            CryptError::RecoveryKeyMismatch => "Recovery key words don't match",
+            CryptError::InvalidIdentity => "Invalid identity keys",
+        };
+
+        write!(f, "{message}")
+    }
+}
+
+impl Error for CryptError {}
+
+#[derive(Serialize, Deserialize)]
 pub struct NoteData {
     pub id: u32,
     pub title: String,
-    pub content: String,
+    pub content: Zeroizing<String>,
     pub updated_at: i64,
 }
 
-#[derive(Debug)]
+impl fmt::Debug for NoteData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoteData")
+            .field("id", &self.id)
+            .field("title", &self.title)
+            .field("content", &REDACTED)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
 pub struct AccountEncryptionData {
-    pub recovery_key_auth: String,
+    pub recovery_key_auth: SecretString,
+    pub kdf_password: KdfParams,
+    pub kdf_recovery_auth: KdfParams,
     pub salt_auth: SaltString,
     pub salt_data: SaltString,
     pub salt_recovery_auth: SaltString,
     pub salt_server_auth: SaltString,
     pub salt_server_recovery: SaltString,
+    pub salt_server_mek: SaltString,
 
     pub mek_password_nonce: Vec<u8>,
 
@@ -36,188 +128,883 @@ pub struct AccountEncryptionData {
 
     pub stored_password_hash: String,
     pub stored_recovery_hash: String,
+    pub stored_mek_hash: String,
+}
+
+impl fmt::Debug for AccountEncryptionData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountEncryptionData")
+            .field("recovery_key_auth", &self.recovery_key_auth)
+            .field("kdf_password", &self.kdf_password)
+            .field("kdf_recovery_auth", &self.kdf_recovery_auth)
+            .field("salt_auth", &self.salt_auth)
+            .field("salt_data", &self.salt_data)
+            .field("salt_recovery_auth", &self.salt_recovery_auth)
+            .field("salt_server_auth", &self.salt_server_auth)
+            .field("salt_server_recovery", &self.salt_server_recovery)
+            .field("salt_server_mek", &self.salt_server_mek)
+            .field("stored_password_hash", &REDACTED)
+            .field("stored_recovery_hash", &REDACTED)
+            .field("stored_mek_hash", &REDACTED)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Everything derived from the password, renewed when the key derivation parameters are raised
+pub struct PasswordEncryptionData {
+    pub kdf_password: KdfParams,
+    pub salt_auth: SaltString,
+    pub salt_server_auth: SaltString,
+    pub stored_password_hash: String,
+    pub salt_data: SaltString,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+}
+
+impl fmt::Debug for PasswordEncryptionData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordEncryptionData")
+            .field("kdf_password", &self.kdf_password)
+            .field("salt_auth", &self.salt_auth)
+            .field("salt_server_auth", &self.salt_server_auth)
+            .field("stored_password_hash", &REDACTED)
+            .field("salt_data", &self.salt_data)
+            .finish_non_exhaustive()
+    }
 }
 
 #[derive(Debug)]
 pub struct UserEncryptionData {
-    pub master_encryption_key: Key<Aes256Gcm>,
-    pub recovery_key_data: String,
+    pub master_encryption_key: MasterKey,
+    pub recovery_key_data: SecretString,
+    pub kdf_recovery_data: KdfParams,
     pub salt_recovery_data: SaltString,
     pub mek_recovery_nonce: Vec<u8>,
     pub encrypted_mek_recovery: Vec<u8>,
 }
 
-pub fn create_user() -> UserEncryptionData {
+#[derive(Debug)]
+pub struct MekRotationData {
+    pub master_encryption_key: MasterKey,
+    pub recovery_key_data: SecretString,
+    pub kdf_recovery_data: KdfParams,
+    pub salt_data: SaltString,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+    pub salt_recovery_data: SaltString,
+    pub encrypted_mek_recovery: Vec<u8>,
+    pub mek_recovery_nonce: Vec<u8>,
+}
+
+/// Number of words of a recovery key
+const RECOVERY_KEY_WORDS: usize = 24;
+/// Number of words asked back to the user to confirm a recovery key has been written down
+const RECOVERY_CHALLENGE_WORDS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryKeyKind {
+    Data, //Decrypts the master encryption key, generated with the local user
+    Auth, //Authenticates on the server, generated with the account
+}
+
+/// Recovery key shown once to the user, kept in memory until the user proved it has been written down
+#[derive(Debug)]
+pub struct PendingRecoveryKey {
+    pub kind: RecoveryKeyKind,
+    pub recovery_key: SecretString,
+    pub challenge: Vec<usize>, //Positions of the words asked back, empty until requested
+}
+
+/// Build a master encryption key from raw bytes, the bytes should be wiped by the caller
+pub fn master_key_from_slice(bytes: &[u8]) -> Result<MasterKey, CryptError> {
+    if bytes.len() != 32 {
+        return Err(CryptError::TamperedCiphertext);
+    }
+
+    Ok(SecretBox::init_with_mut(|key: &mut [u8; 32]| key.copy_from_slice(bytes)))
+}
+
+fn cipher(key: &MasterKey) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.expose_secret()))
+}
+
+fn nonce(bytes: &[u8]) -> Result<Nonce<U12>, CryptError> {
+    let nonce: [u8; 12] = bytes.try_into().map_err(|_| CryptError::TamperedCiphertext)?;
+
+    Ok(Nonce::from(nonce))
+}
+
+fn salt(salt: &str) -> Result<SaltString, CryptError> {
+    SaltString::from_b64(salt).map_err(|_| CryptError::MalformedSalt)
+}
+
+fn recovery_key() -> Result<SecretString, CryptError> {
+    let mnemonic = bip39::Mnemonic::generate_in(Language::English, RECOVERY_KEY_WORDS).map_err(|_| CryptError::Encryption)?;
+
+    Ok(mnemonic.to_string().into())
+}
+
+/// Parse a recovery key typed by the user, words are normalized and the BIP-39 checksum is verified
+pub fn parse_recovery_key(recovery_key: &SecretString) -> Result<SecretString, CryptError> {
+    let normalized = Zeroizing::new(recovery_key.expose_secret().to_lowercase());
+
+    let mnemonic = bip39::Mnemonic::parse_in_normalized(Language::English, &normalized)
+        .map_err(|_| CryptError::InvalidRecoveryKey)?;
+
+    if mnemonic.word_count() != RECOVERY_KEY_WORDS {
+        return Err(CryptError::InvalidRecoveryKey);
+    }
+
+    Ok(mnemonic.to_string().into())
+}
+
+/// Pick distinct word positions (starting at 0) of a recovery key to ask back to the user
+pub fn recovery_key_challenge() -> Vec<usize> {
+    let mut challenge = Vec::with_capacity(RECOVERY_CHALLENGE_WORDS);
+
+    while challenge.len() < RECOVERY_CHALLENGE_WORDS {
+        let position = OsRng.next_u32() as usize % RECOVERY_KEY_WORDS;
+
+        if !challenge.contains(&position) {
+            challenge.push(position);
+        }
+    }
+
+    challenge.sort_unstable();
+    challenge
+}
+
+/// Check the words typed by the user against the recovery key, in the order of the challenge
+pub fn check_recovery_key_words(recovery_key: &SecretString, challenge: &[usize], words: &[SecretString]) -> Result<(), CryptError> {
+    if challenge.is_empty() || challenge.len() != words.len() {
+        return Err(CryptError::RecoveryKeyMismatch);
+    }
+
+    let expected: Vec<&str> = recovery_key.expose_secret().split_whitespace().collect();
+
+    let matches = challenge.iter().zip(words).all(|(position, word)| {
+        expected.get(*position).is_some_and(|expected| word.expose_secret().trim().eq_ignore_ascii_case(expected))
+    });
+
+    match matches {
+        true => Ok(()),
+        false => Err(CryptError::RecoveryKeyMismatch)
+    }
+}
+
+/// Parameters used for new salts, accounts created with weaker ones are upgraded on login if the instance requires it
+pub fn default_kdf() -> KdfParams {
+    KdfParams::default()
+}
+
+/// Read key derivation parameters stored in the local database
+pub fn parse_kdf(kdf: &str) -> Result<KdfParams, CryptError> {
+    kdf.parse().map_err(|_| CryptError::MalformedKdfParams)
+}
+
+fn argon2(kdf: &KdfParams) -> Result<Argon2<'static>, CryptError> {
+    let algorithm = match kdf.algorithm {
+        KdfAlgorithm::Argon2d => Algorithm::Argon2d,
+        KdfAlgorithm::Argon2i => Algorithm::Argon2i,
+        KdfAlgorithm::Argon2id => Algorithm::Argon2id,
+    };
+
+    let params = Params::new(kdf.memory, kdf.iterations, kdf.parallelism, None).map_err(|_| CryptError::MalformedKdfParams)?;
+
+    Ok(Argon2::new(algorithm, Version::V0x13, params))
+}
+
+fn hash_password(kdf: &KdfParams, secret: &[u8], salt: &SaltString) -> Result<String, CryptError> {
+    let hash = argon2(kdf)?.hash_password(secret, salt).map_err(|_| CryptError::KeyDerivation)?;
+
+    Ok(hash.to_string())
+}
+
+/// Derive a 256 bits key from a secret, output is the same as the hash of `Argon2::hash_password`
+fn derive_key(secret: &[u8], salt: &SaltString, kdf: &KdfParams) -> Result<Zeroizing<[u8; 32]>, CryptError> {
+    let mut salt_bytes = [0u8; 64];
+    let salt_bytes = salt.as_salt().decode_b64(&mut salt_bytes).map_err(|_| CryptError::MalformedSalt)?;
+
+    let mut key = Zeroizing::new([0u8; 32]);
+
+    argon2(kdf)?
+        .hash_password_into(secret, salt_bytes, key.as_mut())
+        .map_err(|_| CryptError::KeyDerivation)?;
+
+    Ok(key)
+}
+
+pub fn create_user() -> Result<UserEncryptionData, CryptError> {
     //Generate encryption key
-    let master_encryption_key: Key<Aes256Gcm> = Aes256Gcm::generate_key(OsRng).into();
+    let master_encryption_key: MasterKey = SecretBox::init_with_mut(|key: &mut [u8; 32]| OsRng.fill_bytes(key));
 
     //Generate recovery keys for auth and data
-    let recovery_key_data = bip39::Mnemonic::generate_in(Language::English, 24)
-        .unwrap()
-        .to_string();
-
-    //Init AesGcm and Argon2
-    let argon2 = Argon2::default();
+    let recovery_key_data = recovery_key()?;
 
     //Generate needed salts
     let salt_recovery_data = SaltString::generate(&mut OsRng);
+    let kdf_recovery_data = default_kdf();
 
-    let recovery_hash_data = argon2
-        .hash_password(recovery_key_data.as_bytes(), &salt_recovery_data)
-        .unwrap();
-
-    let recovery_key_hash = recovery_hash_data.hash.unwrap();
+    let recovery_key_hash = derive_key(recovery_key_data.expose_secret().as_bytes(), &salt_recovery_data, &kdf_recovery_data)?;
 
-    let recovery_key = Key::<Aes256Gcm>::from_slice(recovery_key_hash.as_bytes());
+    let recovery_key = Key::<Aes256Gcm>::from_slice(recovery_key_hash.as_ref());
     let cipher = Aes256Gcm::new(recovery_key);
-    
+
     //Generate nonce for mek password/recovery
     let mek_recovery_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    
+
     //Generate hash for mek password and recovery
     let encrypted_mek_recovery = cipher
-    .encrypt(&mek_recovery_nonce, master_encryption_key.as_slice())
-    .unwrap();
+    .encrypt(&mek_recovery_nonce, master_encryption_key.expose_secret().as_slice())
+    .map_err(|_| CryptError::Encryption)?;
 
-    UserEncryptionData {
+    Ok(UserEncryptionData {
         master_encryption_key,
         recovery_key_data,
+        kdf_recovery_data,
         salt_recovery_data,
         mek_recovery_nonce:  mek_recovery_nonce.to_vec(),
         encrypted_mek_recovery,
-    }
+    })
 }
 
 
-pub fn create_account(password: String, mek: Key<Aes256Gcm>) -> AccountEncryptionData {
+/// `kdf_recovery_data` are the parameters of the local user recovery key, they are reused for the master encryption key hash
+pub fn create_account(password: &SecretString, mek: &MasterKey, kdf_recovery_data: &KdfParams) -> Result<AccountEncryptionData, CryptError> {
     //Generate recovery keys for auth and data
-    let recovery_key_auth = bip39::Mnemonic::generate_in(Language::English, 24)
-        .unwrap()
-        .to_string();
+    let recovery_key_auth = recovery_key()?;
 
-    //Init AesGcm and Argon2
-    let argon2 = Argon2::default();
+    let kdf_recovery_auth = default_kdf();
 
     //Generate needed salts
-    let salt_auth = SaltString::generate(&mut OsRng);
-    let salt_data = SaltString::generate(&mut OsRng);
     let salt_recovery_auth = SaltString::generate(&mut OsRng);
-    let salt_server_auth = SaltString::generate(&mut OsRng);
     let salt_server_recovery = SaltString::generate(&mut OsRng);
+    let salt_server_mek = SaltString::generate(&mut OsRng);
 
     //Generate hash for password and data
-    let password_hash_auth = argon2
-        .hash_password(password.as_bytes(), &salt_auth)
-        .unwrap()
-        .to_string();
-    let recovery_hash_auth = argon2
-        .hash_password(recovery_key_auth.as_bytes(), &salt_recovery_auth)
-        .unwrap()
-        .to_string();
-    let password_hash_data = argon2
-        .hash_password(password.as_bytes(), &salt_data)
-        .unwrap();
-
-    let password_key_hash = password_hash_data.hash.unwrap();
-
-    let password_key = Key::<Aes256Gcm>::from_slice(password_key_hash.as_bytes());
+    let password_data = encrypt_password(password, mek, &default_kdf())?;
+    let recovery_hash_auth = Zeroizing::new(hash_password(&kdf_recovery_auth, recovery_key_auth.expose_secret().as_bytes(), &salt_recovery_auth)?);
+
+    //Generate hashs for recovery stored on server
+    let stored_recovery_hash = hash_password(&kdf_recovery_auth, recovery_hash_auth.as_bytes(), &salt_server_recovery)?;
+    let stored_mek_hash = mek_hash(mek, &salt_server_mek, kdf_recovery_data)?;
+
+
+    Ok(AccountEncryptionData {
+        recovery_key_auth,
+        kdf_password: password_data.kdf_password,
+        kdf_recovery_auth,
+        salt_auth: password_data.salt_auth,
+        salt_data: password_data.salt_data,
+        salt_recovery_auth,
+        salt_server_auth: password_data.salt_server_auth,
+        salt_server_recovery,
+        salt_server_mek,
+        mek_password_nonce: password_data.mek_password_nonce,
+        encrypted_mek_password: password_data.encrypted_mek_password,
+        stored_password_hash: password_data.stored_password_hash,
+        stored_recovery_hash,
+        stored_mek_hash,
+    })
+}
+
+/// Derive everything depending on the password with new salts, used at account creation and to raise the parameters
+pub fn encrypt_password(password: &SecretString, mek: &MasterKey, kdf_password: &KdfParams) -> Result<PasswordEncryptionData, CryptError> {
+    let salt_auth = SaltString::generate(&mut OsRng);
+    let salt_server_auth = SaltString::generate(&mut OsRng);
+    let salt_data = SaltString::generate(&mut OsRng);
+
+    let password_hash_auth = Zeroizing::new(hash_password(kdf_password, password.expose_secret().as_bytes(), &salt_auth)?);
+    let stored_password_hash = hash_password(kdf_password, password_hash_auth.as_bytes(), &salt_server_auth)?;
+
+    let (encrypted_mek_password, mek_password_nonce) = encrypt_mek_password(password, &salt_data, mek, kdf_password)?;
+
+    Ok(PasswordEncryptionData {
+        kdf_password: kdf_password.clone(),
+        salt_auth,
+        salt_server_auth,
+        stored_password_hash,
+        salt_data,
+        encrypted_mek_password,
+        mek_password_nonce,
+    })
+}
+
+/// Encrypt the master encryption key with a key derived from the password
+fn encrypt_mek_password(password: &SecretString, salt_data: &SaltString, mek: &MasterKey, kdf_password: &KdfParams) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
+    let password_key_hash = derive_key(password.expose_secret().as_bytes(), salt_data, kdf_password)?;
+
+    let password_key = Key::<Aes256Gcm>::from_slice(password_key_hash.as_ref());
     let cipher = Aes256Gcm::new(password_key);
 
-    //Generate nonce for mek password/recovery
     let mek_password_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
 
-    //Generate hash for mek password and recovery
     let encrypted_mek_password = cipher
-        .encrypt(&mek_password_nonce, mek.as_slice())
-        .unwrap();
+        .encrypt(&mek_password_nonce, mek.expose_secret().as_slice())
+        .map_err(|_| CryptError::Encryption)?;
 
-    //Generate hashs for password and recovery stored on server
-    let stored_password_hash = argon2
-        .hash_password(password_hash_auth.as_bytes(), &salt_server_auth)
-        .unwrap()
-        .to_string();
-    let stored_recovery_hash = argon2
-        .hash_password(recovery_hash_auth.as_bytes(), &salt_server_recovery)
-        .unwrap()
-        .to_string();
+    Ok((encrypted_mek_password, mek_password_nonce.to_vec()))
+}
 
+/// Generate a new master encryption key, wrapped with the password and a new recovery key.
+/// `kdf_password` must be the parameters of the account, they are shared with the auth salts.
+pub fn rotate_mek(password: &SecretString, kdf_password: &KdfParams) -> Result<MekRotationData, CryptError> {
+    let user_encryption_data = create_user()?;
 
-    AccountEncryptionData {
-        recovery_key_auth,
-        salt_auth,
+    let salt_data = SaltString::generate(&mut OsRng);
+
+    let (encrypted_mek_password, mek_password_nonce) = encrypt_mek_password(password, &salt_data, &user_encryption_data.master_encryption_key, kdf_password)?;
+
+    Ok(MekRotationData {
+        master_encryption_key: user_encryption_data.master_encryption_key,
+        recovery_key_data: user_encryption_data.recovery_key_data,
+        kdf_recovery_data: user_encryption_data.kdf_recovery_data,
         salt_data,
-        salt_recovery_auth,
-        salt_server_auth,
-        salt_server_recovery,
-        mek_password_nonce: mek_password_nonce.to_vec(),
         encrypted_mek_password,
-        stored_password_hash,
-        stored_recovery_hash,
+        mek_password_nonce,
+        salt_recovery_data: user_encryption_data.salt_recovery_data,
+        encrypted_mek_recovery: user_encryption_data.encrypted_mek_recovery,
+        mek_recovery_nonce: user_encryption_data.mek_recovery_nonce,
+    })
+}
+
+/// Encrypt a key with another one, used to keep a pending master encryption key at rest
+pub fn wrap_key(key: &MasterKey, wrapping_key: &MasterKey) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let wrapped_key = cipher(wrapping_key)
+        .encrypt(&nonce, key.expose_secret().as_slice())
+        .map_err(|_| CryptError::Encryption)?;
+
+    Ok((wrapped_key, nonce.to_vec()))
+}
+
+pub fn unwrap_key(wrapped_key: &[u8], nonce: &[u8], wrapping_key: &MasterKey) -> Result<MasterKey, CryptError> {
+    let key = Zeroizing::new(cipher(wrapping_key)
+        .decrypt(&self::nonce(nonce)?, wrapped_key)
+        .map_err(|_| CryptError::WrongKey)?);
+
+    master_key_from_slice(&key)
+}
+
+/// Derive the key protecting the master encryption key at rest from the local unlock secret (passphrase or PIN)
+pub fn derive_local_key(secret: &SecretString, salt_local: &str, kdf_local: &KdfParams) -> Result<MasterKey, CryptError> {
+    let local_key = derive_key(secret.expose_secret().as_bytes(), &salt(salt_local)?, kdf_local)?;
+
+    master_key_from_slice(local_key.as_ref())
+}
+
+/// Name, media type and size of an attachment, encrypted with the attachment key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentMetadata {
+    pub name: String,
+    pub media_type: String,
+    pub size: u64,
+}
+
+/// Decrypted description of an attachment sent to the frontend, `downloaded` is false until every chunk is stored locally
+#[derive(Serialize, Debug)]
+pub struct AttachmentData {
+    pub id: u32,
+    #[serde(flatten)]
+    pub metadata: AttachmentMetadata,
+    pub downloaded: bool,
+}
+
+/// Attachment split into encrypted chunks, each chunk is addressed by the hex sha256 of its bytes
+pub struct EncryptedAttachment {
+    pub encrypted_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub metadata_nonce: Vec<u8>,
+    pub chunks: Vec<(String, Vec<u8>)>,
+}
+
+/// Encrypt a file with a new attachment key wrapped with the master encryption key.
+/// The position of a chunk is authenticated so chunks can't be reordered.
+pub fn encrypt_attachment(data: &[u8], metadata: &AttachmentMetadata, mek: &MasterKey) -> Result<EncryptedAttachment, CryptError> {
+    let key: MasterKey = SecretBox::init_with_mut(|key: &mut [u8; 32]| OsRng.fill_bytes(key));
+    let (encrypted_key, key_nonce) = wrap_key(&key, mek)?;
+
+    let metadata_nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let metadata = cipher(&key)
+        .encrypt(&metadata_nonce, serde_json::to_vec(metadata).map_err(|_| CryptError::Encryption)?.as_slice())
+        .map_err(|_| CryptError::Encryption)?;
+
+    let chunks = data.chunks(shared::ATTACHMENT_CHUNK_SIZE).enumerate().map(|(position, chunk)| {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = cipher(&key)
+            .encrypt(&nonce, Payload { msg: chunk, aad: &(position as u32).to_be_bytes() })
+            .map_err(|_| CryptError::Encryption)?;
+
+        //The nonce is stored in front of the chunk
+        let chunk = [nonce.as_slice(), &ciphertext].concat();
+
+        Ok((chunk_hash(&chunk), chunk))
+    }).collect::<Result<_, CryptError>>()?;
+
+    Ok(EncryptedAttachment {
+        encrypted_key,
+        key_nonce,
+        metadata,
+        metadata_nonce: metadata_nonce.to_vec(),
+        chunks,
+    })
+}
+
+/// Address of an encrypted chunk
+pub fn chunk_hash(chunk: &[u8]) -> String {
+    hex::encode(Sha256::digest(chunk))
+}
+
+pub fn decrypt_attachment_metadata(key: &MasterKey, metadata: &[u8], metadata_nonce: &[u8]) -> Result<AttachmentMetadata, CryptError> {
+    let plaintext = cipher(key)
+        .decrypt(&nonce(metadata_nonce)?, metadata)
+        .map_err(|_| CryptError::TamperedCiphertext)?;
+
+    serde_json::from_slice(&plaintext).map_err(|_| CryptError::TamperedCiphertext)
+}
+
+/// Check the chunk matches its hash and decrypt it
+pub fn decrypt_attachment_chunk(key: &MasterKey, position: u32, hash: &str, chunk: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptError> {
+    if chunk_hash(chunk) != hash || chunk.len() < 12 {
+        return Err(CryptError::TamperedCiphertext);
     }
+
+    let (chunk_nonce, ciphertext) = chunk.split_at(12);
+
+    let plaintext = cipher(key)
+        .decrypt(&nonce(chunk_nonce)?, Payload { msg: ciphertext, aad: &position.to_be_bytes() })
+        .map_err(|_| CryptError::TamperedCiphertext)?;
+
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Wrap a key again with another key, used for attachment keys when the master encryption key changes
+pub fn rewrap_key(wrapped_key: &[u8], nonce: &[u8], old_key: &MasterKey, new_key: &MasterKey) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
+    wrap_key(&unwrap_key(wrapped_key, nonce, old_key)?, new_key)
 }
 
-pub fn login(login_request: LoginRequest, password: String) -> String {
-    let argon2 = Argon2::default();
+/// Decrypt a note content with the old key and encrypt it with the new one
+pub fn reencrypt_note(content: &[u8], nonce: &[u8], old_mek: &MasterKey, new_mek: &MasterKey) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
+    let plaintext = Zeroizing::new(cipher(old_mek)
+        .decrypt(&self::nonce(nonce)?, content)
+        .map_err(|_| CryptError::TamperedCiphertext)?);
 
-    let salt_auth = SaltString::from_b64(&login_request.salt_auth).unwrap();
-    let salt_server_auth = SaltString::from_b64(&login_request.salt_server_auth).unwrap();
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
 
-    let password_hash_auth = argon2.hash_password(password.as_bytes(), &salt_auth)
-        .unwrap()
-        .to_string();
+    let ciphertext = cipher(new_mek)
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| CryptError::Encryption)?;
 
-    argon2.hash_password(password_hash_auth.as_bytes(), &salt_server_auth)
-        .unwrap()
-        .to_string()
+    Ok((ciphertext, nonce.to_vec()))
 }
 
-pub fn decrypt_mek(password: String, encrypted_mek_password: Vec<u8>, salt_data: String, mek_password_nonce: Vec<u8>) -> Key<Aes256Gcm> {
-    let argon2 = Argon2::default();
+pub fn login(login_request: LoginRequest, password: &SecretString) -> Result<String, CryptError> {
+    let kdf_password = &login_request.kdf_password;
 
-    let salt_data = SaltString::from_b64(&salt_data).unwrap();
+    let salt_auth = salt(&login_request.salt_auth)?;
+    let salt_server_auth = salt(&login_request.salt_server_auth)?;
 
-    let password_hash_data = argon2
-        .hash_password(password.as_bytes(), &salt_data)
-        .unwrap();
+    let password_hash_auth = Zeroizing::new(hash_password(kdf_password, password.expose_secret().as_bytes(), &salt_auth)?);
 
-    let password_key_hash = password_hash_data.hash.unwrap();
-    let password_key = Key::<Aes256Gcm>::from_slice(password_key_hash.as_bytes());
-    
-    let cipher = Aes256Gcm::new(password_key);
+    hash_password(kdf_password, password_hash_auth.as_bytes(), &salt_server_auth)
+}
+
+/// Start an OPAQUE login, the state is kept to finish it with the answer of the server
+pub fn opaque_login_start(password: &SecretString) -> Result<(ClientLogin<Suite>, Vec<u8>), CryptError> {
+    let start = ClientLogin::<Suite>::start(&mut OsRng, password.expose_secret().as_bytes())
+        .map_err(|_| CryptError::KeyDerivation)?;
+
+    Ok((start.state, start.message.serialize().to_vec()))
+}
+
+/// Finish an OPAQUE login, return the message proving the password to the server and the session key.
+/// `kdf_password` must be the parameters the password was registered with.
+/// Fails with `WrongKey` if the password is wrong or the account doesn't exist.
+pub fn opaque_login_finish(state: ClientLogin<Suite>, password: &SecretString, credential_response: &[u8], kdf_password: &KdfParams) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>), CryptError> {
+    let response = CredentialResponse::deserialize(credential_response).map_err(|_| CryptError::WrongKey)?;
+    let ksf = argon2(kdf_password)?;
+
+    let finish = state
+        .finish(&mut OsRng, password.expose_secret().as_bytes(), response, ClientLoginFinishParameters::new(Some(CONTEXT), Identifiers::default(), Some(&ksf)))
+        .map_err(|_| CryptError::WrongKey)?;
+
+    Ok((finish.message.serialize().to_vec(), Zeroizing::new(finish.session_key.to_vec())))
+}
+
+/// Start the registration of the password, the state is kept to finish it with the answer of the server
+pub fn opaque_registration_start(password: &SecretString) -> Result<(ClientRegistration<Suite>, Vec<u8>), CryptError> {
+    let start = ClientRegistration::<Suite>::start(&mut OsRng, password.expose_secret().as_bytes())
+        .map_err(|_| CryptError::KeyDerivation)?;
+
+    Ok((start.state, start.message.serialize().to_vec()))
+}
+
+/// Record uploaded to the server, the password can't be recovered from it.
+/// The password is stretched with `kdf_password`, the record must be registered again when they change.
+pub fn opaque_registration_finish(state: ClientRegistration<Suite>, password: &SecretString, registration_response: &[u8], kdf_password: &KdfParams) -> Result<Vec<u8>, CryptError> {
+    let response = RegistrationResponse::deserialize(registration_response).map_err(|_| CryptError::Encryption)?;
+    let ksf = argon2(kdf_password)?;
+
+    let finish = state
+        .finish(&mut OsRng, password.expose_secret().as_bytes(), response, ClientRegistrationFinishParameters::new(Identifiers::default(), Some(&ksf)))
+        .map_err(|_| CryptError::KeyDerivation)?;
+
+    Ok(finish.message.serialize().to_vec())
+}
+
+pub fn decrypt_mek(password: &SecretString, encrypted_mek_password: Vec<u8>, salt_data: String, mek_password_nonce: Vec<u8>, kdf_password: &KdfParams) -> Result<MasterKey, CryptError> {
+    let salt_data = salt(&salt_data)?;
+
+    let password_key_hash = derive_key(password.expose_secret().as_bytes(), &salt_data, kdf_password)?;
 
-    let mek_slice = cipher.decrypt(Nonce::from_slice(&mek_password_nonce), encrypted_mek_password.as_slice()).unwrap();
+    unwrap_key(&encrypted_mek_password, &mek_password_nonce, &master_key_from_slice(password_key_hash.as_ref())?)
+}
+
+/// Decrypt the master encryption key with the data recovery key, it must have been checked with `parse_recovery_key`
+pub fn decrypt_mek_recovery(recovery_key_data: &SecretString, encrypted_mek_recovery: &[u8], salt_recovery_data: &str, mek_recovery_nonce: &[u8], kdf_recovery_data: &KdfParams) -> Result<MasterKey, CryptError> {
+    let recovery_key_hash = derive_key(recovery_key_data.expose_secret().as_bytes(), &salt(salt_recovery_data)?, kdf_recovery_data)?;
 
-    let mek = Key::<Aes256Gcm>::from_slice(&mek_slice);
+    unwrap_key(encrypted_mek_recovery, mek_recovery_nonce, &master_key_from_slice(recovery_key_hash.as_ref())?)
+}
+
+/// Hash of the master encryption key stored on the server, sent back to login with the data recovery key
+pub fn mek_hash(mek: &MasterKey, salt_server_mek: &SaltString, kdf_recovery_data: &KdfParams) -> Result<String, CryptError> {
+    hash_password(kdf_recovery_data, mek.expose_secret(), salt_server_mek)
+}
 
-    mek.to_owned()
+/// Same as `mek_hash` with the salt sent by the server
+pub fn mek_hash_from_b64(mek: &MasterKey, salt_server_mek: &str, kdf_recovery_data: &KdfParams) -> Result<String, CryptError> {
+    mek_hash(mek, &salt(salt_server_mek)?, kdf_recovery_data)
 }
 
 pub fn encrypt_note(
-    content: String,
-    master_encryption_key: Key<Aes256Gcm>,
-) -> Result<(Vec<u8>, Vec<u8>), Box<dyn std::error::Error>> {
+    content: &str,
+    master_encryption_key: &MasterKey,
+) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
     //Encrypt
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
 
-    let cipher = Aes256Gcm::new(&master_encryption_key);
-
-    let ciphertext = cipher.encrypt(&nonce, content.as_bytes()).unwrap();
+    let ciphertext = cipher(master_encryption_key)
+        .encrypt(&nonce, content.as_bytes())
+        .map_err(|_| CryptError::Encryption)?;
 
     Ok((ciphertext, nonce.to_vec()))
 }
 
-pub fn decrypt_note(note: schema::Note, mek: Key<Aes256Gcm>) -> Result<NoteData, Box<dyn std::error::Error>> {
-    let nonce_array: [u8; 12] = note.nonce.try_into().expect("nonce must be 12 bytes");
-    let nonce = Nonce::from(nonce_array);
+pub fn decrypt_note(note: schema::Note, mek: &MasterKey) -> Result<NoteData, CryptError> {
+    let content = decrypt_note_content(&note.content, &note.nonce, mek)?;
 
-    let cipher = Aes256Gcm::new(&mek);
-    let plaintext = cipher.decrypt(&nonce, note.content.as_ref()).unwrap();
     let data_unser = NoteData {
         id: note.id.unwrap(),
         title: note.title,
-        content: String::from_utf8(plaintext).unwrap(),
+        content,
         updated_at: note.updated_at
     };
 
     Ok(data_unser)
 }
+
+/// Decrypt a revision of the local note `id`, revisions are encrypted like notes
+pub fn decrypt_note_revision(id: u32, revision: shared::NoteRevisionContent, mek: &MasterKey) -> Result<NoteData, CryptError> {
+    let content = decrypt_note_content(&revision.content, &revision.nonce, mek)?;
+
+    Ok(NoteData {
+        id,
+        title: revision.title,
+        content,
+        updated_at: revision.updated_at
+    })
+}
+
+/// Decrypt note content encrypted with `encrypt_note`, used for notes, their snapshots and their revisions
+pub fn decrypt_note_content(content: &[u8], note_nonce: &[u8], mek: &MasterKey) -> Result<Zeroizing<String>, CryptError> {
+    let nonce = nonce(note_nonce)?;
+
+    let plaintext = cipher(mek)
+        .decrypt(&nonce, content)
+        .map_err(|_| CryptError::TamperedCiphertext)?;
+    let content = String::from_utf8(plaintext).map_err(|e| {
+        //Wipe the invalid plaintext as well
+        drop(Zeroizing::new(e.into_bytes()));
+        CryptError::InvalidUtf8
+    })?;
+
+    Ok(Zeroizing::new(content))
+}
+
+/// Prefix of the signed X25519 public key, so the signature can't be used for anything else
+const IDENTITY_SIGNATURE_CONTEXT: &[u8] = b"notto identity x25519 v1";
+/// Digits of a fingerprint, shown in groups of 5
+const FINGERPRINT_DIGITS: usize = 30;
+
+/// Generate the identity key pairs of an account, the private keys are encrypted with the master encryption key
+pub fn create_identity(mek: &MasterKey) -> Result<shared::Identity, CryptError> {
+    let x25519 = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+    let ed25519 = ed25519_dalek::SigningKey::generate(&mut OsRng);
+
+    let x25519_public = x25519_dalek::PublicKey::from(&x25519).to_bytes();
+    let signature = ed25519.sign(&[IDENTITY_SIGNATURE_CONTEXT, &x25519_public].concat());
+
+    let mut keys = Zeroizing::new([0u8; 64]);
+    keys[..32].copy_from_slice(x25519.as_bytes());
+    keys[32..].copy_from_slice(ed25519.as_bytes());
+
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let encrypted_keys = cipher(mek)
+        .encrypt(&nonce, keys.as_slice())
+        .map_err(|_| CryptError::Encryption)?;
+
+    Ok(shared::Identity {
+        x25519_public: x25519_public.to_vec(),
+        ed25519_public: ed25519.verifying_key().to_bytes().to_vec(),
+        signature: signature.to_bytes().to_vec(),
+        encrypted_keys,
+        keys_nonce: nonce.to_vec()
+    })
+}
+
+/// Check the X25519 public key of a user has been signed by its Ed25519 key
+pub fn verify_identity(identity: &shared::PublicIdentity) -> Result<(), CryptError> {
+    let ed25519_public: [u8; 32] = identity.ed25519_public.as_slice().try_into().map_err(|_| CryptError::InvalidIdentity)?;
+    let signature: [u8; 64] = identity.signature.as_slice().try_into().map_err(|_| CryptError::InvalidIdentity)?;
+
+    if identity.x25519_public.len() != 32 {
+        return Err(CryptError::InvalidIdentity);
+    }
+
+    ed25519_dalek::VerifyingKey::from_bytes(&ed25519_public)
+        .map_err(|_| CryptError::InvalidIdentity)?
+        .verify_strict(&[IDENTITY_SIGNATURE_CONTEXT, &identity.x25519_public].concat(), &ed25519_dalek::Signature::from_bytes(&signature))
+        .map_err(|_| CryptError::InvalidIdentity)
+}
+
+/// Digits identifying the public keys of a user, compared out of band to make sure the server didn't swap them
+pub fn fingerprint(username: &str, ed25519_public: &[u8], x25519_public: &[u8]) -> String {
+    let hash = Sha256::new()
+        .chain_update(IDENTITY_SIGNATURE_CONTEXT)
+        .chain_update((username.len() as u32).to_be_bytes())
+        .chain_update(username.as_bytes())
+        .chain_update(ed25519_public)
+        .chain_update(x25519_public)
+        .finalize();
+
+    //Each group of 5 digits comes from 5 bytes of the hash
+    hash.chunks(5)
+        .take(FINGERPRINT_DIGITS / 5)
+        .map(|bytes| {
+            let value = bytes.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Fingerprints of two users in the same order on both sides, so they can read the same number to each other
+pub fn safety_number(username: &str, fingerprint: &str, other_username: &str, other_fingerprint: &str) -> String {
+    match username <= other_username {
+        true => format!("{fingerprint} {other_fingerprint}"),
+        false => format!("{other_fingerprint} {fingerprint}")
+    }
+}
+
+/// Prefix of the information bound to the keys wrapping a shared note key
+const NOTE_KEY_CONTEXT: &[u8] = b"notto note key v1";
+
+/// Generate the key a shared note is encrypted with, a new one is used every time its shares change
+pub fn create_note_key() -> MasterKey {
+    SecretBox::init_with_mut(|key: &mut [u8; 32]| OsRng.fill_bytes(key))
+}
+
+/// X25519 private key of an identity, decrypted with the master encryption key
+fn identity_secret(identity: &schema::Identity, mek: &MasterKey) -> Result<x25519_dalek::StaticSecret, CryptError> {
+    let keys = Zeroizing::new(cipher(mek)
+        .decrypt(&nonce(&identity.keys_nonce)?, identity.encrypted_keys.as_slice())
+        .map_err(|_| CryptError::WrongKey)?);
+
+    let mut secret = Zeroizing::new([0u8; 32]);
+    secret.copy_from_slice(keys.get(..32).ok_or(CryptError::TamperedCiphertext)?);
+
+    Ok(x25519_dalek::StaticSecret::from(*secret))
+}
+
+/// Key wrapping a note key between its owner and a recipient, agreed with their X25519 keys.
+/// It is bound to the note and the version of the key so a wrapped key can't be replayed on another note.
+fn share_wrapping_key(secret: &x25519_dalek::StaticSecret, public: &[u8], id_note: u64, version: u32) -> Result<MasterKey, CryptError> {
+    let public: [u8; 32] = public.try_into().map_err(|_| CryptError::InvalidIdentity)?;
+
+    let shared_secret = secret.diffie_hellman(&x25519_dalek::PublicKey::from(public));
+
+    //Low order public keys give a secret known by everyone
+    if !shared_secret.was_contributory() {
+        return Err(CryptError::InvalidIdentity);
+    }
+
+    let info = [NOTE_KEY_CONTEXT, &id_note.to_be_bytes(), &version.to_be_bytes()].concat();
+    let mut key = Zeroizing::new([0u8; 32]);
+
+    hkdf::Hkdf::<Sha256>::new(None, shared_secret.as_bytes())
+        .expand(&info, key.as_mut())
+        .map_err(|_| CryptError::KeyDerivation)?;
+
+    master_key_from_slice(key.as_ref())
+}
+
+/// Wrap a note key for a recipient, its identity must have been checked with `verify_identity`
+pub fn share_note_key(key: &MasterKey, identity: &schema::Identity, recipient: &shared::PublicIdentity, id_note: u64, version: u32, mek: &MasterKey) -> Result<(Vec<u8>, Vec<u8>), CryptError> {
+    let wrapping_key = share_wrapping_key(&identity_secret(identity, mek)?, &recipient.x25519_public, id_note, version)?;
+
+    wrap_key(key, &wrapping_key)
+}
+
+/// Unwrap a note key shared by its owner, refused if the identity of the owner is not signed
+pub fn unwrap_shared_note_key(encrypted_key: &[u8], key_nonce: &[u8], identity: &schema::Identity, owner: &shared::PublicIdentity, id_note: u64, version: u32, mek: &MasterKey) -> Result<MasterKey, CryptError> {
+    verify_identity(owner)?;
+
+    let wrapping_key = share_wrapping_key(&identity_secret(identity, mek)?, &owner.x25519_public, id_note, version)?;
+
+    unwrap_key(encrypted_key, key_nonce, &wrapping_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::opaque::opaque_ke::{
+        CredentialFinalization, CredentialRequest, RegistrationRequest, RegistrationUpload, ServerLogin, ServerLoginParameters, ServerRegistration,
+        ServerSetup,
+    };
+
+    use super::*;
+
+    /// Cheap parameters, the costs don't change what is tested
+    fn test_kdf() -> KdfParams {
+        KdfParams { memory: 64, iterations: 1, ..KdfParams::default() }
+    }
+
+    fn test_key() -> MasterKey {
+        SecretBox::init_with_mut(|key: &mut [u8; 32]| OsRng.fill_bytes(key))
+    }
+
+    #[test]
+    fn wrap_and_unwrap_key() {
+        let key = test_key();
+        let wrapping_key = test_key();
+
+        let (wrapped_key, nonce) = wrap_key(&key, &wrapping_key).unwrap();
+        let unwrapped_key = unwrap_key(&wrapped_key, &nonce, &wrapping_key).unwrap();
+
+        assert_eq!(unwrapped_key.expose_secret(), key.expose_secret());
+        assert_eq!(unwrap_key(&wrapped_key, &nonce, &test_key()).unwrap_err(), CryptError::WrongKey);
+    }
+
+    #[test]
+    fn unwrap_tampered_key() {
+        let wrapping_key = test_key();
+        let (mut wrapped_key, nonce) = wrap_key(&test_key(), &wrapping_key).unwrap();
+
+        wrapped_key[0] ^= 1;
+        assert_eq!(unwrap_key(&wrapped_key, &nonce, &wrapping_key).unwrap_err(), CryptError::WrongKey);
+        assert_eq!(unwrap_key(&wrapped_key, &nonce[1..], &wrapping_key).unwrap_err(), CryptError::TamperedCiphertext);
+    }
+
+    #[test]
+    fn unlock_with_wrong_secret() {
+        let mek = test_key();
+        let salt_local = SaltString::generate(&mut OsRng).to_string();
+
+        let local_key = derive_local_key(&SecretString::from("secret"), &salt_local, &test_kdf()).unwrap();
+        let (encrypted_mek, mek_nonce) = wrap_key(&mek, &local_key).unwrap();
+
+        //Same secret and salt give the same key
+        let local_key = derive_local_key(&SecretString::from("secret"), &salt_local, &test_kdf()).unwrap();
+        assert_eq!(unwrap_key(&encrypted_mek, &mek_nonce, &local_key).unwrap().expose_secret(), mek.expose_secret());
+
+        let wrong_key = derive_local_key(&SecretString::from("wrong"), &salt_local, &test_kdf()).unwrap();
+        assert_eq!(unwrap_key(&encrypted_mek, &mek_nonce, &wrong_key).unwrap_err(), CryptError::WrongKey);
+
+        assert_eq!(derive_local_key(&SecretString::from("secret"), "not a salt!", &test_kdf()).unwrap_err(), CryptError::MalformedSalt);
+    }
+
+    #[test]
+    fn upgrade_kdf() {
+        let mek = test_key();
+        let password = SecretString::from("password");
+        let login_request = |data: &PasswordEncryptionData| LoginRequest {
+            salt_auth: data.salt_auth.to_string(),
+            salt_server_auth: data.salt_server_auth.to_string(),
+            kdf_password: data.kdf_password.clone(),
+            opaque: None,
+        };
+
+        let old = encrypt_password(&password, &mek, &test_kdf()).unwrap();
+        assert_eq!(login(login_request(&old), &password).unwrap(), old.stored_password_hash);
+
+        let stronger_kdf = KdfParams { iterations: 2, ..test_kdf() };
+        let new = encrypt_password(&password, &mek, &stronger_kdf).unwrap();
+        assert_eq!(login(login_request(&new), &password).unwrap(), new.stored_password_hash);
+        assert_ne!(new.stored_password_hash, old.stored_password_hash);
+        assert_ne!(login(login_request(&new), &SecretString::from("wrong")).unwrap(), new.stored_password_hash);
+
+        //The key is only decrypted with the parameters it was encrypted with
+        let decrypt = |kdf: &KdfParams, password: &SecretString| decrypt_mek(password, new.encrypted_mek_password.clone(),
+            new.salt_data.to_string(), new.mek_password_nonce.clone(), kdf);
+
+        assert_eq!(decrypt(&stronger_kdf, &password).unwrap().expose_secret(), mek.expose_secret());
+        assert_eq!(decrypt(&test_kdf(), &password).unwrap_err(), CryptError::WrongKey);
+        assert_eq!(decrypt(&stronger_kdf, &SecretString::from("wrong")).unwrap_err(), CryptError::WrongKey);
+    }
+
+    /// Register the password the way the server does and start a login with it, return the server state and its answer
+    fn opaque_server_login(setup: &ServerSetup<Suite>, password: &SecretString, credential_request: &[u8]) -> (ServerLogin<Suite>, Vec<u8>) {
+        let (state, registration_request) = opaque_registration_start(password).unwrap();
+        let registration_response = ServerRegistration::start(setup, RegistrationRequest::deserialize(&registration_request).unwrap(), b"alice")
+            .unwrap().message.serialize().to_vec();
+        let upload = opaque_registration_finish(state, password, &registration_response, &test_kdf()).unwrap();
+        let record = ServerRegistration::finish(RegistrationUpload::deserialize(&upload).unwrap());
+
+        let start = ServerLogin::start(&mut OsRng, setup, Some(record), CredentialRequest::deserialize(credential_request).unwrap(), b"alice",
+            ServerLoginParameters { context: Some(CONTEXT), ..Default::default() }).unwrap();
+
+        (start.state, start.message.serialize().to_vec())
+    }
+
+    #[test]
+    fn opaque_login() {
+        let setup = ServerSetup::<Suite>::new(&mut OsRng);
+        let password = SecretString::from("password");
+
+        let (state, credential_request) = opaque_login_start(&password).unwrap();
+        let (server_state, credential_response) = opaque_server_login(&setup, &password, &credential_request);
+        let (finalization, session_key) = opaque_login_finish(state, &password, &credential_response, &test_kdf()).unwrap();
+
+        let server_session_key = server_state.finish(CredentialFinalization::deserialize(&finalization).unwrap(),
+            ServerLoginParameters { context: Some(CONTEXT), ..Default::default() }).unwrap().session_key;
+        assert_eq!(session_key.as_slice(), server_session_key.as_slice());
+
+        //A wrong password is noticed by the client before anything is sent
+        let (state, credential_request) = opaque_login_start(&SecretString::from("wrong")).unwrap();
+        let (_, credential_response) = opaque_server_login(&setup, &password, &credential_request);
+        assert_eq!(opaque_login_finish(state, &SecretString::from("wrong"), &credential_response, &test_kdf()).unwrap_err(), CryptError::WrongKey);
+
+        //The password is stretched with the parameters it was registered with
+        let (state, credential_request) = opaque_login_start(&password).unwrap();
+        let (_, credential_response) = opaque_server_login(&setup, &password, &credential_request);
+        let stronger_kdf = KdfParams { iterations: 2, ..test_kdf() };
+        assert_eq!(opaque_login_finish(state, &password, &credential_response, &stronger_kdf).unwrap_err(), CryptError::WrongKey);
+    }
+
+    #[test]
+    fn decrypt_tampered_note() {
+        let mek = test_key();
+        let (mut content, nonce) = encrypt_note("content", &mek).unwrap();
+
+        assert_eq!(decrypt_note_content(&content, &nonce, &mek).unwrap().as_str(), "content");
+        assert_eq!(decrypt_note_content(&content, &nonce, &test_key()).unwrap_err(), CryptError::TamperedCiphertext);
+
+        content[0] ^= 1;
+        assert_eq!(decrypt_note_content(&content, &nonce, &mek).unwrap_err(), CryptError::TamperedCiphertext);
+    }
+}
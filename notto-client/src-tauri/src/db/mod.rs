@@ -3,22 +3,214 @@ use std::{path::PathBuf};
 use tokio::sync::Mutex;
 
 use rusqlite::Connection;
-use tauri_plugin_log::log::{debug, trace};
+use tauri_plugin_log::log::{debug, info, trace};
+
+use crate::crypt;
 
 pub mod operations;
 pub mod schema;
 
+/// Version of the local database, stored in `PRAGMA user_version` and raised by `migrate`
+const SCHEMA_VERSION: u32 = 2;
+
 pub fn init(db_path: PathBuf) -> Result<Mutex<Connection>, Box<dyn std::error::Error>> {
     debug!("creating/opening database at {db_path:?}");
-    let conn = Connection::open(db_path).unwrap();
+    let mut conn = Connection::open(db_path).unwrap();
     trace!("db create correctly: {conn:?}");
-    
+
+    setup(&mut conn)?;
+
+    Ok(Mutex::new(conn))
+}
+
+/// Migrate the database and create the missing tables
+fn setup(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
     conn.execute("PRAGMA foreign_keys = ON", []).unwrap();
-    
+
+    migrate(conn)?;
+
     // Create tables
-    schema::Note::create(&conn)?;
-    schema::User::create(&conn)?;
+    schema::Note::create(conn)?;
+    schema::NoteHistory::create(conn)?;
+    schema::User::create(conn)?;
+    schema::MekRotation::create(conn)?;
+    schema::Instance::create(conn)?;
+    schema::SyncCursor::create(conn)?;
+    schema::Attachment::create(conn)?;
+    schema::Identity::create(conn)?;
+    schema::NoteKey::create(conn)?;
     trace!("Tables have been created correctly");
 
-    Ok(Mutex::new(conn))
-}
\ No newline at end of file
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+    Ok(())
+}
+
+fn has_table(conn: &Connection, table: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(conn.query_one("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?", (table,), |row| row.get::<_, u32>(0))? > 0)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(conn.query_one("SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2", (table, column), |row| row.get::<_, u32>(0))? > 0)
+}
+
+/// Add a column to a table created by an older version, `definition` must give a default to NOT NULL columns
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !has_column(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"), ())?;
+    }
+
+    Ok(())
+}
+
+/// Bring a database created by an older version to the current schema, in one transaction.
+/// Tables that don't exist yet are created afterwards by `setup`.
+fn migrate(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+
+    if version < 1 && has_table(&tx, "user")? {
+        migrate_users(&tx)?;
+    }
+
+    if version < 2 && has_table(&tx, "note")? {
+        add_column(&tx, "note", "idempotency_key", "TEXT")?;
+    }
+
+    tx.commit()?;
+
+    info!("database migrated from version {version} to {SCHEMA_VERSION}");
+    Ok(())
+}
+
+/// Databases created before the master encryption key was encrypted at rest, and before the recovery key
+/// confirmation and the key derivation parameters were stored.
+/// The plain text key is kept in `legacy_mek` until the first unlock wraps it with the local key, see `operations::unlock_user`.
+fn migrate_users(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    //Accounts created before the parameters were stored used the defaults
+    let default_kdf = crypt::default_kdf().to_string();
+
+    if has_column(conn, "user", "master_encryption_key")? {
+        conn.execute("ALTER TABLE user RENAME COLUMN master_encryption_key TO legacy_mek", ())?;
+    } else {
+        add_column(conn, "user", "legacy_mek", "BLOB")?;
+    }
+
+    add_column(conn, "user", "encrypted_mek", "BLOB NOT NULL DEFAULT x''")?;
+    add_column(conn, "user", "mek_nonce", "BLOB NOT NULL DEFAULT x''")?;
+    add_column(conn, "user", "salt_local", "TEXT NOT NULL DEFAULT ''")?;
+    add_column(conn, "user", "lock_timeout", "INTEGER")?;
+    add_column(conn, "user", "kdf_local", &format!("TEXT NOT NULL DEFAULT '{default_kdf}'"))?;
+    add_column(conn, "user", "kdf_recovery_data", &format!("TEXT NOT NULL DEFAULT '{default_kdf}'"))?;
+
+    if !has_column(conn, "user", "recovery_backed_up")? {
+        add_column(conn, "user", "recovery_backed_up", "INTEGER NOT NULL DEFAULT 0")?;
+
+        //Recovery keys of existing users were shown when they were created and can't be shown again
+        conn.execute("UPDATE user SET recovery_backed_up = 1", ())?;
+    }
+
+    if !has_column(conn, "user", "kdf_password")? {
+        add_column(conn, "user", "kdf_password", "TEXT")?;
+
+        conn.execute("UPDATE user SET kdf_password = ? WHERE token IS NOT NULL", (&default_kdf,))?;
+    }
+
+    if has_table(conn, "mek_rotation")? {
+        add_column(conn, "mek_rotation", "kdf_recovery_data", &format!("TEXT NOT NULL DEFAULT '{default_kdf}'"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::{ExposeSecret, SecretString};
+
+    use super::*;
+    use crate::db::{operations, schema::User};
+
+    /// Database as created before the master encryption key was encrypted at rest
+    fn legacy_database(mek: &[u8]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute(
+            "CREATE TABLE user (
+                id INTEGER PRIMARY KEY,
+                username TEXT,
+                master_encryption_key BLOB,
+                salt_recovery_data TEXT,
+                mek_recovery_nonce BLOB,
+                encrypted_mek_recovery BLOB,
+                token TEXT,
+                instance TEXT
+            )", ()).unwrap();
+        conn.execute("INSERT INTO user (username, master_encryption_key, salt_recovery_data, mek_recovery_nonce, encrypted_mek_recovery, token, instance)
+            VALUES ('alice', ?, 'salt', x'00', x'00', x'0102', 'http://localhost')", (mek,)).unwrap();
+
+        conn
+    }
+
+    #[test]
+    fn migrate_legacy_database() {
+        let mek = [7u8; 32];
+        let mut conn = legacy_database(&mek);
+
+        setup(&mut conn).unwrap();
+
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+
+        let mut user = User::select(&conn, "alice".to_string()).unwrap().unwrap();
+        assert!(user.recovery_backed_up);
+        assert_eq!(user.kdf_password, Some(crypt::default_kdf().to_string()));
+        assert_eq!(User::select_legacy_mek(&conn, user.id.unwrap()).unwrap(), Some(mek.to_vec()));
+
+        //The first unlock encrypts the key with the secret and removes the plain text one
+        let secret = SecretString::from("secret");
+        operations::unlock_user(&mut conn, &mut user, &secret).unwrap();
+        assert_eq!(user.mek().unwrap().expose_secret(), &mek);
+        assert_eq!(User::select_legacy_mek(&conn, user.id.unwrap()).unwrap(), None);
+
+        let mut user = User::select(&conn, "alice".to_string()).unwrap().unwrap();
+        assert!(operations::unlock_user(&mut conn, &mut user, &SecretString::from("wrong")).is_err());
+        operations::unlock_user(&mut conn, &mut user, &secret).unwrap();
+        assert_eq!(user.mek().unwrap().expose_secret(), &mek);
+    }
+
+    #[test]
+    fn migrate_is_run_once() {
+        let mut conn = legacy_database(&[7u8; 32]);
+
+        setup(&mut conn).unwrap();
+        setup(&mut conn).unwrap();
+
+        assert!(!has_column(&conn, "user", "master_encryption_key").unwrap());
+        assert!(has_column(&conn, "user", "legacy_mek").unwrap());
+    }
+
+    #[test]
+    fn idempotency_key_is_kept() {
+        let mut conn = legacy_database(&[7u8; 32]);
+
+        //Notes of the first version had no idempotency key
+        conn.execute("CREATE TABLE note (id INTEGER PRIMARY KEY, id_server INTEGER, id_user INTEGER NOT NULL REFERENCES user(id), title TEXT,
+            content BLOB, nonce BLOB, updated_at INTEGER, synched INTEGER NOT NULL)", ()).unwrap();
+        conn.execute("INSERT INTO note (id_user, title, content, nonce, updated_at, synched) VALUES (1, '', x'00', x'00', 0, 0)", ()).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+
+        setup(&mut conn).unwrap();
+
+        //Same key until the note is detached, it is then a new note for the server
+        let key = schema::Note::idempotency_key(&conn, 1).unwrap();
+        assert_eq!(schema::Note::idempotency_key(&conn, 1).unwrap(), key);
+
+        schema::Note::detach_all(&conn, 1).unwrap();
+        assert_ne!(schema::Note::idempotency_key(&conn, 1).unwrap(), key);
+    }
+}
@@ -1,14 +1,21 @@
+use std::time::Duration;
+
 use aes_gcm::{Aes256Gcm, Key};
 use chrono::{DateTime, Local, NaiveDateTime};
 use rusqlite::Connection;
 use serde::Serialize;
-use tauri_plugin_log::log::{debug, trace};
+use similar::{ChangeTag, TextDiff};
+use tauri_plugin_log::log::{debug, error, trace};
+
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use secrecy::SecretString;
+use zeroize::Zeroizing;
 
-use crate::{crypt::{self, NoteData}, db::schema::{Note, User}};
+use crate::{crypt::{self, AttachmentData, AttachmentMetadata, MasterKey, NoteData, PendingRecoveryKey, RecoveryKeyKind}, db::schema::{Attachment, AttachmentChunk, Identity, Instance, MekRotation, Note, NoteHistory, NoteKey, SyncCursor, User}};
 
 //TODO: refactor this, data encryption and stuff should not be inside db?
-pub fn create_note(conn: &Connection, id_user: u32, title: String, mek: Key<Aes256Gcm>) -> Result<(), Box<dyn std::error::Error>> {
-    let (content, nonce) = crypt::encrypt_note("".to_string(), mek).unwrap(); //Content empty because it's first note
+pub fn create_note(conn: &Connection, id_user: u32, title: String, mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let (content, nonce) = crypt::encrypt_note("", mek)?; //Content empty because it's first note
 
     let note = Note {
         id: None,
@@ -26,10 +33,11 @@ pub fn create_note(conn: &Connection, id_user: u32, title: String, mek: Key<Aes2
     Ok(())
 }
 
-pub fn get_note(conn: &Connection, id: u32, mek: Key<Aes256Gcm>) -> Result<NoteData, Box<dyn std::error::Error>> {
+pub fn get_note(conn: &Connection, id: u32, mek: &MasterKey) -> Result<NoteData, Box<dyn std::error::Error>> {
     let note = Note::select(conn, id).unwrap().unwrap();
+    let key = note_key(conn, &note, mek)?;
 
-    let decrypted_note = crypt::decrypt_note(note, mek).unwrap();
+    let decrypted_note = crypt::decrypt_note(note, key.as_ref().unwrap_or(mek))?;
 
     debug!("note decrypted");
 
@@ -42,48 +50,400 @@ pub fn get_notes(conn: &Connection, id_user: u32) -> Result<Vec<Note>, Box<dyn s
     Ok(notes)
 }
 
-pub fn update_note(conn: &Connection, note_data: NoteData, mek: Key<Aes256Gcm>) -> Result<(), Box<dyn std::error::Error>> {
-    let (content, nonce) = crypt::encrypt_note(note_data.content, mek).unwrap();
-    
-    let mut note = Note::select(conn, note_data.id).unwrap().unwrap();
+/// Key the content of a shared note is encrypted with, None for notes encrypted with the master encryption key
+fn note_key(conn: &Connection, note: &Note, mek: &MasterKey) -> Result<Option<MasterKey>, Box<dyn std::error::Error>> {
+    let key = match NoteKey::select(conn, note.id.unwrap())? {
+        Some(key) => key,
+        None => return Ok(None)
+    };
+
+    let unwrapped = match &key.owner {
+        None => crypt::unwrap_key(&key.encrypted_key, &key.key_nonce, mek)?,
+        Some(owner) => {
+            let identity = Identity::select(conn, note.id_user.unwrap())?.ok_or("identity not found")?;
+            crypt::unwrap_shared_note_key(&key.encrypted_key, &key.key_nonce, &identity, owner, note.id_server.unwrap(), key.version, mek)?
+        }
+    };
+
+    Ok(Some(unwrapped))
+}
+
+pub fn get_note_key(conn: &Connection, id_note: u32, mek: &MasterKey) -> Result<Option<MasterKey>, Box<dyn std::error::Error>> {
+    let note = Note::select(conn, id_note)?.ok_or("note not found")?;
+
+    note_key(conn, &note, mek)
+}
+
+/// Decrypt a revision of the local note `id_note` with the key given by its key version.
+/// Revisions encrypted with a previous key of the note or with the master encryption key of another user can't be decrypted.
+pub fn decrypt_note_revision(conn: &Connection, id_note: u32, revision: shared::NoteRevisionContent, mek: &MasterKey) -> Result<NoteData, Box<dyn std::error::Error>> {
+    let note_key_version = NoteKey::select(conn, id_note)?.map(|key| (key.version, key.owner.is_some()));
+
+    let key = match (revision.key_version, note_key_version) {
+        (None, Some((_, true))) => return Err("revision is encrypted with the key of the owner".into()),
+        (None, _) => None,
+        (Some(version), Some((current, _))) if version == current => get_note_key(conn, id_note, mek)?,
+        (Some(_), _) => return Err("revision is encrypted with a previous key of the note".into())
+    };
+
+    Ok(crypt::decrypt_note_revision(id_note, revision, key.as_ref().unwrap_or(mek))?)
+}
+
+/// Encrypt a revision of the local note `id_note` again with `new_key`, the new key of the note.
+/// None for revisions that can't be decrypted anymore, the server deletes them.
+pub fn reencrypt_note_revision(conn: &Connection, id_note: u32, revision: shared::NoteRevisionContent, new_key: &MasterKey, mek: &MasterKey) -> Result<Option<shared::RotatedRevision>, Box<dyn std::error::Error>> {
+    let id = revision.id;
+
+    let data = match decrypt_note_revision(conn, id_note, revision, mek) {
+        Ok(data) => data,
+        Err(e) => {
+            debug!("revision {id} dropped: {e}");
+            return Ok(None);
+        }
+    };
+
+    let (content, nonce) = crypt::encrypt_note(&data.content, new_key)?;
+
+    Ok(Some(shared::RotatedRevision { id, content, nonce }))
+}
+
+/// Notes shared with the user with the read permission can't be modified
+fn check_writable(conn: &Connection, id_note: u32) -> Result<(), Box<dyn std::error::Error>> {
+    match NoteKey::select(conn, id_note)? {
+        Some(key) if key.permission == shared::SharePermission::Read => Err("note is shared read-only".into()),
+        _ => Ok(())
+    }
+}
+
+/// A snapshot is taken when the last one is older than this, in seconds
+const SNAPSHOT_INTERVAL: i64 = 5 * 60;
+/// A snapshot is taken when this many characters changed since the last one
+const SNAPSHOT_EDIT_DISTANCE: usize = 200;
+/// Snapshots older than this are deleted, in seconds
+const SNAPSHOT_MAX_AGE: i64 = 30 * 24 * 3600;
+/// Only the most recent snapshots of a note are kept
+const SNAPSHOT_MAX_COUNT: u32 = 50;
+
+/// Save the new version of a note.
+/// The previous version is kept as a snapshot when enough time passed or enough characters changed since the last snapshot.
+pub fn update_note(conn: &Connection, note_data: NoteData, mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let now = Local::now().to_utc().timestamp();
+
+    check_writable(conn, note_data.id)?;
+
+    let note = Note::select(conn, note_data.id).unwrap().unwrap();
+    let key = note_key(conn, &note, mek)?;
+    let key = key.as_ref().unwrap_or(mek);
+
+    if needs_snapshot(conn, &note, &note_data.content, now, key, mek)? {
+        snapshot_note(conn, &note, now, key, mek)?;
+    }
+
+    write_note(conn, note, &note_data, now, key)?;
+
+    trace!("note updated");
+    Ok(())
+}
+
+/// `key` is the key of the note, snapshots are always encrypted with the master encryption key
+fn needs_snapshot(conn: &Connection, note: &Note, content: &str, now: i64, key: &MasterKey, mek: &MasterKey) -> Result<bool, Box<dyn std::error::Error>> {
+    let previous = crypt::decrypt_note_content(&note.content, &note.nonce, key)?;
+
+    //Nothing worth keeping
+    if previous.is_empty() || previous.as_str() == content {
+        return Ok(false);
+    }
+
+    let latest = match NoteHistory::select_latest(conn, note.id.unwrap())? {
+        Some(latest) => latest,
+        None => return Ok(true)
+    };
 
-    note.title = note_data.title;
+    if now - latest.taken_at >= SNAPSHOT_INTERVAL {
+        return Ok(true);
+    }
+
+    let snapshot = crypt::decrypt_note_content(&latest.content, &latest.nonce, mek)?;
+
+    Ok(edit_distance(&snapshot, content) >= SNAPSHOT_EDIT_DISTANCE)
+}
+
+/// Characters inserted or deleted to go from `old` to `new`.
+/// The diff is cut short on very large notes, the result is then an upper bound.
+fn edit_distance(old: &str, new: &str) -> usize {
+    TextDiff::configure()
+        .timeout(Duration::from_millis(50))
+        .diff_chars(old, new)
+        .iter_all_changes()
+        .filter(|change| change.tag() != ChangeTag::Equal)
+        .count()
+}
+
+/// Keep the stored version of the note as a snapshot.
+/// It is encrypted again with the master encryption key so it stays readable when the key of a shared note changes.
+fn snapshot_note(conn: &Connection, note: &Note, now: i64, key: &MasterKey, mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let id_note = note.id.unwrap();
+
+    let (content, nonce) = crypt::reencrypt_note(&note.content, &note.nonce, key, mek)?;
+
+    NoteHistory {
+        id: None,
+        id_note,
+        title: note.title.clone(),
+        content,
+        nonce,
+        updated_at: note.updated_at,
+        taken_at: now
+    }.insert(conn)?;
+
+    NoteHistory::prune(conn, id_note, now - SNAPSHOT_MAX_AGE, SNAPSHOT_MAX_COUNT)?;
+
+    trace!("note snapshot taken");
+    Ok(())
+}
+
+fn write_note(conn: &Connection, mut note: Note, note_data: &NoteData, now: i64, key: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let (content, nonce) = crypt::encrypt_note(&note_data.content, key)?;
+
+    note.title = note_data.title.clone();
     note.content = content;
     note.nonce = nonce;
-    note.updated_at = Local::now().to_utc().timestamp();
+    note.updated_at = now;
     note.synched = false;
-    
-    note.update(conn).unwrap();
-    
-    trace!("note updated");
+
+    note.update(conn)?;
+
     Ok(())
 }
 
-pub fn create_user(conn: &Connection, username: String) -> Result<User, Box<dyn std::error::Error>> {
-    let user_encryption_data = crypt::create_user();
+/// Snapshots of a note, most recent first
+pub fn get_note_snapshots(conn: &Connection, id_note: u32) -> Result<Vec<NoteHistory>, Box<dyn std::error::Error>> {
+    NoteHistory::select_all_from_note(conn, id_note)
+}
+
+pub fn get_note_snapshot(conn: &Connection, id: u32, mek: &MasterKey) -> Result<NoteData, Box<dyn std::error::Error>> {
+    let snapshot = NoteHistory::select(conn, id)?.ok_or("snapshot not found")?;
+
+    Ok(NoteData {
+        id: snapshot.id_note,
+        title: snapshot.title,
+        content: crypt::decrypt_note_content(&snapshot.content, &snapshot.nonce, mek)?,
+        updated_at: snapshot.updated_at
+    })
+}
+
+/// Replace a note with one of its snapshots, the current version is snapshotted first so the restore can be undone
+pub fn restore_note_snapshot(conn: &mut Connection, id: u32, mek: &MasterKey) -> Result<NoteData, Box<dyn std::error::Error>> {
+    let now = Local::now().to_utc().timestamp();
+
+    let tx = conn.transaction()?;
+
+    let snapshot = get_note_snapshot(&tx, id, mek)?;
+    check_writable(&tx, snapshot.id)?;
+
+    let note = Note::select(&tx, snapshot.id)?.ok_or("note not found")?;
+    let key = note_key(&tx, &note, mek)?;
+    let key = key.as_ref().unwrap_or(mek);
+
+    snapshot_note(&tx, &note, now, key, mek)?;
+
+    let restored = NoteData { updated_at: now, ..snapshot };
+    write_note(&tx, note, &restored, now, key)?;
+
+    tx.commit()?;
+
+    debug!("note {} restored from snapshot {id}", restored.id);
+    Ok(restored)
+}
+
+/// Create a local user, its master encryption key is stored encrypted with a key derived from `unlock_secret`.
+/// The user is returned unlocked, its recovery key is pending until the user confirms it.
+pub fn create_user(conn: &Connection, username: String, unlock_secret: &SecretString) -> Result<User, Box<dyn std::error::Error>> {
+    let user_encryption_data = crypt::create_user()?;
 
-    let user = User {
+    let salt_local = SaltString::generate(&mut OsRng).to_string();
+    let kdf_local = crypt::default_kdf();
+
+    let mut user = User {
         id: None,
         username,
-        master_encryption_key: user_encryption_data.master_encryption_key,
+        encrypted_mek: Vec::new(),
+        mek_nonce: Vec::new(),
+        salt_local: salt_local.clone(),
+        lock_timeout: None,
+        kdf_local: kdf_local.to_string(),
         salt_recovery_data: user_encryption_data.salt_recovery_data.to_string(),
         mek_recovery_nonce: user_encryption_data.mek_recovery_nonce,
         encrypted_mek_recovery: user_encryption_data.encrypted_mek_recovery,
         token: None,
-        instance: None
+        instance: None,
+        recovery_backed_up: false,
+        kdf_recovery_data: user_encryption_data.kdf_recovery_data.to_string(),
+        kdf_password: None,
+        master_encryption_key: None,
+        local_key: Some(crypt::derive_local_key(unlock_secret, &salt_local, &kdf_local)?),
+        pending_recovery_keys: Vec::new(),
     };
 
-    user.insert(&conn).unwrap();
+    set_user_mek(&mut user, user_encryption_data.master_encryption_key)?;
+
+    user.insert(conn).unwrap();
+    user.id = Some(conn.last_insert_rowid() as u32);
 
-    //TODO: send recovery keys to frontend
+    add_pending_recovery_key(&mut user, RecoveryKeyKind::Data, user_encryption_data.recovery_key_data);
 
     Ok(user)
 }
 
-pub fn update_user(conn: &Connection, new_user: User) {
+/// Create a local user from an account recovered with its data recovery key, the user is returned unlocked.
+/// The recovery key is considered backed up since it has just been typed.
+pub fn restore_user(conn: &Connection, username: String, unlock_secret: &SecretString, mek: MasterKey, recovery: shared::DataRecoveryRequest, login: shared::Login, instance: String) -> Result<User, Box<dyn std::error::Error>> {
+    if User::select(conn, username.clone())?.is_some() {
+        return Err("User already exists".into());
+    }
+
+    let salt_local = SaltString::generate(&mut OsRng).to_string();
+    let kdf_local = crypt::default_kdf();
+
+    let mut user = User {
+        id: None,
+        username,
+        encrypted_mek: Vec::new(),
+        mek_nonce: Vec::new(),
+        salt_local: salt_local.clone(),
+        lock_timeout: None,
+        kdf_local: kdf_local.to_string(),
+        salt_recovery_data: recovery.salt_recovery_data,
+        mek_recovery_nonce: recovery.mek_recovery_nonce,
+        encrypted_mek_recovery: recovery.encrypted_mek_recovery,
+        token: Some(login.token),
+        instance: Some(instance),
+        recovery_backed_up: true,
+        kdf_recovery_data: recovery.kdf_recovery_data.to_string(),
+        kdf_password: Some(login.kdf_password.to_string()),
+        master_encryption_key: None,
+        local_key: Some(crypt::derive_local_key(unlock_secret, &salt_local, &kdf_local)?),
+        pending_recovery_keys: Vec::new(),
+    };
+
+    set_user_mek(&mut user, mek)?;
+
+    user.insert(conn)?;
+    user.id = Some(conn.last_insert_rowid() as u32);
+
+    debug!("user restored from recovery key");
+    Ok(user)
+}
+
+pub fn update_user(conn: &Connection, new_user: &User) {
     new_user.update(conn).unwrap();
 }
 
+/// Replace the master encryption key of an unlocked user, it is encrypted with the local key before being stored.
+/// `update_user` must be called to save it.
+pub fn set_user_mek(user: &mut User, mek: MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let local_key = user.local_key.as_ref().ok_or("App is locked")?;
+
+    let (encrypted_mek, mek_nonce) = crypt::wrap_key(&mek, local_key)?;
+
+    user.encrypted_mek = encrypted_mek;
+    user.mek_nonce = mek_nonce;
+    user.master_encryption_key = Some(mek);
+
+    Ok(())
+}
+
+/// Decrypt the master encryption key with the unlock secret and keep it in memory until the user is locked.
+/// A plain text key left by an older version is encrypted with this secret instead, it becomes the unlock secret.
+pub fn unlock_user(conn: &mut Connection, user: &mut User, unlock_secret: &SecretString) -> Result<(), Box<dyn std::error::Error>> {
+    let id_user = user.id.ok_or("User has no id")?;
+
+    if let Some(legacy_mek) = User::select_legacy_mek(conn, id_user)? {
+        let mek = crypt::master_key_from_slice(&Zeroizing::new(legacy_mek))?;
+
+        let salt_local = SaltString::generate(&mut OsRng).to_string();
+        let kdf_local = crypt::default_kdf();
+
+        user.local_key = Some(crypt::derive_local_key(unlock_secret, &salt_local, &kdf_local)?);
+        user.salt_local = salt_local;
+        user.kdf_local = kdf_local.to_string();
+        set_user_mek(user, mek)?;
+
+        let tx = conn.transaction()?;
+        user.update(&tx)?;
+        User::delete_legacy_mek(&tx, id_user)?;
+        tx.commit()?;
+
+        debug!("plain text master encryption key encrypted with the unlock secret");
+        return Ok(());
+    }
+
+    let local_key = crypt::derive_local_key(unlock_secret, &user.salt_local, &crypt::parse_kdf(&user.kdf_local)?)?;
+
+    let mek = crypt::unwrap_key(&user.encrypted_mek, &user.mek_nonce, &local_key)?;
+
+    user.master_encryption_key = Some(mek);
+    user.local_key = Some(local_key);
+
+    trace!("user unlocked");
+    Ok(())
+}
+
+/// Forget the decrypted keys, they are wiped from memory when dropped
+pub fn lock_user(user: &mut User) {
+    user.master_encryption_key = None;
+    user.local_key = None;
+    user.pending_recovery_keys.clear();
+
+    trace!("user locked");
+}
+
+/// Keep a new recovery key until the user confirms it, it replaces a pending one of the same kind.
+/// `update_user` must be called to save the backed up flag.
+pub fn add_pending_recovery_key(user: &mut User, kind: RecoveryKeyKind, recovery_key: SecretString) {
+    user.pending_recovery_keys.retain(|pending| pending.kind != kind);
+    user.pending_recovery_keys.push(PendingRecoveryKey { kind, recovery_key, challenge: Vec::new() });
+
+    if kind == RecoveryKeyKind::Data {
+        user.recovery_backed_up = false;
+    }
+}
+
+/// Ask some words of a pending recovery key back to the user, return their positions
+pub fn recovery_key_challenge(user: &mut User, kind: RecoveryKeyKind) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let pending = user.pending_recovery_keys.iter_mut()
+        .find(|pending| pending.kind == kind)
+        .ok_or("No recovery key to confirm")?;
+
+    pending.challenge = crypt::recovery_key_challenge();
+
+    Ok(pending.challenge.clone())
+}
+
+/// Check the words of the challenge, the recovery key is forgotten once confirmed.
+/// `update_user` must be called to save the backed up flag.
+pub fn confirm_recovery_key(user: &mut User, kind: RecoveryKeyKind, words: &[SecretString]) -> Result<(), Box<dyn std::error::Error>> {
+    let index = user.pending_recovery_keys.iter()
+        .position(|pending| pending.kind == kind)
+        .ok_or("No recovery key to confirm")?;
+
+    let pending = &mut user.pending_recovery_keys[index];
+    let result = crypt::check_recovery_key_words(&pending.recovery_key, &pending.challenge, words);
+
+    //A new challenge is needed after a wrong answer
+    pending.challenge.clear();
+    result?;
+
+    user.pending_recovery_keys.remove(index);
+
+    if kind == RecoveryKeyKind::Data {
+        user.recovery_backed_up = true;
+    }
+
+    trace!("recovery key confirmed");
+    Ok(())
+}
+
 pub fn get_user(conn: &Connection, username: String) -> Result<Option<User>, Box<dyn std::error::Error>> {
     let user = User::select(conn, username).unwrap();
 
@@ -96,6 +456,599 @@ pub fn get_users(conn: &Connection) -> Result<Vec<User>, Box<dyn std::error::Err
     Ok(users)
 }
 
+/// Prepare a master encryption key rotation started on the server as `id_server`, it will be uploaded by the sync.
+/// `password` must have been checked by the server, the new master encryption key is wrapped with it.
+/// Return the new recovery key, the previous one can't decrypt the new master encryption key.
+pub fn create_mek_rotation(conn: &Connection, user: &User, password: &SecretString, id_server: u64) -> Result<SecretString, Box<dyn std::error::Error>> {
+    let kdf_password = match &user.kdf_password {
+        Some(kdf) => crypt::parse_kdf(kdf)?,
+        None => return Err("User must be logged in to rotate the master encryption key".into())
+    };
+
+    let rotation_data = crypt::rotate_mek(password, &kdf_password)?;
+
+    let (encrypted_mek, mek_nonce) = crypt::wrap_key(&rotation_data.master_encryption_key, user.mek()?)?;
+
+    let rotation = MekRotation {
+        id_user: user.id.unwrap(),
+        id_server: Some(id_server),
+        encrypted_mek,
+        mek_nonce,
+        salt_data: rotation_data.salt_data.to_string(),
+        encrypted_mek_password: rotation_data.encrypted_mek_password,
+        mek_password_nonce: rotation_data.mek_password_nonce,
+        salt_recovery_data: rotation_data.salt_recovery_data.to_string(),
+        encrypted_mek_recovery: rotation_data.encrypted_mek_recovery,
+        mek_recovery_nonce: rotation_data.mek_recovery_nonce,
+        committed: false,
+        kdf_recovery_data: rotation_data.kdf_recovery_data.to_string(),
+    };
+
+    rotation.insert(conn)?;
+
+    Ok(rotation_data.recovery_key_data)
+}
+
+/// Re-encrypt every note of the user with the new master encryption key and use it
+/// once the server has committed the rotation. Everything is done in one transaction.
+pub fn finish_mek_rotation(conn: &mut Connection, user: &mut User, rotation: MekRotation) -> Result<(), Box<dyn std::error::Error>> {
+    let new_mek = crypt::unwrap_key(&rotation.encrypted_mek, &rotation.mek_nonce, user.mek()?)?;
+
+    let tx = conn.transaction()?;
+
+    reencrypt_notes(&tx, user.id.unwrap(), user.mek()?, &new_mek)?;
+    rewrap_attachment_keys(&tx, user.id.unwrap(), user.mek()?, &new_mek)?;
+    rewrap_identity_keys(&tx, user.id.unwrap(), user.mek()?, &new_mek)?;
+
+    set_user_mek(user, new_mek)?;
+    user.salt_recovery_data = rotation.salt_recovery_data.clone();
+    user.encrypted_mek_recovery = rotation.encrypted_mek_recovery.clone();
+    user.mek_recovery_nonce = rotation.mek_recovery_nonce.clone();
+    user.kdf_recovery_data = rotation.kdf_recovery_data.clone();
+    user.update(&tx)?;
+
+    rotation.delete(&tx)?;
+
+    tx.commit()?;
+
+    debug!("master encryption key rotated");
+    Ok(())
+}
+
+/// Delete the local profile of the user and its notes
+pub fn delete_user(conn: &mut Connection, user: &User) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+
+    if let Some(rotation) = MekRotation::select(&tx, user.id.unwrap())? {
+        rotation.delete(&tx)?;
+    }
+
+    SyncCursor::delete(&tx, user.id.unwrap())?;
+    Identity::delete(&tx, user.id.unwrap())?;
+    Attachment::delete_all(&tx, user.id.unwrap())?;
+    NoteHistory::delete_all(&tx, user.id.unwrap())?;
+    NoteKey::delete_all(&tx, user.id.unwrap())?;
+    Note::delete_all(&tx, user.id.unwrap())?;
+    user.delete(&tx)?;
+
+    tx.commit()?;
+
+    debug!("local user deleted");
+    Ok(())
+}
+
+/// Keep the local profile and its notes but forget the instance, notes are uploaded again on the next account
+pub fn detach_user(conn: &mut Connection, user: &mut User) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+
+    if let Some(rotation) = MekRotation::select(&tx, user.id.unwrap())? {
+        rotation.delete(&tx)?;
+    }
+
+    //Shares belong to the instance, the notes of the user are encrypted again with its master encryption key
+    //and notes shared with the user are forgotten. It must be done while the identity is known.
+    unshare_notes(&tx, user.id.unwrap(), user.mek()?)?;
+
+    SyncCursor::delete(&tx, user.id.unwrap())?;
+    Identity::delete(&tx, user.id.unwrap())?; //A new account gets its own identity
+    Attachment::detach_all(&tx, user.id.unwrap())?;
+    Note::detach_all(&tx, user.id.unwrap())?;
+
+    user.token = None;
+    user.instance = None;
+    user.kdf_password = None;
+    user.update(&tx)?;
+
+    tx.commit()?;
+
+    debug!("local user detached from its instance");
+    Ok(())
+}
+
+/// Encrypt the shared notes of the user with its master encryption key and delete the notes shared with it
+fn unshare_notes(conn: &Connection, id_user: u32, mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    for mut note in Note::select_all(conn, id_user)? {
+        let id_note = note.id.unwrap();
+
+        let key = match NoteKey::select(conn, id_note)? {
+            Some(key) => key,
+            None => continue
+        };
+
+        if key.owner.is_none() {
+            let note_key = note_key(conn, &note, mek)?.unwrap();
+            (note.content, note.nonce) = crypt::reencrypt_note(&note.content, &note.nonce, &note_key, mek)?;
+            note.update(conn)?;
+        }
+
+        NoteKey::delete(conn, id_note)?;
+
+        if key.owner.is_some() {
+            NoteHistory::delete_all_from_note(conn, id_note)?;
+            Note::delete(conn, id_note)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Re-encrypt every local note of the user and their snapshots, used when the master encryption key changed.
+/// Shared notes keep their key, only the keys of the notes owned by the user are wrapped again.
+pub fn reencrypt_notes(conn: &Connection, id_user: u32, old_mek: &MasterKey, new_mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    let notes = Note::select_all(conn, id_user)?;
+
+    for mut note in notes {
+        if NoteKey::select(conn, note.id.unwrap())?.is_some() {
+            continue;
+        }
+
+        let (content, nonce) = crypt::reencrypt_note(&note.content, &note.nonce, old_mek, new_mek)?;
+
+        note.content = content;
+        note.nonce = nonce;
+
+        note.update(conn)?;
+    }
+
+    for mut snapshot in NoteHistory::select_all(conn, id_user)? {
+        (snapshot.content, snapshot.nonce) = crypt::reencrypt_note(&snapshot.content, &snapshot.nonce, old_mek, new_mek)?;
+
+        snapshot.update(conn)?;
+    }
+
+    for mut key in NoteKey::select_all_owned(conn, id_user)? {
+        (key.encrypted_key, key.key_nonce) = crypt::rewrap_key(&key.encrypted_key, &key.key_nonce, old_mek, new_mek)?;
+
+        key.upsert(conn)?;
+    }
+
+    trace!("notes re-encrypted");
+    Ok(())
+}
+
+/// Remember the metadata of an instance
+pub fn save_instance(conn: &Connection, url: String, info: shared::InstanceInfo) -> Result<(), Box<dyn std::error::Error>> {
+    let instance = Instance {
+        url,
+        info,
+        checked_at: Local::now().timestamp(),
+    };
+
+    instance.upsert(conn)
+}
+
+pub fn get_instance(conn: &Connection, url: &str) -> Result<Option<Instance>, Box<dyn std::error::Error>> {
+    Instance::select(conn, url)
+}
+
+/// Cursor of the last note received by the user, None before the first download
+pub fn get_sync_cursor(conn: &Connection, id_user: u32) -> Result<Option<shared::NoteCursor>, Box<dyn std::error::Error>> {
+    Ok(SyncCursor::select(conn, id_user)?.map(|cursor| cursor.into()))
+}
+
+/// Merge a page of notes received from the server and move the cursor after it, both in one transaction
+pub fn save_note_page(conn: &mut Connection, id_user: u32, notes: Vec<shared::Note>, cursor: shared::NoteCursor) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+
+    for mut note in notes {
+        let key = note.key.take();
+
+        let mut note = Note::from(note);
+        note.id_user = Some(id_user);
+
+        //Notes shared with the user are known by their server id, their client id is the one of the device of their owner
+        let existing = match key.as_ref().is_some_and(|key| key.owner.is_some()) {
+            true => match Note::select_id_by_server_id(&tx, note.id_server.unwrap())? {
+                Some(id) => Note::select(&tx, id)?,
+                None => None
+            },
+            false => Note::select(&tx, note.id.unwrap())?
+        };
+
+        //Check if exist
+        let saved = match existing {
+            Some(sn) => {
+                if note.updated_at > sn.updated_at {
+                    //Note is more recent on server
+                    match sn.synched {
+                        true => {
+                            note.id = sn.id;
+                            note.update(&tx)?;
+                            sn.id
+                        },
+                        false => {
+                            error!("Note {:?} is in conflict and it's not handled :(", sn.id); //TODO
+                            None
+                        }
+                    }
+                } else {
+                    None
+                }
+            },
+            None => {
+                note.insert(&tx)?;
+                Some(tx.last_insert_rowid() as u32)
+            }
+        };
+
+        //The key changes with the content, it is only kept with the content it decrypts
+        if let (Some(id_note), Some(key)) = (saved, key) {
+            NoteKey::new(id_note, key).upsert(&tx)?;
+        }
+
+        //TODO: if deleted
+    }
+
+    SyncCursor { id_user, updated_at: cursor.updated_at, id_note: cursor.id }.upsert(&tx)?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Keep a note encrypted with a new key by the user, as answered by the server
+pub fn save_rekeyed_note(conn: &mut Connection, id_note: u32, note: shared::Note) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+
+    let mut note = note;
+    let key = note.key.take().ok_or("note key missing")?;
+
+    let mut note = Note::from(note);
+    note.id = Some(id_note);
+    note.update(&tx)?;
+
+    NoteKey::new(id_note, key).upsert(&tx)?;
+
+    tx.commit()?;
+
+    debug!("note {id_note} encrypted with a new key");
+    Ok(())
+}
+
+/// Keys of the notes shared by the user, to send them wrapped with a new master encryption key
+pub fn get_owned_note_keys(conn: &Connection, id_user: u32) -> Result<Vec<(u64, NoteKey)>, Box<dyn std::error::Error>> {
+    NoteKey::select_all_owned(conn, id_user)?.into_iter().map(|key| {
+        let note = Note::select(conn, key.id_note)?.ok_or("note not found")?;
+
+        Ok((note.id_server.ok_or("shared note has no server id")?, key))
+    }).collect()
+}
+
+/// Wrap the key of every local attachment of the user with the new master encryption key
+pub fn rewrap_attachment_keys(conn: &Connection, id_user: u32, old_mek: &MasterKey, new_mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    for mut attachment in Attachment::select_all(conn, id_user)? {
+        (attachment.encrypted_key, attachment.key_nonce) = crypt::rewrap_key(&attachment.encrypted_key, &attachment.key_nonce, old_mek, new_mek)?;
+
+        attachment.update(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Private keys of the identity are encrypted like a note content
+pub fn rewrap_identity_keys(conn: &Connection, id_user: u32, old_mek: &MasterKey, new_mek: &MasterKey) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut identity) = Identity::select(conn, id_user)? {
+        (identity.encrypted_keys, identity.keys_nonce) = crypt::reencrypt_note(&identity.encrypted_keys, &identity.keys_nonce, old_mek, new_mek)?;
+
+        identity.upsert(conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_identity(conn: &Connection, id_user: u32) -> Result<Option<Identity>, Box<dyn std::error::Error>> {
+    Identity::select(conn, id_user)
+}
+
+/// Keep the identity published on the server
+pub fn save_identity(conn: &Connection, id_user: u32, identity: shared::Identity) -> Result<(), Box<dyn std::error::Error>> {
+    Identity {
+        id_user,
+        x25519_public: identity.x25519_public,
+        ed25519_public: identity.ed25519_public,
+        signature: identity.signature,
+        encrypted_keys: identity.encrypted_keys,
+        keys_nonce: identity.keys_nonce
+    }.upsert(conn)?;
+
+    debug!("identity saved");
+    Ok(())
+}
+
+/// Encrypt a file and attach it to a note, it is uploaded on the next sync
+pub fn add_attachment(conn: &mut Connection, id_note: u32, metadata: AttachmentMetadata, data: &[u8], mek: &MasterKey) -> Result<AttachmentData, Box<dyn std::error::Error>> {
+    //Attachment keys are wrapped with the master encryption key, users the note is shared with couldn't read them.
+    //Refused for every note that has been shared, the server only refuses notes that are still shared.
+    if NoteKey::select(conn, id_note)?.is_some() {
+        return Err("files can't be attached to a shared note".into());
+    }
+
+    let encrypted = crypt::encrypt_attachment(data, &metadata, mek)?;
+
+    let tx = conn.transaction()?;
+
+    let id = Attachment {
+        id: None,
+        id_server: None,
+        id_note,
+        encrypted_key: encrypted.encrypted_key,
+        key_nonce: encrypted.key_nonce,
+        metadata: encrypted.metadata,
+        metadata_nonce: encrypted.metadata_nonce,
+        synched: false
+    }.insert(&tx)?;
+
+    for (position, (hash, chunk)) in encrypted.chunks.into_iter().enumerate() {
+        AttachmentChunk { id_attachment: id, position: position as u32, hash, data: Some(chunk) }.insert(&tx)?;
+    }
+
+    tx.commit()?;
+
+    debug!("attachment added to note {id_note}");
+    Ok(AttachmentData { id, metadata, downloaded: true })
+}
+
+pub fn get_attachments(conn: &Connection, id_note: u32, mek: &MasterKey) -> Result<Vec<AttachmentData>, Box<dyn std::error::Error>> {
+    Attachment::select_all_from_note(conn, id_note)?.into_iter().map(|attachment| {
+        let id = attachment.id.unwrap();
+        let key = crypt::unwrap_key(&attachment.encrypted_key, &attachment.key_nonce, mek)?;
+        let metadata = crypt::decrypt_attachment_metadata(&key, &attachment.metadata, &attachment.metadata_nonce)?;
+        let downloaded = AttachmentChunk::select_all(conn, id)?.iter().all(|chunk| chunk.data.is_some());
+
+        Ok(AttachmentData { id, metadata, downloaded })
+    }).collect()
+}
+
+/// Store the attachments listed by the server that are not known locally, their chunks are downloaded when opened.
+/// Keys of known attachments are replaced, they change when the master encryption key is rotated on another device.
+pub fn merge_attachments(conn: &mut Connection, attachments: Vec<shared::Attachment>) -> Result<(), Box<dyn std::error::Error>> {
+    let tx = conn.transaction()?;
+
+    for attachment in attachments {
+        let id_server = attachment.id.unwrap();
+
+        if let Some(mut known) = Attachment::select_by_server_id(&tx, id_server)? {
+            known.encrypted_key = attachment.encrypted_key;
+            known.key_nonce = attachment.key_nonce;
+            known.update(&tx)?;
+            continue;
+        }
+
+        //The note of the attachment hasn't been received yet
+        let Some(id_note) = Note::select_id_by_server_id(&tx, attachment.id_note)? else {
+            continue;
+        };
+
+        let id = Attachment {
+            id: None,
+            id_server: Some(id_server),
+            id_note,
+            encrypted_key: attachment.encrypted_key,
+            key_nonce: attachment.key_nonce,
+            metadata: attachment.metadata,
+            metadata_nonce: attachment.metadata_nonce,
+            synched: true
+        }.insert(&tx)?;
+
+        for (position, hash) in attachment.chunks.into_iter().enumerate() {
+            AttachmentChunk { id_attachment: id, position: position as u32, hash, data: None }.insert(&tx)?;
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Decrypt a downloaded attachment
+pub fn read_attachment(conn: &Connection, id: u32, mek: &MasterKey) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+    let attachment = Attachment::select(conn, id)?.ok_or("attachment not found")?;
+    let key = crypt::unwrap_key(&attachment.encrypted_key, &attachment.key_nonce, mek)?;
+
+    let mut data = Zeroizing::new(Vec::new());
+
+    for chunk in AttachmentChunk::select_all(conn, id)? {
+        let encrypted = chunk.data.ok_or("attachment has not been downloaded")?;
+        data.extend_from_slice(&crypt::decrypt_attachment_chunk(&key, chunk.position, &chunk.hash, &encrypted)?);
+    }
+
+    Ok(data)
+}
+
 /// Execute when frontend load for the first time
-pub fn init(conn: &Connection) {
-}
\ No newline at end of file
+pub fn init(_conn: &Connection) {
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::*;
+
+    /// Database with a user and one synced note, returns the id of the note
+    fn test_database() -> (Connection, u32) {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::setup(&mut conn).unwrap();
+
+        conn.execute("INSERT INTO user (username, encrypted_mek, salt_recovery_data, mek_recovery_nonce, encrypted_mek_recovery, mek_nonce, salt_local, kdf_local, kdf_recovery_data)
+            VALUES ('alice', x'', '', x'', x'', x'', '', '', '')", ()).unwrap();
+        conn.execute("INSERT INTO note (id_server, id_user, title, content, nonce, updated_at, synched) VALUES (1, 1, '', x'00', x'00', 0, 1)", ()).unwrap();
+        let id_note = conn.last_insert_rowid() as u32;
+
+        (conn, id_note)
+    }
+
+    fn revision(content: &str, key: &MasterKey, key_version: Option<u32>) -> shared::NoteRevisionContent {
+        let (content, nonce) = crypt::encrypt_note(content, key).unwrap();
+
+        shared::NoteRevisionContent { id: 1, id_note: 1, title: "title".to_string(), content, nonce, updated_at: 0, key_version }
+    }
+
+    #[test]
+    fn decrypt_revision_with_its_key() {
+        let (conn, id_note) = test_database();
+        let mek = crypt::create_note_key();
+        let note_key = crypt::create_note_key();
+
+        //Revision archived before the note was shared
+        let data = decrypt_note_revision(&conn, id_note, revision("before sharing", &mek, None), &mek).unwrap();
+        assert_eq!(data.content.as_str(), "before sharing");
+
+        let (encrypted_key, key_nonce) = crypt::wrap_key(&note_key, &mek).unwrap();
+        NoteKey { id_note, version: 2, encrypted_key, key_nonce, owner: None, permission: shared::SharePermission::Write }.upsert(&conn).unwrap();
+
+        let data = decrypt_note_revision(&conn, id_note, revision("shared", &note_key, Some(2)), &mek).unwrap();
+        assert_eq!(data.content.as_str(), "shared");
+
+        //The key of a previous version isn't known anymore
+        assert!(decrypt_note_revision(&conn, id_note, revision("previous key", &crypt::create_note_key(), Some(1)), &mek).is_err());
+    }
+
+    #[test]
+    fn reencrypt_revisions_with_new_key() {
+        let (conn, id_note) = test_database();
+        let mek = crypt::create_note_key();
+        let key = crypt::create_note_key();
+        let new_key = crypt::create_note_key();
+
+        let (encrypted_key, key_nonce) = crypt::wrap_key(&key, &mek).unwrap();
+        NoteKey { id_note, version: 2, encrypted_key, key_nonce, owner: None, permission: shared::SharePermission::Write }.upsert(&conn).unwrap();
+
+        //Revisions from before the note was shared and from its current key are kept, the others are dropped
+        let rekeyed: Vec<_> = [
+            revision("master key", &mek, None),
+            revision("current key", &key, Some(2)),
+            revision("previous key", &crypt::create_note_key(), Some(1)),
+        ].into_iter().map(|revision| reencrypt_note_revision(&conn, id_note, revision, &new_key, &mek).unwrap()).collect();
+
+        assert!(rekeyed[2].is_none());
+
+        //The server stores the new version with them
+        let (encrypted_key, key_nonce) = crypt::wrap_key(&new_key, &mek).unwrap();
+        NoteKey { id_note, version: 3, encrypted_key, key_nonce, owner: None, permission: shared::SharePermission::Write }.upsert(&conn).unwrap();
+
+        for (rekeyed, expected) in rekeyed.into_iter().flatten().zip(["master key", "current key"]) {
+            let revision = shared::NoteRevisionContent {
+                id: rekeyed.id,
+                id_note: 1,
+                title: "title".to_string(),
+                content: rekeyed.content,
+                nonce: rekeyed.nonce,
+                updated_at: 0,
+                key_version: Some(3)
+            };
+
+            assert_eq!(decrypt_note_revision(&conn, id_note, revision, &mek).unwrap().content.as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn attach_to_shared_note() {
+        let (mut conn, id_note) = test_database();
+        let mek = crypt::create_note_key();
+        let metadata = || AttachmentMetadata { name: "file".to_string(), media_type: "text/plain".to_string(), size: 4 };
+
+        assert!(add_attachment(&mut conn, id_note, metadata(), b"data", &mek).is_ok());
+
+        let (encrypted_key, key_nonce) = crypt::wrap_key(&crypt::create_note_key(), &mek).unwrap();
+        NoteKey { id_note, version: 1, encrypted_key, key_nonce, owner: None, permission: shared::SharePermission::Write }.upsert(&conn).unwrap();
+
+        assert!(add_attachment(&mut conn, id_note, metadata(), b"data", &mek).is_err());
+    }
+
+    #[test]
+    fn resume_mek_rotation() {
+        let (mut conn, first) = test_database();
+        let mek = || crypt::master_key_from_slice(&[1; 32]).unwrap();
+        let local_key = || crypt::master_key_from_slice(&[2; 32]).unwrap();
+        let new_mek = crypt::create_note_key();
+
+        let mut user = User::select(&conn, "alice".to_string()).unwrap().unwrap();
+        user.local_key = Some(local_key());
+        set_user_mek(&mut user, mek()).unwrap();
+
+        let (content, nonce) = crypt::encrypt_note("first", &mek()).unwrap();
+        conn.execute("UPDATE note SET content = ?, nonce = ? WHERE id = ?", (content, nonce, first)).unwrap();
+        let (content, nonce) = crypt::encrypt_note("second", &mek()).unwrap();
+        conn.execute("INSERT INTO note (id_server, id_user, title, content, nonce, updated_at, synched) VALUES (2, 1, '', ?, ?, 0, 1)", (content, nonce)).unwrap();
+        let second = conn.last_insert_rowid() as u32;
+
+        let (encrypted_mek, mek_nonce) = crypt::wrap_key(&new_mek, &mek()).unwrap();
+        MekRotation {
+            id_user: 1,
+            id_server: Some(1),
+            encrypted_mek,
+            mek_nonce,
+            salt_data: String::new(),
+            encrypted_mek_password: Vec::new(),
+            mek_password_nonce: Vec::new(),
+            salt_recovery_data: "new salt".to_string(),
+            encrypted_mek_recovery: Vec::new(),
+            mek_recovery_nonce: Vec::new(),
+            committed: false,
+            kdf_recovery_data: String::new(),
+        }.insert(&conn).unwrap();
+
+        let not_rotated = |conn: &Connection| Note::select_all_not_rotated(conn, 1).unwrap().iter().map(|note| note.id.unwrap()).collect::<Vec<_>>();
+
+        //Resumed after the first note was uploaded, only the second one is left
+        MekRotation::insert_note(&conn, first).unwrap();
+        assert_eq!(not_rotated(&conn), vec![second]);
+
+        //The server refused the commit, every note is uploaded again
+        MekRotation::clear_notes(&conn).unwrap();
+        assert_eq!(not_rotated(&conn), vec![first, second]);
+
+        //Committed by the server, the new key is used locally
+        let rotation = MekRotation::select(&conn, 1).unwrap().unwrap();
+        finish_mek_rotation(&mut conn, &mut user, rotation).unwrap();
+
+        assert!(MekRotation::select(&conn, 1).unwrap().is_none());
+        assert_eq!(get_note(&conn, first, &new_mek).unwrap().content.as_str(), "first");
+        assert_eq!(get_note(&conn, second, &new_mek).unwrap().content.as_str(), "second");
+
+        let stored = User::select(&conn, "alice".to_string()).unwrap().unwrap();
+        assert_eq!(stored.salt_recovery_data, "new salt");
+        assert_eq!(crypt::unwrap_key(&stored.encrypted_mek, &stored.mek_nonce, &local_key()).unwrap().expose_secret(), new_mek.expose_secret());
+    }
+
+    #[test]
+    fn restore_revision() {
+        let (conn, id_note) = test_database();
+        let mek = crypt::create_note_key();
+
+        let (content, nonce) = crypt::encrypt_note("current", &mek).unwrap();
+        conn.execute("UPDATE note SET content = ?, nonce = ? WHERE id = ?", (content, nonce, id_note)).unwrap();
+
+        let data = decrypt_note_revision(&conn, id_note, revision("restored", &mek, None), &mek).unwrap();
+        update_note(&conn, data, &mek).unwrap();
+
+        let note = get_note(&conn, id_note, &mek).unwrap();
+        assert_eq!(note.content.as_str(), "restored");
+        assert!(!Note::select(&conn, id_note).unwrap().unwrap().synched);
+
+        //The replaced version is kept as a snapshot
+        let snapshot = get_note_snapshots(&conn, id_note).unwrap().pop().unwrap();
+        assert_eq!(get_note_snapshot(&conn, snapshot.id.unwrap(), &mek).unwrap().content.as_str(), "current");
+    }
+}
@@ -1,9 +1,12 @@
+use std::fmt;
+
 use aes_gcm::{Aes256Gcm, Key};
 use chrono::NaiveDateTime;
 use rusqlite::Connection;
+use shared::REDACTED;
 use tauri_plugin_log::log::debug;
 
-use crate::crypt::NoteData;
+use crate::crypt::{MasterKey, NoteData, PendingRecoveryKey};
 
 use rusqlite::Error::QueryReturnedNoRows;
 
@@ -34,15 +37,17 @@ impl From<shared::Note> for Note {
     }
 }
 
-impl Into<shared::Note> for Note {
-    fn into(self) -> shared::Note {
+impl From<Note> for shared::Note {
+    fn from(note: Note) -> Self {
         shared::Note {
-            id: self.id.unwrap(),
-            id_server: self.id_server,
-            title: self.title,
-            content: self.content,
-            nonce: self.nonce,
-            updated_at: self.updated_at,
+            id: note.id.unwrap(),
+            id_server: note.id_server,
+            title: note.title,
+            content: note.content,
+            nonce: note.nonce,
+            updated_at: note.updated_at,
+            key: None,
+            idempotency_key: None,
         }
     }
 }
@@ -58,7 +63,8 @@ impl Note {
                 content BLOB,
                 nonce BLOB,
                 updated_at INTEGER,
-                synched INTEGER NOT NULL
+                synched INTEGER NOT NULL,
+                idempotency_key TEXT
             )", 
             (), // empty list of parameters.
         ).unwrap();
@@ -67,7 +73,7 @@ impl Note {
     }
 
     pub fn select(conn: &Connection, id: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
-        let note = match conn.query_one(
+        let note = conn.query_one(
             "SELECT * FROM note WHERE id = ?", 
             (id,),
             |row| {
@@ -82,10 +88,7 @@ impl Note {
                     synched: row.get(7)?
                 })
             }
-        ) {
-            Ok(note) => Some(note),
-            Err(_) => None
-        };
+        ).ok();
 
         Ok(note)
     }
@@ -106,6 +109,50 @@ impl Note {
         Ok(())
     }
 
+    /// Local id of a note known by the server
+    pub fn select_id_by_server_id(conn: &Connection, id_server: u64) -> Result<Option<u32>, Box<dyn std::error::Error>> {
+        match conn.query_one("SELECT id FROM note WHERE id_server = ?", (id_server,), |row| row.get(0)) {
+            Ok(id) => Ok(Some(id)),
+            Err(QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn delete(conn: &Connection, id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note WHERE id = ?", (id,))?;
+
+        Ok(())
+    }
+
+    pub fn delete_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note WHERE id_user = ?", (id_user,))?;
+
+        Ok(())
+    }
+
+    /// Forget the server ids of the notes of the user, they will be uploaded as new notes
+    pub fn detach_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE note SET id_server = NULL, synched = FALSE, idempotency_key = NULL WHERE id_user = ?", (id_user,))?;
+
+        Ok(())
+    }
+
+    /// Key sent with a note the server doesn't know yet, generated the first time and kept until the note is detached
+    /// so a batch sent again after a timeout doesn't insert it twice
+    pub fn idempotency_key(conn: &Connection, id: u32) -> Result<String, Box<dyn std::error::Error>> {
+        let key: Option<String> = conn.query_one("SELECT idempotency_key FROM note WHERE id = ?", (id,), |row| row.get(0))?;
+
+        match key {
+            Some(key) => Ok(key),
+            None => {
+                let key = uuid::Uuid::new_v4().to_string();
+                conn.execute("UPDATE note SET idempotency_key = ? WHERE id = ?", (&key, id))?;
+
+                Ok(key)
+            }
+        }
+    }
+
     pub fn select_all(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
         let mut stmt = conn.prepare("SELECT * FROM note WHERE id_user = ?").unwrap();
 
@@ -133,35 +180,213 @@ impl Note {
 
         Ok(notes)
     }
+
+    /// Select notes known by the server that haven't been uploaded in the current master encryption key rotation
+    pub fn select_all_not_rotated(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare("SELECT * FROM note WHERE id_user = ? AND id_server IS NOT NULL AND id NOT IN (SELECT id_note FROM mek_rotation_note)").unwrap();
+
+        let rows = stmt.query_map(
+            [id_user,],
+            |row| {
+                Ok(Note{
+                    id: row.get(0)?,
+                    id_server: row.get(1)?,
+                    id_user: row.get(2)?,
+                    title: row.get(3)?,
+                    content: row.get(4)?,
+                    nonce: row.get(5)?,
+                    updated_at: row.get(6)?,
+                    synched: row.get(7)?,
+                })
+            }
+        ).unwrap();
+
+        let mut notes = Vec::new();
+
+        for note in rows {
+            notes.push(note.unwrap());
+        }
+
+        Ok(notes)
+    }
+}
+
+/// Encrypted copy of a previous version of a local note, see `db::operations::update_note`
+#[derive(Debug)]
+pub struct NoteHistory {
+    pub id: Option<u32>,
+    pub id_note: u32,
+    pub title: String,
+    pub content: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub updated_at: i64, //Date of the version
+    pub taken_at: i64, //Date it has been replaced
+}
+
+impl NoteHistory {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS note_history (
+                id INTEGER PRIMARY KEY,
+                id_note INTEGER NOT NULL REFERENCES note(id),
+                title TEXT,
+                content BLOB NOT NULL,
+                nonce BLOB NOT NULL,
+                updated_at INTEGER NOT NULL,
+                taken_at INTEGER NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        conn.execute("CREATE INDEX IF NOT EXISTS note_history_id_note ON note_history (id_note, taken_at)", ()).unwrap();
+
+        Ok(())
+    }
+
+    pub fn insert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO note_history (id_note, title, content, nonce, updated_at, taken_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (&self.id_note, &self.title, &self.content, &self.nonce, &self.updated_at, &self.taken_at)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn update(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE note_history SET content = ?, nonce = ? WHERE id = ?",
+            (&self.content, &self.nonce, &self.id))?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(Self::query(conn, "SELECT * FROM note_history WHERE id = ?", id)?.pop())
+    }
+
+    /// Most recent snapshot of the note
+    pub fn select_latest(conn: &Connection, id_note: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(Self::query(conn, "SELECT * FROM note_history WHERE id_note = ? ORDER BY taken_at DESC, id DESC LIMIT 1", id_note)?.pop())
+    }
+
+    /// Snapshots of the note, most recent first
+    pub fn select_all_from_note(conn: &Connection, id_note: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT * FROM note_history WHERE id_note = ? ORDER BY taken_at DESC, id DESC", id_note)
+    }
+
+    pub fn select_all(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT note_history.* FROM note_history JOIN note ON note.id = note_history.id_note WHERE note.id_user = ?", id_user)
+    }
+
+    fn query(conn: &Connection, sql: &str, param: impl rusqlite::ToSql) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare(sql)?;
+
+        let rows = stmt.query_map(
+            [param],
+            |row| {
+                Ok(NoteHistory {
+                    id: row.get(0)?,
+                    id_note: row.get(1)?,
+                    title: row.get(2)?,
+                    content: row.get(3)?,
+                    nonce: row.get(4)?,
+                    updated_at: row.get(5)?,
+                    taken_at: row.get(6)?,
+                })
+            }
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Delete the snapshots of the note taken before `taken_before` and those beyond the `max_count` most recent
+    pub fn prune(conn: &Connection, id_note: u32, taken_before: i64, max_count: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note_history WHERE id_note = ?1 AND (taken_at < ?2 OR id NOT IN
+            (SELECT id FROM note_history WHERE id_note = ?1 ORDER BY taken_at DESC, id DESC LIMIT ?3))", (id_note, taken_before, max_count))?;
+
+        Ok(())
+    }
+
+    pub fn delete_all_from_note(conn: &Connection, id_note: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note_history WHERE id_note = ?", (id_note,))?;
+
+        Ok(())
+    }
+
+    pub fn delete_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note_history WHERE id_note IN (SELECT id FROM note WHERE id_user = ?)", (id_user,))?;
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone)]
 pub struct User {
     pub id: Option<u32>,
     pub username: String,
 
-    //TODO: Do not store that in plain text but use give the user the possibility to use biometric to decrypt?
-    pub master_encryption_key: Key<Aes256Gcm>, 
+    //Master encryption key encrypted with the local key, derived from the unlock secret (passphrase or PIN)
+    pub encrypted_mek: Vec<u8>,
+    pub mek_nonce: Vec<u8>,
+    pub salt_local: String,
+    pub lock_timeout: Option<u64>, //Seconds of inactivity before the app is locked, None to never lock automatically
+    pub kdf_local: String, //Key derivation parameters used with salt_local
 
     pub salt_recovery_data: String,
     pub mek_recovery_nonce: Vec<u8>,
     pub encrypted_mek_recovery: Vec<u8>,
     pub token: Option<Vec<u8>>,
-    pub instance: Option<String>
+    pub instance: Option<String>,
+    pub recovery_backed_up: bool, //true once the user confirmed the data recovery key, the one able to decrypt notes
+    pub kdf_recovery_data: String, //Key derivation parameters used with salt_recovery_data
+    pub kdf_password: Option<String>, //Key derivation parameters of the account password, None until logged in
+
+    //Only in memory while the app is unlocked, never stored in plain text
+    pub master_encryption_key: Option<MasterKey>,
+    pub local_key: Option<MasterKey>,
+    pub pending_recovery_keys: Vec<PendingRecoveryKey>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("lock_timeout", &self.lock_timeout)
+            .field("salt_recovery_data", &self.salt_recovery_data)
+            .field("token", &self.token.as_ref().map(|_| REDACTED))
+            .field("instance", &self.instance)
+            .field("recovery_backed_up", &self.recovery_backed_up)
+            .field("kdf_local", &self.kdf_local)
+            .field("kdf_recovery_data", &self.kdf_recovery_data)
+            .field("kdf_password", &self.kdf_password)
+            .field("unlocked", &self.master_encryption_key.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 impl User {
+    /// Columns read by `select`, named since migrated databases don't have them in the same order
+    const COLUMNS: &str = "id, username, encrypted_mek, salt_recovery_data, mek_recovery_nonce, encrypted_mek_recovery, token, instance,
+        mek_nonce, salt_local, lock_timeout, recovery_backed_up, kdf_local, kdf_recovery_data, kdf_password";
+
     pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
         conn.execute(
         "CREATE TABLE IF NOT EXISTS user (
                 id INTEGER PRIMARY KEY,
                 username TEXT,
-                master_encryption_key BLOB,
+                encrypted_mek BLOB,
                 salt_recovery_data TEXT,
                 mek_recovery_nonce BLOB,
                 encrypted_mek_recovery BLOB,
                 token TEXT,
-                instance TEXT
+                instance TEXT,
+                mek_nonce BLOB,
+                salt_local TEXT,
+                lock_timeout INTEGER,
+                recovery_backed_up INTEGER NOT NULL DEFAULT 0,
+                kdf_local TEXT NOT NULL,
+                kdf_recovery_data TEXT NOT NULL,
+                kdf_password TEXT,
+                legacy_mek BLOB
             )", 
             (), // empty list of parameters.
         ).unwrap();
@@ -171,8 +396,11 @@ impl User {
 
     pub fn insert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
         conn.execute(
-            "INSERT INTO user (id, username, master_encryption_key, salt_recovery_data, mek_recovery_nonce, encrypted_mek_recovery, token, instance) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", 
-            (&self.id, &self.username, &self.master_encryption_key.to_vec(), &self.salt_recovery_data, &self.mek_recovery_nonce, &self.encrypted_mek_recovery, &self.token, &self.instance)
+            "INSERT INTO user (id, username, encrypted_mek, salt_recovery_data, mek_recovery_nonce, encrypted_mek_recovery, token, instance, mek_nonce, salt_local, lock_timeout, recovery_backed_up,
+                kdf_local, kdf_recovery_data, kdf_password)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)", 
+            (&self.id, &self.username, &self.encrypted_mek, &self.salt_recovery_data, &self.mek_recovery_nonce, &self.encrypted_mek_recovery, &self.token, &self.instance,
+                &self.mek_nonce, &self.salt_local, &self.lock_timeout, &self.recovery_backed_up, &self.kdf_local, &self.kdf_recovery_data, &self.kdf_password)
         ).unwrap();
 
         Ok(())
@@ -180,27 +408,33 @@ impl User {
 
     pub fn select(conn: &Connection, username: String) -> Result<Option<Self>, Box<dyn std::error::Error>> {
         let user = match conn.query_one(
-            "SELECT * FROM user WHERE username = ?", 
+            &format!("SELECT {} FROM user WHERE username = ?", Self::COLUMNS), 
             (username,),
             |row| {
-                let mek: Vec<u8> = row.get(2)?;
-                let mek: [u8; 32] = mek.try_into().unwrap();
-                let mek: Key<Aes256Gcm> = mek.into();
-
                 Ok(User{
                     id: row.get(0)?,
                     username: row.get(1)?,
-                    master_encryption_key: mek,
+                    encrypted_mek: row.get(2)?,
                     salt_recovery_data: row.get(3)?,
                     mek_recovery_nonce: row.get(4)?,
                     encrypted_mek_recovery: row.get(5)?,
                     token: row.get(6)?,
-                    instance: row.get(7)?
+                    instance: row.get(7)?,
+                    mek_nonce: row.get(8)?,
+                    salt_local: row.get(9)?,
+                    lock_timeout: row.get(10)?,
+                    recovery_backed_up: row.get(11)?,
+                    kdf_local: row.get(12)?,
+                    kdf_recovery_data: row.get(13)?,
+                    kdf_password: row.get(14)?,
+                    master_encryption_key: None,
+                    local_key: None,
+                    pending_recovery_keys: Vec::new()
                 })
             }
         ) {
             Ok(v) => Some(v),
-            Err(e) if e == QueryReturnedNoRows => None,
+            Err(QueryReturnedNoRows) => None,
             Err(e) => return Err(e.into())
         };
 
@@ -208,24 +442,30 @@ impl User {
     }
 
     pub fn select_all(conn: &Connection) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
-        let mut stmt = conn.prepare("SELECT * FROM user").unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM user", Self::COLUMNS)).unwrap();
 
         let rows = stmt.query_map(
             [],
             |row| {
-                let mek: Vec<u8> = row.get(2)?;
-                let mek: [u8; 32] = mek.try_into().unwrap();
-                let mek: Key<Aes256Gcm> = mek.into();
-
                 Ok(User{
                     id: row.get(0)?,
                     username: row.get(1)?,
-                    master_encryption_key: mek,
+                    encrypted_mek: row.get(2)?,
                     salt_recovery_data: row.get(3)?,
                     mek_recovery_nonce: row.get(4)?,
                     encrypted_mek_recovery: row.get(5)?,
                     token: row.get(6)?,
-                    instance: row.get(7)?
+                    instance: row.get(7)?,
+                    mek_nonce: row.get(8)?,
+                    salt_local: row.get(9)?,
+                    lock_timeout: row.get(10)?,
+                    recovery_backed_up: row.get(11)?,
+                    kdf_local: row.get(12)?,
+                    kdf_recovery_data: row.get(13)?,
+                    kdf_password: row.get(14)?,
+                    master_encryption_key: None,
+                    local_key: None,
+                    pending_recovery_keys: Vec::new()
                 })
             }
         ).unwrap();
@@ -240,9 +480,650 @@ impl User {
     }
     
     pub fn update(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
-        conn.execute("UPDATE user SET username = ?, master_encryption_key = ?, salt_recovery_data = ?, mek_recovery_nonce = ?, encrypted_mek_recovery = ?, token = ?, instance = ? WHERE id = ?",
-        (&self.username, &self.master_encryption_key.to_vec(), &self.salt_recovery_data, &self.mek_recovery_nonce, &self.encrypted_mek_recovery, &self.token, &self.id, &self.instance))?;
+        conn.execute("UPDATE user SET username = ?, encrypted_mek = ?, salt_recovery_data = ?, mek_recovery_nonce = ?, encrypted_mek_recovery = ?, token = ?, instance = ?,
+                mek_nonce = ?, salt_local = ?, lock_timeout = ?, recovery_backed_up = ?,
+                kdf_local = ?, kdf_recovery_data = ?, kdf_password = ? WHERE id = ?",
+        (&self.username, &self.encrypted_mek, &self.salt_recovery_data, &self.mek_recovery_nonce, &self.encrypted_mek_recovery, &self.token, &self.instance,
+            &self.mek_nonce, &self.salt_local, &self.lock_timeout, &self.recovery_backed_up,
+            &self.kdf_local, &self.kdf_recovery_data, &self.kdf_password, &self.id))?;
         
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn delete(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM user WHERE id = ?", (&self.id,))?;
+
+        Ok(())
+    }
+
+    /// Plain text master encryption key kept by databases created before it was encrypted at rest, see `db::migrate`
+    pub fn select_legacy_mek(conn: &Connection, id: u32) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(conn.query_one("SELECT legacy_mek FROM user WHERE id = ?", (id,), |row| row.get(0))?)
+    }
+
+    pub fn delete_legacy_mek(conn: &Connection, id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE user SET legacy_mek = NULL WHERE id = ?", (id,))?;
+
+        Ok(())
+    }
+
+    /// Master encryption key of the user, only available while the app is unlocked
+    pub fn mek(&self) -> Result<&MasterKey, Box<dyn std::error::Error>> {
+        self.master_encryption_key.as_ref().ok_or_else(|| "App is locked".into())
+    }
+}
+
+/// Master encryption key rotation in progress, kept until the new key is used locally
+#[derive(Debug)]
+pub struct MekRotation {
+    pub id_user: u32,
+    pub id_server: Option<u64>, //id of the rotation on server, None if not started yet
+    pub encrypted_mek: Vec<u8>, //New master encryption key, encrypted with the current one
+    pub mek_nonce: Vec<u8>,
+    pub salt_data: String,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+    pub salt_recovery_data: String,
+    pub encrypted_mek_recovery: Vec<u8>,
+    pub mek_recovery_nonce: Vec<u8>,
+    pub committed: bool, //true: server already uses the new key
+    pub kdf_recovery_data: String,
+}
+
+impl MekRotation {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS mek_rotation (
+                id_user INTEGER PRIMARY KEY REFERENCES user(id),
+                id_server INTEGER,
+                encrypted_mek BLOB NOT NULL,
+                mek_nonce BLOB NOT NULL,
+                salt_data TEXT NOT NULL,
+                encrypted_mek_password BLOB NOT NULL,
+                mek_password_nonce BLOB NOT NULL,
+                salt_recovery_data TEXT NOT NULL,
+                encrypted_mek_recovery BLOB NOT NULL,
+                mek_recovery_nonce BLOB NOT NULL,
+                committed INTEGER NOT NULL,
+                kdf_recovery_data TEXT NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        //Notes already uploaded to server in the current rotation
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS mek_rotation_note (
+                id_note INTEGER PRIMARY KEY REFERENCES note(id)
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    pub fn insert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO mek_rotation (id_user, id_server, encrypted_mek, mek_nonce, salt_data, encrypted_mek_password, mek_password_nonce,
+                salt_recovery_data, encrypted_mek_recovery, mek_recovery_nonce, committed, kdf_recovery_data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            (&self.id_user, &self.id_server, &self.encrypted_mek, &self.mek_nonce, &self.salt_data, &self.encrypted_mek_password, &self.mek_password_nonce,
+                &self.salt_recovery_data, &self.encrypted_mek_recovery, &self.mek_recovery_nonce, &self.committed, &self.kdf_recovery_data)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id_user: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let rotation = match conn.query_one(
+            "SELECT * FROM mek_rotation WHERE id_user = ?",
+            (id_user,),
+            |row| {
+                Ok(MekRotation{
+                    id_user: row.get(0)?,
+                    id_server: row.get(1)?,
+                    encrypted_mek: row.get(2)?,
+                    mek_nonce: row.get(3)?,
+                    salt_data: row.get(4)?,
+                    encrypted_mek_password: row.get(5)?,
+                    mek_password_nonce: row.get(6)?,
+                    salt_recovery_data: row.get(7)?,
+                    encrypted_mek_recovery: row.get(8)?,
+                    mek_recovery_nonce: row.get(9)?,
+                    committed: row.get(10)?,
+                    kdf_recovery_data: row.get(11)?
+                })
+            }
+        ) {
+            Ok(v) => Some(v),
+            Err(QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(rotation)
+    }
+
+    pub fn update(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE mek_rotation SET id_server = ?, committed = ? WHERE id_user = ?",
+            (&self.id_server, &self.committed, &self.id_user))?;
+
+        Ok(())
+    }
+
+    /// Delete the rotation and its progress
+    pub fn delete(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        Self::clear_notes(conn)?;
+        conn.execute("DELETE FROM mek_rotation WHERE id_user = ?", (&self.id_user,))?;
+
+        Ok(())
+    }
+
+    pub fn insert_note(conn: &Connection, id_note: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("INSERT OR IGNORE INTO mek_rotation_note (id_note) VALUES (?)", (id_note,))?;
+
+        Ok(())
+    }
+
+    pub fn clear_notes(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM mek_rotation_note", ())?;
+
+        Ok(())
+    }
+}
+/// Metadata of an instance the user entered, refreshed before every login
+#[derive(Debug)]
+pub struct Instance {
+    pub url: String,
+    pub info: shared::InstanceInfo,
+    pub checked_at: i64,
+}
+
+impl Instance {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS instance (
+                url TEXT PRIMARY KEY,
+                info TEXT NOT NULL,
+                checked_at INTEGER NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    /// `info` is stored as json
+    pub fn upsert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO instance (url, info, checked_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(url) DO UPDATE SET info = excluded.info, checked_at = excluded.checked_at",
+            (&self.url, serde_json::to_string(&self.info)?, &self.checked_at)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, url: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let instance = match conn.query_one(
+            "SELECT * FROM instance WHERE url = ?",
+            (url,),
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?))
+        ) {
+            Ok((url, info, checked_at)) => Some(Instance {
+                url,
+                info: serde_json::from_str(&info)?,
+                checked_at
+            }),
+            Err(QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(instance)
+    }
+}
+
+/// Position of the last note received from the server, the download continues from it after an interruption
+#[derive(Debug)]
+pub struct SyncCursor {
+    pub id_user: u32,
+    pub updated_at: i64,
+    pub id_note: u64, //Server id of the note
+}
+
+impl SyncCursor {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS sync_cursor (
+                id_user INTEGER PRIMARY KEY REFERENCES user(id),
+                updated_at INTEGER NOT NULL,
+                id_note INTEGER NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    pub fn upsert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO sync_cursor (id_user, updated_at, id_note) VALUES (?1, ?2, ?3)
+                ON CONFLICT(id_user) DO UPDATE SET updated_at = excluded.updated_at, id_note = excluded.id_note",
+            (&self.id_user, &self.updated_at, &self.id_note)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id_user: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let cursor = match conn.query_one(
+            "SELECT * FROM sync_cursor WHERE id_user = ?",
+            (id_user,),
+            |row| Ok(SyncCursor {
+                id_user: row.get(0)?,
+                updated_at: row.get(1)?,
+                id_note: row.get(2)?
+            })
+        ) {
+            Ok(v) => Some(v),
+            Err(QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into())
+        };
+
+        Ok(cursor)
+    }
+
+    pub fn delete(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM sync_cursor WHERE id_user = ?1", (id_user,))?;
+
+        Ok(())
+    }
+}
+
+impl From<SyncCursor> for shared::NoteCursor {
+    fn from(cursor: SyncCursor) -> Self {
+        shared::NoteCursor {
+            updated_at: cursor.updated_at,
+            id: cursor.id_note,
+        }
+    }
+}
+
+/// Attachment of a local note, see `crypt::encrypt_attachment`
+#[derive(Debug)]
+pub struct Attachment {
+    pub id: Option<u32>,
+    pub id_server: Option<u64>,
+    pub id_note: u32,
+    pub encrypted_key: Vec<u8>, //Attachment key wrapped with the master encryption key
+    pub key_nonce: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub metadata_nonce: Vec<u8>,
+    pub synched: bool //true: attachment has already been sent with server
+}
+
+impl Attachment {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachment (
+                id INTEGER PRIMARY KEY,
+                id_server INTEGER UNIQUE,
+                id_note INTEGER NOT NULL REFERENCES note(id),
+                encrypted_key BLOB NOT NULL,
+                key_nonce BLOB NOT NULL,
+                metadata BLOB NOT NULL,
+                metadata_nonce BLOB NOT NULL,
+                synched INTEGER NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS attachment_chunk (
+                id_attachment INTEGER NOT NULL REFERENCES attachment(id),
+                position INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                data BLOB,
+                PRIMARY KEY (id_attachment, position)
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    /// Insert the attachment, return its id
+    pub fn insert(&self, conn: &Connection) -> Result<u32, Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO attachment (id_server, id_note, encrypted_key, key_nonce, metadata, metadata_nonce, synched) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            (&self.id_server, &self.id_note, &self.encrypted_key, &self.key_nonce, &self.metadata, &self.metadata_nonce, &self.synched)
+        )?;
+
+        Ok(conn.last_insert_rowid() as u32)
+    }
+
+    pub fn update(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE attachment SET id_server = ?, encrypted_key = ?, key_nonce = ?, synched = ? WHERE id = ?",
+            (&self.id_server, &self.encrypted_key, &self.key_nonce, &self.synched, &self.id))?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(Self::query(conn, "SELECT * FROM attachment WHERE id = ?", id)?.pop())
+    }
+
+    pub fn select_by_server_id(conn: &Connection, id_server: u64) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(Self::query(conn, "SELECT * FROM attachment WHERE id_server = ?", id_server)?.pop())
+    }
+
+    pub fn select_all_from_note(conn: &Connection, id_note: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT * FROM attachment WHERE id_note = ?", id_note)
+    }
+
+    pub fn select_all(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT attachment.* FROM attachment JOIN note ON note.id = attachment.id_note WHERE note.id_user = ?", id_user)
+    }
+
+    /// Attachments not uploaded yet whose note is known by the server
+    pub fn select_all_not_synched(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT attachment.* FROM attachment JOIN note ON note.id = attachment.id_note
+            WHERE note.id_user = ? AND note.id_server IS NOT NULL AND NOT attachment.synched", id_user)
+    }
+
+    fn query(conn: &Connection, sql: &str, param: impl rusqlite::ToSql) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare(sql)?;
+
+        let rows = stmt.query_map(
+            [param],
+            |row| {
+                Ok(Attachment {
+                    id: row.get(0)?,
+                    id_server: row.get(1)?,
+                    id_note: row.get(2)?,
+                    encrypted_key: row.get(3)?,
+                    key_nonce: row.get(4)?,
+                    metadata: row.get(5)?,
+                    metadata_nonce: row.get(6)?,
+                    synched: row.get(7)?,
+                })
+            }
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn delete(conn: &Connection, id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM attachment_chunk WHERE id_attachment = ?", (id,))?;
+        conn.execute("DELETE FROM attachment WHERE id = ?", (id,))?;
+
+        Ok(())
+    }
+
+    pub fn delete_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM attachment_chunk WHERE id_attachment IN
+            (SELECT attachment.id FROM attachment JOIN note ON note.id = attachment.id_note WHERE note.id_user = ?)", (id_user,))?;
+        conn.execute("DELETE FROM attachment WHERE id_note IN (SELECT id FROM note WHERE id_user = ?)", (id_user,))?;
+
+        Ok(())
+    }
+
+    /// Forget the server ids of the attachments of the user so they are uploaded again.
+    /// Attachments that were never downloaded can't be uploaded again and are deleted.
+    pub fn detach_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare("SELECT DISTINCT attachment.id FROM attachment
+            JOIN note ON note.id = attachment.id_note
+            JOIN attachment_chunk ON attachment_chunk.id_attachment = attachment.id
+            WHERE note.id_user = ? AND attachment_chunk.data IS NULL")?;
+
+        let not_downloaded: Vec<u32> = stmt.query_map([id_user], |row| row.get(0))?.collect::<Result<_, _>>()?;
+
+        for id in not_downloaded {
+            Self::delete(conn, id)?;
+        }
+
+        conn.execute("UPDATE attachment SET id_server = NULL, synched = FALSE WHERE id_note IN (SELECT id FROM note WHERE id_user = ?)", (id_user,))?;
+
+        Ok(())
+    }
+}
+
+/// Encrypted chunk of an attachment, `data` is None until it is downloaded
+#[derive(Debug)]
+pub struct AttachmentChunk {
+    pub id_attachment: u32,
+    pub position: u32,
+    pub hash: String,
+    pub data: Option<Vec<u8>>,
+}
+
+impl AttachmentChunk {
+    pub fn insert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO attachment_chunk (id_attachment, position, hash, data) VALUES (?1, ?2, ?3, ?4)",
+            (&self.id_attachment, &self.position, &self.hash, &self.data)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn update(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("UPDATE attachment_chunk SET data = ? WHERE id_attachment = ? AND position = ?",
+            (&self.data, &self.id_attachment, &self.position))?;
+
+        Ok(())
+    }
+
+    /// Chunks of the attachment in order
+    pub fn select_all(conn: &Connection, id_attachment: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare("SELECT * FROM attachment_chunk WHERE id_attachment = ? ORDER BY position")?;
+
+        let rows = stmt.query_map(
+            [id_attachment],
+            |row| {
+                Ok(AttachmentChunk {
+                    id_attachment: row.get(0)?,
+                    position: row.get(1)?,
+                    hash: row.get(2)?,
+                    data: row.get(3)?,
+                })
+            }
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// Identity keys of a local user, see `crypt::create_identity`
+#[derive(Debug)]
+pub struct Identity {
+    pub id_user: u32,
+    pub x25519_public: Vec<u8>,
+    pub ed25519_public: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub encrypted_keys: Vec<u8>, //Private keys, encrypted with the master encryption key
+    pub keys_nonce: Vec<u8>,
+}
+
+impl Identity {
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS identity (
+                id_user INTEGER PRIMARY KEY REFERENCES user(id),
+                x25519_public BLOB NOT NULL,
+                ed25519_public BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                encrypted_keys BLOB NOT NULL,
+                keys_nonce BLOB NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    pub fn upsert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+            "INSERT INTO identity (id_user, x25519_public, ed25519_public, signature, encrypted_keys, keys_nonce) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT(id_user) DO UPDATE SET x25519_public = excluded.x25519_public, ed25519_public = excluded.ed25519_public,
+                    signature = excluded.signature, encrypted_keys = excluded.encrypted_keys, keys_nonce = excluded.keys_nonce",
+            (&self.id_user, &self.x25519_public, &self.ed25519_public, &self.signature, &self.encrypted_keys, &self.keys_nonce)
+        )?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id_user: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let identity = conn.query_one(
+            "SELECT * FROM identity WHERE id_user = ?",
+            (id_user,),
+            |row| {
+                Ok(Identity {
+                    id_user: row.get(0)?,
+                    x25519_public: row.get(1)?,
+                    ed25519_public: row.get(2)?,
+                    signature: row.get(3)?,
+                    encrypted_keys: row.get(4)?,
+                    keys_nonce: row.get(5)?,
+                })
+            }
+        );
+
+        match identity {
+            Ok(identity) => Ok(Some(identity)),
+            Err(QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn delete(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM identity WHERE id_user = ?", (id_user,))?;
+
+        Ok(())
+    }
+}
+
+impl From<Identity> for shared::Identity {
+    fn from(identity: Identity) -> Self {
+        shared::Identity {
+            x25519_public: identity.x25519_public,
+            ed25519_public: identity.ed25519_public,
+            signature: identity.signature,
+            encrypted_keys: identity.encrypted_keys,
+            keys_nonce: identity.keys_nonce
+        }
+    }
+}
+
+/// Key of a shared note, see `db::operations::note_key`.
+/// Keys of notes owned by the user are wrapped with its master encryption key,
+/// keys of notes shared with the user are wrapped with a key agreed with the identity of the owner.
+#[derive(Debug)]
+pub struct NoteKey {
+    pub id_note: u32,
+    pub version: u32,
+    pub encrypted_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+    pub owner: Option<shared::PublicIdentity>, //None when the user owns the note
+    pub permission: shared::SharePermission,
+}
+
+impl NoteKey {
+    /// Key of the local note `id_note`, as sent by the server
+    pub fn new(id_note: u32, key: shared::NoteKey) -> Self {
+        NoteKey {
+            id_note,
+            version: key.version,
+            encrypted_key: key.encrypted_key,
+            key_nonce: key.key_nonce,
+            owner: key.owner,
+            permission: key.permission,
+        }
+    }
+
+    pub fn create(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute(
+        "CREATE TABLE IF NOT EXISTS note_key (
+                id_note INTEGER PRIMARY KEY REFERENCES note(id),
+                version INTEGER NOT NULL,
+                encrypted_key BLOB NOT NULL,
+                key_nonce BLOB NOT NULL,
+                owner TEXT,
+                owner_x25519_public BLOB,
+                owner_ed25519_public BLOB,
+                owner_signature BLOB,
+                permission TEXT NOT NULL
+            )",
+            (),
+        ).unwrap();
+
+        Ok(())
+    }
+
+    pub fn upsert(&self, conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+        let owner = self.owner.as_ref();
+
+        conn.execute(
+            "INSERT INTO note_key (id_note, version, encrypted_key, key_nonce, owner, owner_x25519_public, owner_ed25519_public, owner_signature, permission)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                ON CONFLICT(id_note) DO UPDATE SET version = excluded.version, encrypted_key = excluded.encrypted_key, key_nonce = excluded.key_nonce,
+                    owner = excluded.owner, owner_x25519_public = excluded.owner_x25519_public, owner_ed25519_public = excluded.owner_ed25519_public,
+                    owner_signature = excluded.owner_signature, permission = excluded.permission",
+            (&self.id_note, &self.version, &self.encrypted_key, &self.key_nonce, owner.map(|o| &o.username), owner.map(|o| &o.x25519_public),
+                owner.map(|o| &o.ed25519_public), owner.map(|o| &o.signature), self.permission.to_string())
+        )?;
+
+        Ok(())
+    }
+
+    pub fn select(conn: &Connection, id_note: u32) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        Ok(Self::query(conn, "SELECT * FROM note_key WHERE id_note = ?", id_note)?.pop())
+    }
+
+    /// Keys of the notes owned by the user
+    pub fn select_all_owned(conn: &Connection, id_user: u32) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        Self::query(conn, "SELECT note_key.* FROM note_key JOIN note ON note.id = note_key.id_note WHERE note.id_user = ? AND note_key.owner IS NULL", id_user)
+    }
+
+    fn query(conn: &Connection, sql: &str, param: impl rusqlite::ToSql) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let mut stmt = conn.prepare(sql)?;
+
+        let rows = stmt.query_map(
+            [param],
+            |row| {
+                let owner = match row.get::<_, Option<String>>(4)? {
+                    Some(username) => Some(shared::PublicIdentity {
+                        username,
+                        x25519_public: row.get(5)?,
+                        ed25519_public: row.get(6)?,
+                        signature: row.get(7)?,
+                    }),
+                    None => None
+                };
+
+                let permission = row.get::<_, String>(8)?.parse()
+                    .map_err(|e: String| rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, e.into()))?;
+
+                Ok(NoteKey {
+                    id_note: row.get(0)?,
+                    version: row.get(1)?,
+                    encrypted_key: row.get(2)?,
+                    key_nonce: row.get(3)?,
+                    owner,
+                    permission,
+                })
+            }
+        )?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    pub fn delete(conn: &Connection, id_note: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note_key WHERE id_note = ?", (id_note,))?;
+
+        Ok(())
+    }
+
+    pub fn delete_all(conn: &Connection, id_user: u32) -> Result<(), Box<dyn std::error::Error>> {
+        conn.execute("DELETE FROM note_key WHERE id_note IN (SELECT id FROM note WHERE id_user = ?)", (id_user,))?;
+
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 // #[tauri::command(rename_all = "snake_case")]
 
-use std::{time::Duration, thread::sleep};
+use std::{time::{Duration, Instant}, thread::sleep};
 
 use tokio::{sync::Mutex};
 
@@ -21,6 +21,8 @@ mod sync;
 pub struct AppState {
   database: Mutex<Connection>,
   user: Option<db::schema::User>,
+  last_activity: Instant, //Last command using the master encryption key, used to lock the app after user.lock_timeout
+  usage: Option<shared::Usage>, //Last storage usage reported by the instance of the user
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -37,7 +39,9 @@ pub fn run() {
 
             let app_state = Mutex::new(AppState{ 
                 database: db::init(db_path).unwrap(),
-                user: None
+                user: None,
+                last_activity: Instant::now(),
+                usage: None
             });
 
             let app_handle_clone = app.app_handle().clone();
@@ -52,12 +56,42 @@ pub fn run() {
             commands::create_note,
             commands::get_note,
             commands::edit_note,
+            commands::get_note_snapshots,
+            commands::get_note_snapshot,
+            commands::diff_note_snapshot,
+            commands::restore_note_snapshot,
+            commands::get_note_revisions,
+            commands::get_note_revision,
+            commands::restore_note_revision,
+            commands::get_note_shares,
+            commands::share_note,
+            commands::unshare_note,
             commands::get_all_notes_metadata,
+            commands::add_attachment,
+            commands::get_attachments,
+            commands::get_attachment,
+            commands::delete_attachment,
+            commands::get_usage,
+            commands::get_fingerprint,
+            commands::get_safety_number,
             commands::create_user,
             commands::get_users,
             commands::set_user,
+            commands::check_instance,
+            commands::get_instance,
             commands::sync_create_account,
             commands::sync_login,
+            commands::rotate_mek,
+            commands::delete_account,
+            commands::totp_enroll,
+            commands::totp_confirm,
+            commands::unlock,
+            commands::lock,
+            commands::set_lock_timeout,
+            commands::recovery_key_challenge,
+            commands::confirm_recovery_key,
+            commands::validate_recovery_key,
+            commands::recover_user,
             commands::test,
             ])
         .run(tauri::generate_context!())
@@ -1,38 +1,134 @@
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use reqwest::StatusCode;
 use rusqlite::Connection;
-use tokio::sync::Mutex;
-use crate::{crypt, schema::User};
-use tauri_plugin_log::log::{trace, debug};
+use secrecy::SecretString;
+use shared::KdfParams;
+use tokio::sync::{Mutex, MutexGuard};
+use crate::{AppState, crypt::{self, MasterKey}, db::{self, schema::{AttachmentChunk, Identity, MekRotation, Note, NoteKey, SyncCursor}}, schema::User};
+use tauri_plugin_log::log::{trace, debug, error};
+use zeroize::Zeroizing;
 
 mod operations;
 pub mod service;
 
-pub async fn create_account(user: User, account: crypt::AccountEncryptionData, instance: Option<String>){
-    let instance = match instance {
-        Some(i) => i,
-        None => "http://localhost:3000".to_string()
-    };
+pub const DEFAULT_INSTANCE: &str = "http://localhost:3000";
+
+/// Instance entered by the user, or the default one
+pub fn instance_url(instance: Option<String>) -> String {
+    instance.unwrap_or_else(|| DEFAULT_INSTANCE.to_string())
+}
+
+pub async fn instance_info(instance: String) -> Result<shared::InstanceInfo, Box<dyn std::error::Error>> {
+    operations::instance_info(instance).await
+}
+
+/// Return the token of the session opened by the server. `invite_code` is needed when the instance is invite-only.
+/// The password is registered with OPAQUE right after, the login hash is then refused by the server.
+pub async fn create_account(user: &User, account: crypt::AccountEncryptionData, password: &SecretString, invite_code: Option<String>, instance: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let login_hash = account.stored_password_hash.clone();
+    let kdf_password = account.kdf_password.clone();
 
     let send_user = shared::User {
         id: None,
-        username: user.username,
+        username: user.username.clone(),
         stored_password_hash: account.stored_password_hash,
         stored_recovery_hash: account.stored_recovery_hash,
         encrypted_mek_password: account.encrypted_mek_password,
         mek_password_nonce: account.mek_password_nonce,
-        encrypted_mek_recovery: user.encrypted_mek_recovery,
-        mek_recovery_nonce: user.mek_recovery_nonce,
+        encrypted_mek_recovery: user.encrypted_mek_recovery.clone(),
+        mek_recovery_nonce: user.mek_recovery_nonce.clone(),
         salt_auth: account.salt_auth.to_string(),
         salt_data: account.salt_data.to_string(),
         salt_recovery_auth: account.salt_recovery_auth.to_string(),
         salt_recovery_data: user.salt_recovery_data.to_string(),
         salt_server_auth: account.salt_server_auth.to_string(),
         salt_server_recovery: account.salt_server_recovery.to_string(),
+        salt_server_mek: account.salt_server_mek.to_string(),
+        stored_mek_hash: account.stored_mek_hash,
+        kdf_password: account.kdf_password,
+        kdf_recovery_auth: account.kdf_recovery_auth,
+        kdf_recovery_data: crypt::parse_kdf(&user.kdf_recovery_data)?,
+        invite_code,
+    };
+
+    let token = operations::create_account(send_user, instance.clone()).await?.token;
+
+    //The account works with the login hash until it is registered, it is done again at the next login if it fails
+    if let Err(e) = opaque_register(user.username.clone(), token.clone(), password, &kdf_password, login_hash, instance).await {
+        error!("OPAQUE registration failed: {e}");
+    }
+
+    Ok(token)
+}
+
+/// Replace the login hash stored by the server with an OPAQUE record, `login_hash` is checked one last time
+async fn opaque_register(username: String, token: Vec<u8>, password: &SecretString, kdf_password: &KdfParams, login_hash: String, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let registration_upload = opaque_registration(username.clone(), token.clone(), password, kdf_password, instance.clone()).await?;
+
+    let params = shared::OpaqueRegistrationFinish {
+        username,
+        token,
+        login_hash,
+        registration_upload
+    };
+
+    operations::opaque_register_finish(params, instance).await
+}
+
+/// Build the OPAQUE record of the password stretched with `kdf_password`, it is stored by the caller
+async fn opaque_registration(username: String, token: Vec<u8>, password: &SecretString, kdf_password: &KdfParams, instance: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (state, registration_request) = crypt::opaque_registration_start(password)?;
+
+    let params = shared::OpaqueRegistrationStart {
+        username,
+        token,
+        registration_request
     };
 
-    operations::create_account(send_user, instance).await.unwrap();
+    let response = operations::opaque_register_start(params, instance).await?;
+
+    Ok(crypt::opaque_registration_finish(state, password, &response.registration_response, kdf_password)?)
 }
 
-pub async fn login(username: String, password: String, instance: String) -> shared::Login{
+/// Run an OPAQUE login up to the proof of the password, return it with the session key
+async fn opaque_proof(username: String, password: &SecretString, kdf_password: &KdfParams, instance: String) -> Result<(shared::OpaqueProof, Zeroizing<Vec<u8>>), Box<dyn std::error::Error>> {
+    let (state, credential_request) = crypt::opaque_login_start(password)?;
+
+    let params = shared::OpaqueLoginStart {
+        username,
+        credential_request
+    };
+
+    let response = operations::opaque_login_start(params, instance).await?;
+    let (credential_finalization, session_key) = crypt::opaque_login_finish(state, password, &response.credential_response, kdf_password)?;
+
+    let proof = shared::OpaqueProof {
+        id_login: response.id_login,
+        credential_finalization
+    };
+
+    Ok((proof, session_key))
+}
+
+/// Proof of the password asked again by the server: an OPAQUE login for accounts using it, the login hash otherwise
+async fn password_proof(username: String, password: &SecretString, instance: String) -> Result<(String, Option<shared::OpaqueProof>), Box<dyn std::error::Error>> {
+    let request_params = shared::LoginRequestParams {
+        username: username.clone()
+    };
+
+    let login_request = operations::login_request(request_params, instance.clone()).await?;
+
+    if login_request.opaque == Some(true) {
+        let (proof, _) = opaque_proof(username, password, &login_request.kdf_password, instance).await?;
+
+        return Ok((String::new(), Some(proof)));
+    }
+
+    Ok((crypt::login(login_request, password)?, None))
+}
+
+/// `totp_code` is a TOTP code or a backup code, the server answers 428 if it is missing and two-factor authentication is enabled
+pub async fn login(username: String, password: &SecretString, totp_code: Option<String>, instance: String) -> Result<shared::Login, Box<dyn std::error::Error>> {
 
 
     trace!("requesting login...");
@@ -41,18 +137,491 @@ pub async fn login(username: String, password: String, instance: String) -> shar
         username: username.clone()
     };
     
-    let login_request = operations::login_request(request_params, instance.clone()).await.unwrap();
-    
+    let login_request = operations::login_request(request_params, instance.clone()).await?;
+
+    if login_request.opaque == Some(true) {
+        trace!("logging in with OPAQUE...");
+        let (proof, session_key) = opaque_proof(username.clone(), password, &login_request.kdf_password, instance.clone()).await?;
+
+        let params = shared::OpaqueLoginFinish {
+            username,
+            proof,
+            totp_code
+        };
+
+        let login = operations::opaque_login_finish(params, instance).await?;
+
+        return Ok(login.into_login(shared::opaque::session_token(&session_key)));
+    }
+
     trace!("hashing login...");
-    //Hash
-    let login_hash = crypt::login(login_request, password);
-    
+    //Hash, only for accounts created before OPAQUE or on servers without it
+    let migrate_opaque = login_request.opaque == Some(false);
+    let kdf_password = login_request.kdf_password.clone();
+    let login_hash = crypt::login(login_request, password)?;
+
     //Login
     trace!("loggin in...");
     let login_params = shared::LoginParams {
+        username: username.clone(),
+        login_hash: login_hash.clone(),
+        totp_code
+    };
+
+    let login = operations::login(login_params, instance.clone()).await?;
+
+    //Migrate the account, the login hash is refused once it is done
+    if migrate_opaque {
+        match opaque_register(username, login.token.clone(), password, &kdf_password, login_hash, instance).await {
+            Ok(()) => debug!("account migrated to OPAQUE"),
+            Err(e) => error!("OPAQUE registration failed: {e}")
+        }
+    }
+
+    Ok(login)
+}
+
+/// Generate a TOTP secret on the server, two-factor authentication is enabled once a code is confirmed
+pub async fn totp_enroll(username: String, token: Vec<u8>, instance: String) -> Result<shared::TotpEnrollment, Box<dyn std::error::Error>> {
+    let params = shared::TotpEnrollParams {
         username,
-        login_hash
+        token
     };
-    
-    operations::login(login_params, instance).await.unwrap()
+
+    operations::totp_enroll(params, instance).await
+}
+
+/// Enable two-factor authentication, return the backup codes
+pub async fn totp_confirm(username: String, token: Vec<u8>, code: String, instance: String) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let params = shared::TotpConfirmParams {
+        username,
+        token,
+        code
+    };
+
+    Ok(operations::totp_confirm(params, instance).await?.codes)
+}
+
+/// Delete the account and every note stored on the server, the password is checked again
+pub async fn delete_account(username: String, token: Vec<u8>, password: &SecretString, instance: String) -> Result<shared::DeletionReceipt, Box<dyn std::error::Error>> {
+    let (login_hash, opaque) = password_proof(username.clone(), password, instance.clone()).await?;
+
+    let params = shared::DeleteUserParams {
+        username,
+        token,
+        login_hash,
+        opaque
+    };
+
+    operations::delete_user(params, instance).await
+}
+
+/// Start a master encryption key rotation on the server and return its id, the password is checked first
+/// since the new master encryption key is wrapped with it
+pub async fn start_mek_rotation(username: String, token: Vec<u8>, password: &SecretString, instance: String) -> Result<u64, Box<dyn std::error::Error>> {
+    let (login_hash, opaque) = password_proof(username.clone(), password, instance.clone()).await?;
+
+    let params = shared::MekRotationParams {
+        username,
+        token,
+        login_hash,
+        opaque
+    };
+
+    Ok(operations::start_mek_rotation(params, instance).await?.id)
+}
+
+/// Derive the password again with stronger parameters, every password salt is renewed.
+/// The password is checked with the current parameters first, the OPAQUE record is registered again with the new ones.
+pub async fn upgrade_kdf(username: String, token: Vec<u8>, password: &SecretString, mek: &MasterKey, kdf_password: &KdfParams, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let (login_hash, opaque) = password_proof(username.clone(), password, instance.clone()).await?;
+
+    let registration_upload = match opaque {
+        Some(_) => Some(opaque_registration(username.clone(), token.clone(), password, kdf_password, instance.clone()).await?),
+        None => None
+    };
+
+    let password_data = crypt::encrypt_password(password, mek, kdf_password)?;
+
+    let upgrade = shared::KdfUpgrade {
+        username,
+        token,
+        login_hash,
+        opaque,
+        registration_upload,
+        kdf_password: password_data.kdf_password,
+        salt_auth: password_data.salt_auth.to_string(),
+        salt_server_auth: password_data.salt_server_auth.to_string(),
+        stored_password_hash: password_data.stored_password_hash,
+        salt_data: password_data.salt_data.to_string(),
+        encrypted_mek_password: password_data.encrypted_mek_password,
+        mek_password_nonce: password_data.mek_password_nonce,
+    };
+
+    operations::upgrade_kdf(upgrade, instance).await
+}
+
+/// Decrypt the master encryption key with the data recovery key and get a token without the password.
+/// `totp_code` is asked like for `login` when two-factor authentication is enabled.
+pub async fn recover(username: String, recovery_key_data: &SecretString, totp_code: Option<String>, instance: String) -> Result<(shared::DataRecoveryRequest, MasterKey, shared::Login), Box<dyn std::error::Error>> {
+    trace!("requesting data recovery...");
+    let request_params = shared::LoginRequestParams {
+        username: username.clone()
+    };
+
+    let recovery_request = operations::data_recovery_request(request_params, instance.clone()).await?;
+
+    let mek = crypt::decrypt_mek_recovery(recovery_key_data, &recovery_request.encrypted_mek_recovery,
+        &recovery_request.salt_recovery_data, &recovery_request.mek_recovery_nonce, &recovery_request.kdf_recovery_data)?;
+    let mek_hash = crypt::mek_hash_from_b64(&mek, &recovery_request.salt_server_mek, &recovery_request.kdf_recovery_data)?;
+
+    trace!("recovering account...");
+    let params = shared::DataRecoveryParams {
+        username,
+        mek_hash,
+        totp_code
+    };
+
+    let login = operations::data_recovery(params, instance).await?;
+
+    Ok((recovery_request, mek, login))
+}
+
+/// Bytes and notes stored on the instance and the limits of the user
+pub async fn usage(username: String, token: Vec<u8>, instance: String) -> Result<shared::Usage, Box<dyn std::error::Error>> {
+    let params = shared::UsageParams {
+        username,
+        token: hex::encode(token)
+    };
+
+    operations::usage(params, instance).await
+}
+
+fn identity_params(username: String, token: &[u8]) -> shared::IdentityParams {
+    shared::IdentityParams {
+        username,
+        token: hex::encode(token)
+    }
+}
+
+/// Identity published by the user, None if it has never been published
+pub async fn identity(username: String, token: Vec<u8>, instance: String) -> Result<Option<shared::Identity>, Box<dyn std::error::Error>> {
+    match operations::select_identity(&identity_params(username, &token), instance).await {
+        Ok(identity) => Ok(Some(identity)),
+        Err(e) if is_status(&*e, StatusCode::NOT_FOUND) => Ok(None),
+        Err(e) => Err(e)
+    }
+}
+
+/// Publish the identity of the user, false if another device published one first
+pub async fn publish_identity(username: String, token: Vec<u8>, identity: shared::Identity, instance: String) -> Result<bool, Box<dyn std::error::Error>> {
+    let sent = shared::SentIdentity {
+        username,
+        token,
+        identity
+    };
+
+    match operations::send_identity(sent, instance).await {
+        Ok(()) => Ok(true),
+        Err(e) if is_status(&*e, StatusCode::CONFLICT) => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+/// Public keys of another user, refused if they aren't signed by its identity
+pub async fn public_identity(username: String, token: Vec<u8>, contact: &str, instance: String) -> Result<shared::PublicIdentity, Box<dyn std::error::Error>> {
+    let identity = operations::select_public_identity(&identity_params(username, &token), contact, instance).await?;
+
+    crypt::verify_identity(&identity)?;
+
+    Ok(identity)
+}
+
+fn note_revision_params(username: String, token: &[u8]) -> shared::NoteRevisionParams {
+    shared::NoteRevisionParams {
+        username,
+        token: hex::encode(token)
+    }
+}
+
+/// Previous versions of a note kept by the server, most recent first
+pub async fn select_note_revisions(username: String, token: Vec<u8>, id_note: u64, instance: String) -> Result<Vec<shared::NoteRevision>, Box<dyn std::error::Error>> {
+    operations::select_note_revisions(&note_revision_params(username, &token), id_note, instance).await
+}
+
+pub async fn select_note_revision(username: String, token: Vec<u8>, id_note: u64, id_revision: u64, instance: String) -> Result<shared::NoteRevisionContent, Box<dyn std::error::Error>> {
+    operations::select_note_revision(&note_revision_params(username, &token), id_note, id_revision, instance).await
+}
+
+fn note_share_params(username: String, token: &[u8]) -> shared::NoteShareParams {
+    shared::NoteShareParams {
+        username,
+        token: hex::encode(token)
+    }
+}
+
+/// Users a note of the user is shared with
+pub async fn select_note_shares(username: String, token: Vec<u8>, id_note: u64, instance: String) -> Result<Vec<shared::NoteShare>, Box<dyn std::error::Error>> {
+    operations::select_note_shares(&note_share_params(username, &token), id_note, instance).await
+}
+
+/// Replace the key of a note and the users it is shared with, return the note as stored by the server.
+/// The server refuses it with 409 if the note changed since the version `rekey` was encrypted from.
+pub async fn rekey_note(rekey: shared::NoteRekey, id_note: u64, instance: String) -> Result<shared::Note, Box<dyn std::error::Error>> {
+    operations::update_note_shares(rekey, id_note, instance).await
+}
+
+fn attachment_params(username: String, token: &[u8], id_note: Option<u64>) -> shared::AttachmentParams {
+    shared::AttachmentParams {
+        username,
+        token: hex::encode(token),
+        id_note
+    }
+}
+
+/// Attachments of a note stored on the server, their chunks are only downloaded when opened
+pub async fn select_attachments(username: String, token: Vec<u8>, id_note: u64, instance: String) -> Result<Vec<shared::Attachment>, Box<dyn std::error::Error>> {
+    operations::select_attachments(&attachment_params(username, &token, Some(id_note)), instance).await
+}
+
+/// Download a chunk, it is refused if it doesn't match its hash
+pub async fn download_attachment_chunk(username: String, token: Vec<u8>, hash: &str, instance: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let chunk = operations::download_attachment_chunk(&attachment_params(username, &token, None), hash, instance).await?;
+
+    if crypt::chunk_hash(&chunk) != hash {
+        return Err(crypt::CryptError::TamperedCiphertext.into());
+    }
+
+    Ok(chunk)
+}
+
+/// Upload the chunks of an attachment then the attachment itself, return its server id.
+/// Chunks already stored by the server are kept once, an interrupted upload can be sent again.
+pub async fn upload_attachment(username: String, token: Vec<u8>, attachment: shared::Attachment, chunks: Vec<AttachmentChunk>, instance: String) -> Result<u64, Box<dyn std::error::Error>> {
+    let params = attachment_params(username.clone(), &token, None);
+
+    for chunk in chunks {
+        let data = chunk.data.ok_or("attachment has not been downloaded")?;
+        operations::upload_attachment_chunk(&params, &chunk.hash, data, instance.clone()).await?;
+    }
+
+    let sent = shared::SentAttachment {
+        username,
+        token,
+        attachment
+    };
+
+    let attachment = operations::send_attachment(sent, instance).await?;
+
+    Ok(attachment.id.unwrap())
+}
+
+pub async fn delete_attachment(username: String, token: Vec<u8>, id_server: u64, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    operations::delete_attachment(&attachment_params(username, &token, None), id_server, instance).await
+}
+
+/// Number of notes sent at once while rotating the master encryption key
+const ROTATION_BATCH_SIZE: usize = 50;
+
+/// Upload a pending master encryption key rotation and switch to the new key once the server committed it.
+/// Every step can be interrupted and resumed, progress is kept in the local database.
+pub async fn resume_mek_rotation(state: &mut MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user.as_ref().ok_or("no user selected")?;
+    let id_user = user.id.ok_or("user is not saved")?;
+    let username = user.username.clone();
+    let (token, instance) = (user.token.clone(), user.instance.clone());
+
+    let mut rotation = {
+        let conn = state.database.lock().await;
+        match MekRotation::select(&conn, id_user)? {
+            Some(r) => r,
+            None => return Ok(())
+        }
+    };
+
+    if !rotation.committed {
+        let (token, instance) = match (token, instance) {
+            (Some(t), Some(i)) => (t, i),
+            _ => return Err("user must be logged in to rotate the master encryption key".into())
+        };
+
+        //Rotations are started on the server with the password when they are created
+        let id_rotation = match rotation.id_server {
+            Some(id) => id,
+            None => {
+                let conn = state.database.lock().await;
+                rotation.delete(&conn)?;
+
+                return Err("master encryption key rotation was never started, it must be started again".into());
+            }
+        };
+
+        let mek = state.user.as_ref().ok_or("no user selected")?.mek()?;
+        let new_mek = crypt::unwrap_key(&rotation.encrypted_mek, &rotation.mek_nonce, mek)?;
+
+        loop {
+            let (notes, keys) = {
+                let conn = state.database.lock().await;
+                let notes: Vec<Note> = Note::select_all_not_rotated(&conn, id_user)?.into_iter().take(ROTATION_BATCH_SIZE).collect();
+                let keys = notes.iter().map(|note| NoteKey::select(&conn, note.id.unwrap())).collect::<Result<Vec<_>, _>>()?;
+
+                (notes, keys)
+            };
+
+            if notes.is_empty() {
+                break;
+            }
+
+            let mut rotated = Vec::with_capacity(notes.len());
+
+            //Notes shared with the user belong to another user
+            for (note, key) in notes.iter().zip(&keys).filter(|(_, key)| key.as_ref().is_none_or(|key| key.owner.is_none())) {
+                let id_server = note.id_server.unwrap();
+
+                //Shared notes and their revisions are encrypted with their own key, only the key is wrapped again
+                let (content, nonce, revisions) = match key {
+                    Some(_) => (note.content.clone(), note.nonce.clone(), None),
+                    None => {
+                        let (content, nonce) = crypt::reencrypt_note(&note.content, &note.nonce, mek, &new_mek)?;
+                        let revisions = rotate_revisions(username.clone(), &token, id_server, mek, &new_mek, instance.clone()).await?;
+
+                        (content, nonce, Some(revisions))
+                    }
+                };
+
+                rotated.push(shared::RotatedNote {
+                    id_server,
+                    content,
+                    nonce,
+                    updated_at: note.updated_at,
+                    revisions
+                });
+            }
+
+            let rotated_notes = shared::RotatedNotes {
+                username: username.clone(),
+                token: token.clone(),
+                id_rotation,
+                notes: rotated
+            };
+
+            operations::send_rotated_notes(rotated_notes, instance.clone()).await?;
+
+            let conn = state.database.lock().await;
+            for note in notes {
+                MekRotation::insert_note(&conn, note.id.unwrap())?;
+            }
+        }
+
+        //Keys of every attachment on the server are sent with the commit, some of them may not be known locally
+        let attachments = operations::select_attachments(&attachment_params(username.clone(), &token, None), instance.clone()).await?;
+        let attachment_keys = attachments.into_iter().map(|attachment| {
+            let (encrypted_key, key_nonce) = crypt::rewrap_key(&attachment.encrypted_key, &attachment.key_nonce, mek, &new_mek)?;
+
+            Ok(shared::RotatedAttachmentKey {
+                id_server: attachment.id.unwrap(),
+                encrypted_key,
+                key_nonce
+            })
+        }).collect::<Result<_, crypt::CryptError>>()?;
+
+        let note_keys = {
+            let conn = state.database.lock().await;
+            db::operations::get_owned_note_keys(&conn, id_user)?
+        };
+        let note_keys = note_keys.into_iter().map(|(id_server, key)| {
+            let (encrypted_key, key_nonce) = crypt::rewrap_key(&key.encrypted_key, &key.key_nonce, mek, &new_mek)?;
+
+            Ok(shared::RotatedNoteKey {
+                id_server,
+                encrypted_key,
+                key_nonce
+            })
+        }).collect::<Result<_, crypt::CryptError>>()?;
+
+        //The identity published on the server is the one other users know
+        let identity_keys = match identity(username.clone(), token.clone(), instance.clone()).await? {
+            Some(identity) => {
+                let (encrypted_keys, keys_nonce) = crypt::reencrypt_note(&identity.encrypted_keys, &identity.keys_nonce, mek, &new_mek)?;
+                Some(shared::RotatedIdentityKeys { encrypted_keys, keys_nonce })
+            },
+            None => None
+        };
+
+        let salt_server_mek = SaltString::generate(&mut OsRng);
+        let kdf_recovery_data = crypt::parse_kdf(&rotation.kdf_recovery_data)?;
+        let stored_mek_hash = crypt::mek_hash(&new_mek, &salt_server_mek, &kdf_recovery_data)?;
+
+        let commit = shared::MekRotationCommit {
+            username,
+            token,
+            id_rotation,
+            salt_data: rotation.salt_data.clone(),
+            encrypted_mek_password: rotation.encrypted_mek_password.clone(),
+            mek_password_nonce: rotation.mek_password_nonce.clone(),
+            salt_recovery_data: rotation.salt_recovery_data.clone(),
+            encrypted_mek_recovery: rotation.encrypted_mek_recovery.clone(),
+            mek_recovery_nonce: rotation.mek_recovery_nonce.clone(),
+            salt_server_mek: salt_server_mek.to_string(),
+            stored_mek_hash,
+            kdf_recovery_data,
+            attachment_keys: Some(attachment_keys),
+            identity_keys,
+            note_keys: Some(note_keys),
+        };
+
+        let conflict = match operations::commit_mek_rotation(commit, instance).await {
+            Ok(()) => false,
+            Err(e) if is_status(&*e, StatusCode::CONFLICT) => true,
+            Err(e) => return Err(e)
+        };
+
+        if conflict {
+            //Some notes are missing or have been modified since they were sent: get them and upload everything again
+            debug!("mek rotation is missing notes, fetching them");
+            {
+                let conn = state.database.lock().await;
+                SyncCursor::delete(&conn, id_user)?;
+            }
+            service::receive_latest_notes(state).await?;
+
+            let conn = state.database.lock().await;
+            MekRotation::clear_notes(&conn)?;
+
+            return Err("master encryption key rotation is missing notes, it will be retried".into());
+        }
+
+        let conn = state.database.lock().await;
+        rotation.committed = true;
+        rotation.update(&conn)?;
+    }
+
+    let AppState { database, user, .. } = &mut **state;
+    let mut conn = database.lock().await;
+    db::operations::finish_mek_rotation(&mut conn, user.as_mut().ok_or("no user selected")?, rotation)?;
+
+    Ok(())
+}
+
+/// Revisions of a note encrypted again with the new master encryption key
+async fn rotate_revisions(username: String, token: &[u8], id_note: u64, mek: &MasterKey, new_mek: &MasterKey, instance: String) -> Result<Vec<shared::RotatedRevision>, Box<dyn std::error::Error>> {
+    let params = note_revision_params(username, token);
+    let mut rotated = Vec::new();
+
+    let revisions = operations::select_note_revisions(&params, id_note, instance.clone()).await?;
+
+    //Revisions encrypted with a key of the note are kept as they are
+    for revision in revisions.into_iter().filter(|revision| revision.key_version.is_none()) {
+        let revision = operations::select_note_revision(&params, id_note, revision.id, instance.clone()).await?;
+        let (content, nonce) = crypt::reencrypt_note(&revision.content, &revision.nonce, mek, new_mek)?;
+
+        rotated.push(shared::RotatedRevision { id: revision.id, content, nonce });
+    }
+
+    Ok(rotated)
+}
+
+/// Check if an error is an http error with the given status
+pub fn is_status(err: &(dyn std::error::Error + 'static), status: StatusCode) -> bool {
+    err.downcast_ref::<reqwest::Error>().and_then(|e| e.status()) == Some(status)
 }
\ No newline at end of file
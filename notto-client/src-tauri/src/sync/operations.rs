@@ -1,41 +1,336 @@
-use shared::{LoginRequestParams, Note, SelectNoteParams, SentNotes, User};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, HeaderMap, HeaderValue};
+use serde::{Serialize, de::DeserializeOwned};
+use shared::{Encoding, LoginRequestParams, NotePage, SelectNoteParams, SentNotes, User};
 use tauri_plugin_log::log::{trace, debug};
 
+/// Prefix of the routes of the protocol version spoken by the client
+const API_PREFIX: &str = "/v1";
+
+fn url(instance: String, route: &str) -> String {
+    instance + API_PREFIX + route
+}
+
+/// Encoding of note payloads, JSON is only kept for debugging since it's bigger
+const NOTE_ENCODING: Encoding = Encoding::Cbor;
+
+/// Every request announces the protocol version of the client
+fn client() -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    headers.insert(shared::PROTOCOL_HEADER, HeaderValue::from(shared::PROTOCOL_VERSION));
+
+    reqwest::Client::builder().default_headers(headers).build().unwrap()
+}
+
+/// Send the request, answers of a server older than the client are refused.
+/// Servers without the header are older than it and speak the first version.
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let response = request.send().await?;
+
+    let version: u32 = response.headers().get(shared::PROTOCOL_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1);
+
+    if version < shared::PROTOCOL_VERSION {
+        return Err(format!("Instance speaks protocol version {version}, this app needs version {}", shared::PROTOCOL_VERSION).into());
+    }
+
+    api_error(response).await
+}
+
+/// Body of note payloads and the answer asked to the server
+fn encoded<T: Serialize>(request: reqwest::RequestBuilder, body: &T) -> Result<reqwest::RequestBuilder, Box<dyn std::error::Error>> {
+    Ok(request
+        .header(CONTENT_TYPE, NOTE_ENCODING.content_type())
+        .header(ACCEPT, NOTE_ENCODING.content_type())
+        .body(NOTE_ENCODING.encode(body)?))
+}
+
+/// Decode the body with the encoding of its `Content-Type`
+async fn decoded<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, Box<dyn std::error::Error>> {
+    let encoding = response.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Encoding::from_header)
+        .unwrap_or(Encoding::Json);
+
+    Ok(encoding.decode(&response.bytes().await?)?)
+}
+
+/// Turn a client error with a `shared::ApiError` body into that error, other failures into the http error
+async fn api_error(response: reqwest::Response) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    if response.status().is_client_error() {
+        let status_error = response.error_for_status_ref().unwrap_err();
+
+        return match response.json::<shared::ApiError>().await {
+            Ok(api_error) => Err(api_error.into()),
+            Err(_) => Err(status_error.into())
+        };
+    }
+
+    Ok(response.error_for_status()?)
+}
+
+pub async fn instance_info(instance: String) -> Result<shared::InstanceInfo, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = client.get(instance + "/instance").send().await?.error_for_status()?;
+
+    Ok(response.json().await?)
+}
+
 pub async fn send_notes(notes: SentNotes, instance: String) -> Result<Vec<shared::SentNotesResult>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+    let client = client();
 
-    let response = client.post(instance + "/note").json(&notes).send().await.unwrap().error_for_status()?;
+    let request = encoded(client.post(url(instance, "/note")), &notes)?;
+    let response = send(request).await?;
 
-    return Ok(response.json().await.unwrap())
+    decoded(response).await
 }
 
-pub async fn select_notes(params: SelectNoteParams, instance: String) -> Result<Vec<Note>, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+pub async fn select_note_page(params: SelectNoteParams, instance: String) -> Result<NotePage, Box<dyn std::error::Error>> {
+    let client = client();
 
-    let response = client.get(instance + "/note").query(&params).send().await.unwrap().error_for_status()?;
+    let response = send(client.get(url(instance, "/note/page")).query(&params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
 
-    Ok(response.json().await.unwrap())
+    decoded(response).await
 }
 
-pub async fn create_account(user: User, instance: String) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let response = client.post(instance + "/create_account").json(&user).send().await.unwrap();
+pub async fn create_account(user: User, instance: String) -> Result<shared::Signup, Box<dyn std::error::Error>> {
+    let client = client();
 
-    Ok(())
+    let response = send(client.post(url(instance, "/create_account")).json(&user)).await?;
+
+    Ok(response.json().await?)
 }
 
 pub async fn login_request(params: LoginRequestParams, instance: String) -> Result<shared::LoginRequest, Box<dyn std::error::Error>>{
-    let client = reqwest::Client::new();
+    let client = client();
 
-    let response = client.get(instance + "/login").query(&params).send().await?.json().await.unwrap();
+    let response = send(client.get(url(instance, "/login")).query(&params)).await?;
 
-    Ok(response)
+    Ok(response.json().await?)
 }
 
 pub async fn login(params: shared::LoginParams, instance: String) -> Result<shared::Login, Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
+    let client = client();
+
+    let response = send(client.post(url(instance, "/login")).json(&params)).await?;
+
+    Ok(response.json().await.unwrap())
+}
+
+pub async fn opaque_login_start(params: shared::OpaqueLoginStart, instance: String) -> Result<shared::OpaqueLoginResponse, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/login/opaque")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn opaque_login_finish(params: shared::OpaqueLoginFinish, instance: String) -> Result<shared::OpaqueLogin, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/login/opaque/finish")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn opaque_register_start(params: shared::OpaqueRegistrationStart, instance: String) -> Result<shared::OpaqueRegistrationResponse, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/login/opaque/register")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn opaque_register_finish(params: shared::OpaqueRegistrationFinish, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    send(client.post(url(instance, "/login/opaque/register/finish")).json(&params)).await?;
+
+    Ok(())
+}
+
+pub async fn delete_user(params: shared::DeleteUserParams, instance: String) -> Result<shared::DeletionReceipt, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.delete(url(instance, "/user")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn upgrade_kdf(upgrade: shared::KdfUpgrade, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    send(client.post(url(instance, "/login/kdf")).json(&upgrade)).await?;
+
+    Ok(())
+}
+
+pub async fn totp_enroll(params: shared::TotpEnrollParams, instance: String) -> Result<shared::TotpEnrollment, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/totp")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn totp_confirm(params: shared::TotpConfirmParams, instance: String) -> Result<shared::TotpBackupCodes, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/totp/confirm")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn data_recovery_request(params: LoginRequestParams, instance: String) -> Result<shared::DataRecoveryRequest, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, "/data_recovery")).query(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn data_recovery(params: shared::DataRecoveryParams, instance: String) -> Result<shared::Login, Box<dyn std::error::Error>> {
+    let client = client();
 
-    let response = client.post(instance + "/login").json(&params).send().await?.error_for_status()?;
+    let response = send(client.post(url(instance, "/data_recovery")).json(&params)).await?;
 
-    return Ok(response.json().await.unwrap())
-}
\ No newline at end of file
+    Ok(response.json().await?)
+}
+
+pub async fn start_mek_rotation(params: shared::MekRotationParams, instance: String) -> Result<shared::MekRotation, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.post(url(instance, "/rotate_mek")).json(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn send_rotated_notes(notes: shared::RotatedNotes, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    let request = encoded(client.post(url(instance, "/rotate_mek/note")), &notes)?;
+    send(request).await?;
+
+    Ok(())
+}
+
+pub async fn commit_mek_rotation(commit: shared::MekRotationCommit, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    send(client.post(url(instance, "/rotate_mek/commit")).json(&commit)).await?;
+
+    Ok(())
+}
+
+pub async fn usage(params: shared::UsageParams, instance: String) -> Result<shared::Usage, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, "/usage")).query(&params)).await?;
+
+    Ok(response.json().await?)
+}
+
+pub async fn send_identity(identity: shared::SentIdentity, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    let request = encoded(client.post(url(instance, "/identity")), &identity)?;
+    send(request).await?;
+
+    Ok(())
+}
+
+pub async fn select_identity(params: &shared::IdentityParams, instance: String) -> Result<shared::Identity, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, "/identity")).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn select_public_identity(params: &shared::IdentityParams, username: &str, instance: String) -> Result<shared::PublicIdentity, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, &format!("/identity/{username}"))).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn select_note_revisions(params: &shared::NoteRevisionParams, id_note: u64, instance: String) -> Result<Vec<shared::NoteRevision>, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, &format!("/note/{id_note}/revision"))).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn select_note_revision(params: &shared::NoteRevisionParams, id_note: u64, id_revision: u64, instance: String) -> Result<shared::NoteRevisionContent, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, &format!("/note/{id_note}/revision/{id_revision}"))).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn select_note_shares(params: &shared::NoteShareParams, id_note: u64, instance: String) -> Result<Vec<shared::NoteShare>, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, &format!("/note/{id_note}/share"))).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn update_note_shares(rekey: shared::NoteRekey, id_note: u64, instance: String) -> Result<shared::Note, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let request = encoded(client.put(url(instance, &format!("/note/{id_note}/share"))), &rekey)?;
+    let response = send(request).await?;
+
+    decoded(response).await
+}
+
+pub async fn upload_attachment_chunk(params: &shared::AttachmentParams, hash: &str, chunk: Vec<u8>, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    let request = client.put(url(instance, &format!("/attachment/chunk/{hash}")))
+        .query(params)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(chunk);
+    send(request).await?;
+
+    Ok(())
+}
+
+pub async fn download_attachment_chunk(params: &shared::AttachmentParams, hash: &str, instance: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, &format!("/attachment/chunk/{hash}"))).query(params)).await?;
+
+    Ok(response.bytes().await?.to_vec())
+}
+
+pub async fn send_attachment(attachment: shared::SentAttachment, instance: String) -> Result<shared::Attachment, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let request = encoded(client.post(url(instance, "/attachment")), &attachment)?;
+    let response = send(request).await?;
+
+    decoded(response).await
+}
+
+pub async fn select_attachments(params: &shared::AttachmentParams, instance: String) -> Result<Vec<shared::Attachment>, Box<dyn std::error::Error>> {
+    let client = client();
+
+    let response = send(client.get(url(instance, "/attachment")).query(params).header(ACCEPT, NOTE_ENCODING.content_type())).await?;
+
+    decoded(response).await
+}
+
+pub async fn delete_attachment(params: &shared::AttachmentParams, id: u64, instance: String) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client();
+
+    send(client.delete(url(instance, &format!("/attachment/{id}"))).query(params)).await?;
+
+    Ok(())
+}
@@ -1,38 +1,90 @@
-use std::{thread, time::Duration};
+use std::{thread, time::{Duration, Instant}};
 
-use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rusqlite::Connection;
 use serde_json::error;
-use shared::{SelectNoteParams, SentNotes};
+use shared::{NoteCursor, SelectNoteParams, SentNotes};
 use tokio::sync::{Mutex, MutexGuard};
 
 use tauri::{AppHandle, Manager};
-use tauri_plugin_log::log::{debug, trace, error};
+use tauri_plugin_log::log::{debug, trace, error, warn};
 
-use crate::{AppState, db::{self, schema::Note}, sync};
+use reqwest::StatusCode;
+
+use crate::{AppState, crypt, db::{self, schema::{Attachment, AttachmentChunk, MekRotation, Note}}, sync};
+
+/// Storage usage is asked to the server at most once per interval
+const USAGE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
 pub async fn run(handle: AppHandle) {
     let state = handle.state::<Mutex<AppState>>();
-    let mut last_sync = DateTime::<Utc>::MIN_UTC.timestamp();
+    let mut usage_checked: Option<Instant> = None;
 
     loop{
         trace!("Hello, I'm a background service!");
         
         {
-            let state = state.lock().await;
+            let mut state = state.lock().await;
 
-            if let Some(user) = state.user.clone() {
-                if user.id.is_some() && user.token.is_some() && user.instance.is_some() {
-                    //Update sync infos
-                    let sync = Local::now().to_utc().timestamp();
-    
-                    //Sync
-                    receive_latest_notes(&state, last_sync).await;
-                    send_latest_notes(&state).await;
-    
-                    last_sync = sync;
-                }else {
-                    debug!("Conditions are not respected to sync {state:?}");
+            //Lock the app after lock_timeout seconds of inactivity
+            let last_activity = state.last_activity;
+            if let Some(user) = state.user.as_mut() {
+                if let (Some(timeout), Ok(_)) = (user.lock_timeout, user.mek()) {
+                    if last_activity.elapsed() > Duration::from_secs(timeout) {
+                        debug!("inactive for {timeout}s, locking");
+                        db::operations::lock_user(user);
+                    }
+                }
+            }
+
+            let id_user = match &state.user {
+                Some(user) => match (user.id, &user.token, &user.instance) {
+                    (Some(id_user), Some(_), Some(_)) => Some(id_user),
+                    _ => {
+                        debug!("Conditions are not respected to sync {user:?}");
+                        None
+                    }
+                },
+                None => None
+            };
+
+            if let Some(id_user) = id_user {
+                //A pending master encryption key rotation must be finished before syncing notes
+                //Notes are synced encrypted, only the rotation needs the app to be unlocked
+                let has_rotation = {
+                    let conn = state.database.lock().await;
+                    MekRotation::select(&conn, id_user).unwrap().is_some()
+                };
+                let unlocked = state.user.as_ref().is_some_and(|u| u.mek().is_ok());
+
+                let revoked = if has_rotation && !unlocked {
+                    trace!("app is locked, master encryption key rotation paused");
+                    false
+                } else {
+                    let result = match has_rotation {
+                        true => sync::resume_mek_rotation(&mut state).await,
+                        false => sync_notes(&state).await
+                    };
+
+                    match result {
+                        Ok(()) => false,
+                        Err(e) => {
+                            error!("sync failed: {e}");
+                            sync::is_status(&*e, StatusCode::FORBIDDEN)
+                        }
+                    }
+                };
+
+                if revoked {
+                    //Token has been revoked (e.g. master encryption key rotated on another device), user must login again
+                    debug!("token has been revoked, logging out");
+                    logout(&mut state).await;
+                } else if !has_rotation && (state.usage.is_none() || usage_checked.is_none_or(|t| t.elapsed() > USAGE_REFRESH_INTERVAL)) {
+                    usage_checked = Some(Instant::now());
+
+                    if let Err(e) = refresh_usage(&mut state).await {
+                        error!("usage could not be fetched: {e}");
+                    }
                 }
             }
         }
@@ -41,52 +93,129 @@ pub async fn run(handle: AppHandle) {
     }
 }
 
+async fn sync_notes(state: &MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    receive_latest_notes(state).await?;
+    send_latest_notes(state).await?;
+    send_latest_attachments(state).await?;
+    ensure_identity(state).await?;
+
+    Ok(())
+}
+
+/// Keep the identity published by the user, or generate and publish one if there is none yet.
+/// Generating it needs the master encryption key, it waits until the app is unlocked.
+async fn ensure_identity(state: &MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user.as_ref().unwrap();
+    let id_user = user.id.unwrap();
+
+    let known = {
+        let conn = state.database.lock().await;
+        db::operations::get_identity(&conn, id_user)?.is_some()
+    };
+
+    if known {
+        return Ok(());
+    }
+
+    let (token, instance) = (user.token.clone().unwrap(), user.instance.clone().unwrap());
+
+    let published = sync::identity(user.username.clone(), token.clone(), instance.clone()).await?;
+
+    let identity = match published {
+        Some(identity) => identity,
+        None => {
+            let identity = match user.mek() {
+                Ok(mek) => crypt::create_identity(mek)?,
+                Err(_) => return Ok(())
+            };
+
+            if !sync::publish_identity(user.username.clone(), token, identity.clone(), instance).await? {
+                //Published by another device in the meantime, it is fetched on the next sync
+                return Ok(());
+            }
+
+            debug!("identity published");
+            identity
+        }
+    };
 
-pub async fn receive_latest_notes(state: &MutexGuard<'_, AppState>, last_sync: i64) {
     let conn = state.database.lock().await;
-    
-    let user = state.user.clone().unwrap();
+    db::operations::save_identity(&conn, id_user, identity)?;
+
+    Ok(())
+}
+
+/// Ask the server for the storage usage of the current user, warn when it gets close to its quota
+async fn refresh_usage(state: &mut MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user.as_ref().unwrap();
+
+    let usage = sync::usage(user.username.clone(), user.token.clone().unwrap(), user.instance.clone().unwrap()).await?;
+
+    if usage.is_near_quota() {
+        warn!("storage is almost full: {} bytes and {} notes used, quota is {:?}", usage.bytes, usage.notes, usage.quota);
+    }
+
+    state.usage = Some(usage);
 
-    let params = SelectNoteParams {
-        username: user.username,
-        token: hex::encode(user.token.unwrap()), 
-        updated_at: last_sync
+    Ok(())
+}
+
+/// Forget the token of the current user so it has to login again
+async fn logout(state: &mut MutexGuard<'_, AppState>) {
+    let AppState { database, user, .. } = &mut **state;
+    let user = user.as_mut().unwrap();
+    user.token = None;
+
+    let conn = database.lock().await;
+    db::operations::update_user(&conn, user);
+}
+
+/// Download the notes updated since the saved cursor, one page at a time.
+/// Each page is committed with the cursor so an interrupted download continues where it stopped.
+pub async fn receive_latest_notes(state: &MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user.as_ref().unwrap();
+    let id_user = user.id.unwrap();
+
+    let saved_cursor = {
+        let conn = state.database.lock().await;
+        db::operations::get_sync_cursor(&conn, id_user)?
     };
-    
-    //Ask server for modified notes
-    let notes = sync::operations::select_notes(params, user.instance.unwrap()).await.unwrap();
+    let mut cursor = saved_cursor.unwrap_or(NoteCursor { updated_at: DateTime::<Utc>::MIN_UTC.timestamp(), id: 0 });
 
-    trace!("notes received : {notes:?}");
+    loop {
+        let params = SelectNoteParams {
+            username: user.username.clone(),
+            token: hex::encode(user.token.as_ref().unwrap()),
+            updated_at: cursor.updated_at,
+            after_id: Some(cursor.id),
+            limit: None //Page size of the server
+        };
 
-    // Put new notes to database
-    notes.into_iter().for_each(|note| {
-        let mut note = db::schema::Note::from(note);
-        note.id_user = user.id;
-        
-        //Check if exist
-        let selected_note = db::schema::Note::select(&conn, note.id.unwrap()).unwrap();
-
-        match selected_note {
-            Some(sn) => {
-                if note.updated_at > sn.updated_at {
-                    //Note is more recent on server
-                    match sn.synched {
-                        true => note.update(&conn).unwrap(),
-                        false => error!("Note {:?} is in conflict and it's not handled :(", sn.id) //TODO
-                    };
-                }
-            },
-            None => note.insert(&conn).unwrap()
+        //Ask server for modified notes
+        let page = sync::operations::select_note_page(params, user.instance.clone().unwrap()).await?;
+
+        trace!("note page received: {} notes, next {:?}", page.notes.len(), page.next);
+
+        let last = page.notes.last().map(|note| NoteCursor { updated_at: note.updated_at, id: note.id_server.unwrap() });
+
+        if let Some(last) = last {
+            let mut conn = state.database.lock().await;
+            db::operations::save_note_page(&mut conn, id_user, page.notes, last)?;
         }
 
-        //TODO: if deleted
-    });
+        match page.next {
+            Some(next) => cursor = next,
+            None => break
+        }
+    }
+
+    Ok(())
 }
 
-pub async fn send_latest_notes(state: &MutexGuard<'_, AppState>) {
+pub async fn send_latest_notes(state: &MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
     let conn = state.database.lock().await;
 
-    let user = state.user.clone().unwrap();
+    let user = state.user.as_ref().unwrap();
     
     //Fetch db find all notes with synched = false;
     let notes = Note::select_all(&conn, user.id.unwrap()).unwrap();
@@ -94,14 +223,26 @@ pub async fn send_latest_notes(state: &MutexGuard<'_, AppState>) {
     //TODO: Optimise that with a database query
     let notes: Vec<Note> = notes.into_iter().filter(|note| !note.synched).collect();
 
+    //Notes the server doesn't know are sent with the same key until they are saved
+    let mut sent = Vec::with_capacity(notes.len());
+
+    for note in notes {
+        let idempotency_key = match note.id_server {
+            Some(_) => None,
+            None => Some(Note::idempotency_key(&conn, note.id.unwrap())?)
+        };
+
+        sent.push(shared::Note { idempotency_key, ..note.into() });
+    }
+
     let sent_notes = SentNotes {
-        username: user.username,
-        notes: notes.into_iter().map(|n| n.into()).collect(),
-        token: user.token.unwrap()
+        username: user.username.clone(),
+        notes: sent,
+        token: user.token.clone().unwrap()
     };
 
     //Send server these notes
-    let results = sync::operations::send_notes(sent_notes, user.instance.unwrap()).await.unwrap();
+    let results = sync::operations::send_notes(sent_notes, user.instance.clone().unwrap()).await?;
 
     //Handle Results
     results.into_iter().for_each(|result| {
@@ -117,7 +258,50 @@ pub async fn send_latest_notes(state: &MutexGuard<'_, AppState>) {
             shared::NoteStatus::Conflict => {
                 //TODO
                 error!("Note {:?} is in conflict and it's not handled :(", result.id_client) 
-            }
+            },
+            //Kept unsynched, the note is sent again on the next sync
+            shared::NoteStatus::Forbidden => error!("Note {:?} has been refused by the server", result.id_client),
+            shared::NoteStatus::TooLarge => error!("Note {:?} is too large for the instance", result.id_client),
+            shared::NoteStatus::QuotaExceeded => error!("Note {:?} has been refused, the storage quota is exceeded", result.id_client)
         }
     });
-}
\ No newline at end of file
+
+    Ok(())
+}
+/// Upload the attachments of notes known by the server
+pub async fn send_latest_attachments(state: &MutexGuard<'_, AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    let user = state.user.as_ref().unwrap();
+
+    let attachments = {
+        let conn = state.database.lock().await;
+        Attachment::select_all_not_synched(&conn, user.id.unwrap())?
+    };
+
+    for mut attachment in attachments {
+        let (chunks, id_note) = {
+            let conn = state.database.lock().await;
+            let note = Note::select(&conn, attachment.id_note)?.ok_or("note of the attachment not found")?;
+
+            (AttachmentChunk::select_all(&conn, attachment.id.unwrap())?, note.id_server.unwrap())
+        };
+
+        let sent = shared::Attachment {
+            id: None,
+            id_note,
+            encrypted_key: attachment.encrypted_key.clone(),
+            key_nonce: attachment.key_nonce.clone(),
+            metadata: attachment.metadata.clone(),
+            metadata_nonce: attachment.metadata_nonce.clone(),
+            chunks: chunks.iter().map(|chunk| chunk.hash.clone()).collect()
+        };
+
+        let id_server = sync::upload_attachment(user.username.clone(), user.token.clone().unwrap(), sent, chunks, user.instance.clone().unwrap()).await?;
+
+        let conn = state.database.lock().await;
+        attachment.id_server = Some(id_server);
+        attachment.synched = true;
+        attachment.update(&conn)?;
+    }
+
+    Ok(())
+}
@@ -11,12 +11,13 @@ function App() {
   //   await invoke("test", {  }).then(v => console.info(v)).catch((e) => console.error(e));
   // }
   async function create_local_user() {
-    await invoke("create_user", { username: "test_account" }).then(v => console.info(v)).catch((e) => console.error(e));
+    await invoke("create_user", { username: "test_account", unlock_secret: "password" }).then(v => console.info(v)).catch((e) => console.error(e));
   }
   
   useEffect(() => {
     invoke("init").catch((e) => console.error(e));
     invoke("set_user", { username: "test_account" })
+      .then(() => invoke("unlock", { unlock_secret: "password" }))
       .then(() => setUserId(1))
       .catch((e) => console.error(e));
   }, [userId])
@@ -0,0 +1,97 @@
+use std::{env, sync::OnceLock};
+
+use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, TryRngCore};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+pub const FREE_LOGIN_ATTEMPTS: u32 = 5;
+const BASE_LOCK_SECONDS: i64 = 30;
+const MAX_LOCK_SECONDS: i64 = 60 * 60;
+
+const SALT_SIZE: usize = 16; //Same as SaltString::generate on the client
+
+static FAKE_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Lock of an account after `failed_attempts` failed logins in a row, doubled with every failure past the free ones
+pub fn lock_seconds(failed_attempts: u32) -> i64 {
+    if failed_attempts < FREE_LOGIN_ATTEMPTS {
+        return 0;
+    }
+
+    let doublings = (failed_attempts - FREE_LOGIN_ATTEMPTS).min(16);
+
+    (BASE_LOCK_SECONDS << doublings).min(MAX_LOCK_SECONDS)
+}
+
+/// What the server stores of the master encryption key hash sent by the client, a leaked database can't be sent back to recover an account.
+/// The hash is already derived with Argon2 by the client, one SHA-256 is enough. Accounts without a hash keep an empty one.
+pub fn mek_hash_digest(mek_hash: &str) -> String {
+    if mek_hash.is_empty() {
+        return String::new();
+    }
+
+    hex::encode(Sha256::digest(mek_hash.as_bytes()))
+}
+
+/// Key of the fake values, read from FAKE_SALT_SECRET.
+/// If it's not set a random one is used, fake values then change when the server restarts.
+fn fake_secret() -> &'static [u8] {
+    FAKE_SECRET.get_or_init(|| match env::var("FAKE_SALT_SECRET") {
+        Ok(secret) => secret.into_bytes(),
+        Err(_) => {
+            warn!("FAKE_SALT_SECRET is not set, unknown usernames can be detected across restarts");
+
+            let mut secret = vec![0u8; 32];
+            OsRng.try_fill_bytes(&mut secret).unwrap();
+            secret
+        }
+    })
+}
+
+/// Bytes always returned for the same unknown username, so responses don't reveal which accounts exist
+pub fn fake_bytes(username: &str, label: &str, len: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(len);
+    let mut block = 0u32;
+
+    while bytes.len() < len {
+        let mut mac = Hmac::<Sha256>::new_from_slice(fake_secret()).unwrap();
+        mac.update(label.as_bytes());
+        mac.update(&block.to_be_bytes());
+        mac.update(username.as_bytes());
+
+        bytes.extend_from_slice(&mac.finalize().into_bytes());
+        block += 1;
+    }
+
+    bytes.truncate(len);
+    bytes
+}
+
+/// Fake salt in the PHC format used by real salts
+pub fn fake_salt(username: &str, label: &str) -> String {
+    STANDARD_NO_PAD.encode(fake_bytes(username, label, SALT_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_attempts_are_not_locked() {
+        assert_eq!(lock_seconds(0), 0);
+        assert_eq!(lock_seconds(FREE_LOGIN_ATTEMPTS - 1), 0);
+        assert_eq!(lock_seconds(FREE_LOGIN_ATTEMPTS), BASE_LOCK_SECONDS);
+    }
+
+    #[test]
+    fn lock_doubles_up_to_the_cap() {
+        assert_eq!(lock_seconds(FREE_LOGIN_ATTEMPTS + 1), 2 * BASE_LOCK_SECONDS);
+        assert_eq!(lock_seconds(FREE_LOGIN_ATTEMPTS + 2), 4 * BASE_LOCK_SECONDS);
+
+        //30 s doubled 7 times is past an hour
+        assert_eq!(lock_seconds(FREE_LOGIN_ATTEMPTS + 7), MAX_LOCK_SECONDS);
+        assert_eq!(lock_seconds(u32::MAX), MAX_LOCK_SECONDS);
+    }
+}
@@ -0,0 +1,65 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{StatusCode, header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use shared::Encoding;
+use tracing::warn;
+
+/// Body decoded with the encoding of its `Content-Type`, JSON if there is none
+pub struct Encoded<T>(pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Encoded<T> {
+    type Rejection = StatusCode;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let encoding = match request.headers().get(header::CONTENT_TYPE) {
+            Some(content_type) => content_type.to_str().ok()
+                .and_then(Encoding::from_header)
+                .ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?,
+            None => Encoding::Json
+        };
+
+        let body = Bytes::from_request(request, state).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        match encoding.decode(&body) {
+            Ok(value) => Ok(Encoded(value)),
+            Err(e) => {
+                warn!(?encoding, "{e}");
+                Err(StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        }
+    }
+}
+
+/// Encoding asked with the `Accept` header, JSON if there is none or if no known encoding is asked
+pub struct Accept(pub Encoding);
+
+impl<S: Send + Sync> FromRequestParts<S> for Accept {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let encoding = parts.headers.get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(Encoding::from_header)
+            .unwrap_or(Encoding::Json);
+
+        Ok(Accept(encoding))
+    }
+}
+
+/// Response body in the encoding asked by the client
+pub struct Negotiated<T>(pub Encoding, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(encoding, value) = self;
+
+        match encoding.encode(&value) {
+            Ok(body) => ([(header::CONTENT_TYPE, encoding.content_type())], body).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
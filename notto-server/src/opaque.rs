@@ -0,0 +1,77 @@
+use std::sync::OnceLock;
+
+use mysql_async::Conn;
+use shared::opaque::{
+    CONTEXT, OsRng, Suite,
+    opaque_ke::{
+        CredentialFinalization, CredentialRequest, RegistrationRequest, RegistrationUpload, ServerLogin, ServerLoginParameters,
+        ServerRegistration, ServerSetup, errors::ProtocolError,
+    },
+};
+use tracing::info;
+
+use crate::schema;
+
+/// Seconds to finish a login once it is started
+pub const LOGIN_TIMEOUT: i64 = 60;
+
+static SETUP: OnceLock<ServerSetup<Suite>> = OnceLock::new();
+
+/// Load the OPAQUE keys of the server, they are generated and stored the first time.
+/// Every record is bound to them, accounts using OPAQUE can't log in anymore if they are lost.
+pub async fn init(conn: &mut Conn) {
+    let setup = match schema::OpaqueSetup::select(conn).await {
+        Some(setup) => ServerSetup::deserialize(&setup.setup).unwrap(),
+        None => {
+            let setup = ServerSetup::<Suite>::new(&mut OsRng);
+            schema::OpaqueSetup { setup: setup.serialize().to_vec() }.insert(conn).await;
+            info!("OPAQUE keys generated");
+
+            //Another instance may have stored its keys first
+            ServerSetup::deserialize(&schema::OpaqueSetup::select(conn).await.unwrap().setup).unwrap()
+        }
+    };
+
+    let _ = SETUP.set(setup);
+}
+
+fn setup() -> &'static ServerSetup<Suite> {
+    SETUP.get().expect("opaque::init must be called first")
+}
+
+fn parameters() -> ServerLoginParameters<'static, 'static> {
+    ServerLoginParameters { context: Some(CONTEXT), ..Default::default() }
+}
+
+/// Answer the first message of a registration, nothing is kept until the record is uploaded
+pub fn registration_response(username: &str, registration_request: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let request = RegistrationRequest::deserialize(registration_request)?;
+
+    Ok(ServerRegistration::start(setup(), request, username.as_bytes())?.message.serialize().to_vec())
+}
+
+/// Record stored for the account, checked to be well formed
+pub fn record(registration_upload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let upload = RegistrationUpload::<Suite>::deserialize(registration_upload)?;
+
+    Ok(ServerRegistration::finish(upload).serialize().to_vec())
+}
+
+/// Start a login, return the state to keep until it is finished and the answer to the client.
+/// Unknown usernames and accounts without a record get a fake answer the client can't tell apart.
+pub fn login_start(username: &str, record: Option<&[u8]>, credential_request: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ProtocolError> {
+    let record = record.map(ServerRegistration::<Suite>::deserialize).transpose()?;
+    let request = CredentialRequest::deserialize(credential_request)?;
+
+    let start = ServerLogin::start(&mut OsRng, setup(), record, request, username.as_bytes(), parameters())?;
+
+    Ok((start.state.serialize().to_vec(), start.message.serialize().to_vec()))
+}
+
+/// Finish a login, return the session key. Fails when the password is wrong.
+pub fn login_finish(state: &[u8], credential_finalization: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let state = ServerLogin::<Suite>::deserialize(state)?;
+    let finalization = CredentialFinalization::deserialize(credential_finalization)?;
+
+    Ok(state.finish(finalization, parameters())?.session_key.to_vec())
+}
@@ -1,9 +1,20 @@
-use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::{collections::HashMap, fmt};
+
 use mysql_async::{
-    Conn, FromRowError, Row, params,
-    prelude::{FromRow, Queryable, WithParams},
+    Conn, FromRowError, Row, TxOpts, Value, params,
+    prelude::{FromRow, Queryable},
 };
 use serde::{Deserialize, Serialize};
+use shared::{KdfParams, REDACTED};
+use tracing::warn;
+
+/// MySQL error of an insert violating a unique key
+const ER_DUP_ENTRY: u16 = 1062;
+
+/// Positional placeholders of an `IN` list
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Note {
@@ -14,6 +25,7 @@ pub struct Note {
     pub content: Vec<u8>,
     pub nonce: Vec<u8>,
     pub updated_at: i64,
+    pub idempotency_key: Option<String>, //Set by the client on new notes, a batch sent again finds the notes it already inserted
 }
 
 impl FromRow for Note {
@@ -26,6 +38,7 @@ impl FromRow for Note {
             content: row.get(4).ok_or(FromRowError(row.clone()))?,
             nonce: row.get(5).ok_or(FromRowError(row.clone()))?,
             updated_at: row.get(6).ok_or(FromRowError(row.clone()))?,
+            idempotency_key: row.get(7).ok_or(FromRowError(row.clone()))?,
         })
     }
 }
@@ -39,26 +52,85 @@ impl From<shared::Note> for Note {
             title: note.title,
             content: note.content,
             nonce: note.nonce,
-            updated_at: note.updated_at
+            updated_at: note.updated_at,
+            idempotency_key: note.idempotency_key,
         }
     }
 }
 
-impl Into<shared::Note> for Note {
-    fn into(self) -> shared::Note {
+impl From<Note> for shared::Note {
+    fn from(note: Note) -> Self {
         shared::Note {
-            id: self.id_client,
-            id_server: self.id,
-            content: self.content,
-            nonce: self.nonce,
-            title: self.title,
-            updated_at: self.updated_at
+            id: note.id_client,
+            id_server: note.id,
+            content: note.content,
+            nonce: note.nonce,
+            title: note.title,
+            updated_at: note.updated_at,
+            key: None,
+            idempotency_key: None,
         }
     }
 }
 
+/// Create every table used by the server if they don't exist yet
+pub async fn init(conn: &mut Conn) {
+    //Tables created by an older version are migrated first, the missing ones are then created
+    User::migrate(conn).await;
+    Note::migrate(conn).await;
+    NoteRevision::migrate(conn).await;
+
+    User::create(conn).await;
+    Note::create(conn).await;
+    NoteRevision::create(conn).await;
+    UserToken::create(conn).await;
+    MekRotation::create(conn).await;
+    NoteRotation::create(conn).await;
+    RevisionRotation::create(conn).await;
+    UserTotp::create(conn).await;
+    TotpBackupCode::create(conn).await;
+    LoginAttempt::create(conn).await;
+    Invite::create(conn).await;
+    Attachment::create(conn).await;
+    Chunk::create(conn).await;
+    UserQuota::create(conn).await;
+    UserIdentity::create(conn).await;
+    NoteKey::create(conn).await;
+    NoteShare::create(conn).await;
+    OpaqueSetup::create(conn).await;
+    OpaqueRecord::create(conn).await;
+    OpaqueLogin::create(conn).await;
+}
+
 impl Note {
-    //TODO: pub async fn create(&self, conn: &mut Conn) {}
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS note (
+                id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_client INT UNSIGNED NOT NULL,
+                id_user INT UNSIGNED NOT NULL,
+                title TEXT,
+                content LONGBLOB,
+                nonce BLOB,
+                updated_at BIGINT NOT NULL,
+                idempotency_key CHAR(36) NULL,
+                INDEX (id_user, updated_at, id),
+                INDEX (id_user, id_client),
+                UNIQUE INDEX idempotency_key (id_user, idempotency_key),
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Add the idempotency key to a table created by an older version
+    pub async fn migrate(conn: &mut Conn) {
+        conn.query_drop("ALTER TABLE IF EXISTS note ADD COLUMN IF NOT EXISTS idempotency_key CHAR(36) NULL AFTER updated_at,
+                ADD UNIQUE INDEX IF NOT EXISTS idempotency_key (id_user, idempotency_key)")
+            .await
+            .unwrap();
+    }
 
     pub async fn select(&self, conn: &mut Conn) -> Self {
         conn.exec_first(
@@ -72,25 +144,31 @@ impl Note {
         .unwrap()
     }
 
-    pub async fn insert(&self, conn: &mut Conn) {
+    pub async fn insert(&self, conn: &mut impl Queryable) {
         conn.exec_drop(
-            "INSERT INTO note (id_client, id_user, title, content, nonce, updated_at) 
-            VALUES (:id_client, :id_user, :title, :content, :nonce, :updated_at)",
+            "INSERT INTO note (id_client, id_user, title, content, nonce, updated_at, idempotency_key) 
+            VALUES (:id_client, :id_user, :title, :content, :nonce, :updated_at, :idempotency_key)",
             params!(
                 "id_client" => &self.id_client,
                 "id_user" => &self.id_user,
                 "title" => &self.title,
                 "content" => &self.content,
                 "nonce" => &self.nonce,
-                "updated_at" => &self.updated_at
+                "updated_at" => &self.updated_at,
+                "idempotency_key" => &self.idempotency_key
             ),
         )
         .await
         .unwrap();
     }
 
-    pub async fn update(&self, conn: &mut Conn) {
-        conn.exec_drop(
+    /// Replace the content of the note, the previous version is kept as a revision
+    pub async fn update(&self, conn: &mut Conn, archived_at: i64) {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        NoteRevision::archive(&mut tx, std::slice::from_ref(self), archived_at).await;
+
+        tx.exec_drop(
             "UPDATE note 
             SET title = :title, content = :content, nonce = :nonce, updated_at = :updated_at 
             WHERE id = :id",
@@ -104,11 +182,14 @@ impl Note {
         )
         .await
         .unwrap();
+
+        tx.commit().await.unwrap();
     }
 
     pub async fn select_all_from_user(conn: &mut Conn, id_user: u32, after_datetime: i64) -> Vec<Self> {
         conn.exec(
-            "SELECT * FROM note WHERE id_user = :id_user AND updated_at >= :updated_at",
+            "SELECT * FROM note WHERE (id_user = :id_user OR id IN (SELECT id_note FROM note_share WHERE id_user = :id_user))
+                AND updated_at >= :updated_at",
             params!(
                 "id_user" => id_user,
                 "updated_at" => after_datetime
@@ -117,9 +198,465 @@ impl Note {
         .await
         .unwrap()
     }
+
+    /// Insert or update the notes of a user in one transaction.
+    /// Existing notes and notes already inserted by a previous try are looked up with one query each,
+    /// so a batch sent again after a timeout gets the same results without creating duplicates.
+    /// New notes sent again are found with their idempotency key, notes without one are always inserted.
+    /// Notes larger than `max_note_size` or that would exceed the bytes or notes of the `quota` are rejected.
+    /// Results are in the order of `notes`.
+    /// Notes of other users can be updated when they are shared with the user with the write permission.
+    /// The versions replaced by updated notes are kept as revisions archived at `now`.
+    pub async fn save_all(conn: &mut Conn, id_user: u32, mut notes: Vec<Self>, quota: &shared::Quota, now: i64) -> Vec<shared::SentNotesResult> {
+        let mut results = Vec::with_capacity(notes.len());
+
+        if notes.is_empty() {
+            return results;
+        }
+
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        let (mut bytes, mut count) = Note::usage(&mut tx, id_user).await;
+        let fits = |bytes: u64, count: u64| quota.max_bytes.is_none_or(|max| bytes <= max) && quota.max_notes.is_none_or(|max| count <= max);
+
+        //New notes inserted by a previous try are updated like notes the client already knows
+        let keys: Vec<Value> = notes.iter().filter(|note| note.id.is_none()).filter_map(|note| note.idempotency_key.clone()).map(Value::from).collect();
+        let by_key: HashMap<String, u64> = match keys.is_empty() {
+            true => HashMap::new(),
+            false => tx.exec_map(
+                format!("SELECT idempotency_key, id FROM note WHERE id_user = ? AND idempotency_key IN ({})", placeholders(keys.len())),
+                [Value::from(id_user)].into_iter().chain(keys).collect::<Vec<_>>(),
+                |(key, id)| (key, id),
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .collect()
+        };
+
+        for note in notes.iter_mut().filter(|note| note.id.is_none()) {
+            note.id = note.idempotency_key.as_ref().and_then(|key| by_key.get(key)).copied();
+        }
+
+        //Owner, date, size and share permission of the notes already on the server, locked until the end of the transaction
+        let ids: Vec<Value> = notes.iter().filter_map(|note| note.id).map(Value::from).collect();
+        let existing: HashMap<u64, (u32, i64, u64, Option<String>)> = match ids.is_empty() {
+            true => HashMap::new(),
+            false => tx.exec_map(
+                format!("SELECT note.id, note.id_user, note.updated_at, LENGTH(note.content), note_share.permission FROM note
+                    LEFT JOIN note_share ON note_share.id_note = note.id AND note_share.id_user = ?
+                    WHERE note.id IN ({}) FOR UPDATE", placeholders(ids.len())),
+                [Value::from(id_user)].into_iter().chain(ids).collect::<Vec<_>>(),
+                |(id, id_user, updated_at, size, permission)| (id, (id_user, updated_at, size, permission)),
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .collect()
+        };
+
+        let mut updated = Vec::new();
+        let write = shared::SharePermission::Write.to_string();
+
+        for mut note in notes {
+            note.id_user = Some(id_user);
+            let id_client = note.id_client;
+
+            let size = note.content.len() as u64;
+
+            let (id_server, status) = match note.id {
+                _ if size > quota.max_note_size => (note.id.unwrap_or(0), shared::NoteStatus::TooLarge),
+                Some(id) => match existing.get(&id) {
+                    Some((owner, _, _, permission)) if *owner != id_user && permission.as_ref() != Some(&write) => (id, shared::NoteStatus::Forbidden),
+                    //Sent again after a timeout, it is already saved
+                    Some(&(_, updated_at, _, _)) if updated_at == note.updated_at && note.idempotency_key.is_some() => (id, shared::NoteStatus::Ok),
+                    Some(&(_, updated_at, _, _)) if updated_at > note.updated_at => (id, shared::NoteStatus::Conflict),
+                    //Counted in the storage of the owner, which the recipient can't free
+                    Some(&(owner, _, _, _)) if owner != id_user => {
+                        updated.push(note);
+                        (id, shared::NoteStatus::Ok)
+                    },
+                    //A note getting smaller is always accepted so the user can free space
+                    Some(&(_, _, old_size, _)) if size > old_size && !fits(bytes + size - old_size, count) => (id, shared::NoteStatus::QuotaExceeded),
+                    Some(&(_, _, old_size, _)) => {
+                        bytes = (bytes + size).saturating_sub(old_size);
+                        updated.push(note);
+                        (id, shared::NoteStatus::Ok)
+                    },
+                    None => (id, shared::NoteStatus::Forbidden)
+                },
+                None if !fits(bytes + size, count + 1) => (0, shared::NoteStatus::QuotaExceeded),
+                None => {
+                    note.insert(&mut tx).await;
+                    bytes += size;
+                    count += 1;
+                    (tx.last_insert_id().unwrap(), shared::NoteStatus::Ok)
+                }
+            };
+
+            results.push(shared::SentNotesResult { id_client, id_server, status });
+        }
+
+        if !updated.is_empty() {
+            NoteRevision::archive(&mut tx, &updated, now).await;
+
+            tx.exec_batch(
+                "UPDATE note 
+                SET title = :title, content = :content, nonce = :nonce, updated_at = :updated_at 
+                WHERE id = :id",
+                updated.iter().map(|note| params!(
+                    "title" => &note.title,
+                    "content" => &note.content,
+                    "nonce" => &note.nonce,
+                    "updated_at" => &note.updated_at,
+                    "id" => &note.id
+                )),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.commit().await.unwrap();
+
+        results
+    }
+
+    /// Bytes stored by the user, encrypted notes and attachment chunks, and number of notes
+    pub async fn usage(conn: &mut impl Queryable, id_user: u32) -> (u64, u64) {
+        conn.exec_first(
+            "SELECT
+                CAST((SELECT COALESCE(SUM(LENGTH(content)), 0) FROM note WHERE id_user = :id_user)
+                    + (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM chunk WHERE id_user = :id_user) AS UNSIGNED),
+                (SELECT COUNT(*) FROM note WHERE id_user = :id_user)",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Owner of the note, None if it doesn't exist
+    pub async fn select_owner(conn: &mut Conn, id: u64) -> Option<u32> {
+        conn.exec_first(
+            "SELECT id_user FROM note WHERE id = :id",
+            params!(
+                "id" => id
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Notes of a user and notes shared with it strictly after a cursor, in cursor order so the last one gives the next cursor
+    pub async fn select_page(conn: &mut Conn, id_user: u32, cursor: shared::NoteCursor, limit: u32) -> Vec<Self> {
+        conn.exec(
+            "SELECT * FROM note WHERE (id_user = :id_user OR id IN (SELECT id_note FROM note_share WHERE id_user = :id_user))
+                AND (updated_at > :updated_at OR (updated_at = :updated_at AND id > :id))
+                ORDER BY updated_at, id
+                LIMIT :limit",
+            params!(
+                "id_user" => id_user,
+                "updated_at" => cursor.updated_at,
+                "id" => cursor.id,
+                "limit" => limit
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Replace the content and the key of a note and every recipient it is shared with, in one transaction.
+    /// Return false if the note has been updated since `expected_updated_at` or if the key is not newer than the current one.
+    /// Revisions are replaced by `revisions`, encrypted with the new key, the others are deleted since they can't be decrypted by the owner anymore.
+    pub async fn rekey(&self, conn: &mut Conn, expected_updated_at: i64, key: &NoteKey, shares: &[NoteShare], revisions: &[shared::RotatedRevision]) -> bool {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        let updated_at: Option<i64> = tx.exec_first(
+            "SELECT updated_at FROM note WHERE id = :id FOR UPDATE",
+            params!(
+                "id" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        let version: Option<u32> = tx.exec_first(
+            "SELECT version FROM note_key WHERE id_note = :id_note FOR UPDATE",
+            params!(
+                "id_note" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        if updated_at != Some(expected_updated_at) || version.is_some_and(|version| version >= key.version) {
+            tx.rollback().await.unwrap();
+            return false;
+        }
+
+        tx.exec_batch(
+            "UPDATE note_revision SET content = :content, nonce = :nonce, key_version = :key_version
+            WHERE id = :id AND id_note = :id_note",
+            revisions.iter().map(|revision| params!(
+                "content" => &revision.content,
+                "nonce" => &revision.nonce,
+                "key_version" => &key.version,
+                "id" => &revision.id,
+                "id_note" => &self.id
+            )),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM note_revision WHERE id_note = :id_note AND (key_version IS NULL OR key_version != :key_version)",
+            params!(
+                "id_note" => &self.id,
+                "key_version" => &key.version
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "UPDATE note 
+            SET title = :title, content = :content, nonce = :nonce, updated_at = :updated_at 
+            WHERE id = :id",
+            params!(
+                "title" => &self.title,
+                "content" => &self.content,
+                "nonce" => &self.nonce,
+                "updated_at" => &self.updated_at,
+                "id" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "INSERT INTO note_key (id_note, version, encrypted_key, key_nonce)
+            VALUES (:id_note, :version, :encrypted_key, :key_nonce)
+            ON DUPLICATE KEY UPDATE version = VALUES(version), encrypted_key = VALUES(encrypted_key), key_nonce = VALUES(key_nonce)",
+            params!(
+                "id_note" => &key.id_note,
+                "version" => &key.version,
+                "encrypted_key" => &key.encrypted_key,
+                "key_nonce" => &key.key_nonce
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM note_share WHERE id_note = :id_note",
+            params!(
+                "id_note" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_batch(
+            "INSERT INTO note_share (id_note, id_user, permission, encrypted_key, key_nonce)
+            VALUES (:id_note, :id_user, :permission, :encrypted_key, :key_nonce)",
+            shares.iter().map(|share| params!(
+                "id_note" => &share.id_note,
+                "id_user" => &share.id_user,
+                "permission" => share.permission.to_string(),
+                "encrypted_key" => &share.encrypted_key,
+                "key_nonce" => &share.key_nonce
+            )),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        true
+    }
+}
+
+/// Previous version of a note, revisions aren't counted in the quota of the user, their number and age are limited instead
+#[derive(Debug)]
+pub struct NoteRevision {
+    pub id: u64,
+    pub id_note: u64,
+    pub id_user: u32,
+    pub title: String,
+    pub content: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub updated_at: i64,
+    pub archived_at: i64,
+    pub key_version: Option<u32>, //Version of the note key the content is encrypted with, None for the master encryption key of the owner
+}
+
+impl FromRow for NoteRevision {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(NoteRevision {
+            id: row.get(0).ok_or(FromRowError(row.clone()))?,
+            id_note: row.get(1).ok_or(FromRowError(row.clone()))?,
+            id_user: row.get(2).ok_or(FromRowError(row.clone()))?,
+            title: row.get(3).ok_or(FromRowError(row.clone()))?,
+            content: row.get(4).ok_or(FromRowError(row.clone()))?,
+            nonce: row.get(5).ok_or(FromRowError(row.clone()))?,
+            updated_at: row.get(6).ok_or(FromRowError(row.clone()))?,
+            archived_at: row.get(7).ok_or(FromRowError(row.clone()))?,
+            key_version: row.get(8).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl From<NoteRevision> for shared::NoteRevisionContent {
+    fn from(revision: NoteRevision) -> Self {
+        shared::NoteRevisionContent {
+            id: revision.id,
+            id_note: revision.id_note,
+            title: revision.title,
+            content: revision.content,
+            nonce: revision.nonce,
+            updated_at: revision.updated_at,
+            key_version: revision.key_version,
+        }
+    }
+}
+
+impl NoteRevision {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS note_revision (
+                id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_note BIGINT UNSIGNED NOT NULL,
+                id_user INT UNSIGNED NOT NULL,
+                title TEXT,
+                content LONGBLOB,
+                nonce BLOB,
+                updated_at BIGINT NOT NULL,
+                archived_at BIGINT NOT NULL,
+                key_version INT UNSIGNED NULL,
+                INDEX (id_note, id),
+                INDEX (archived_at),
+                FOREIGN KEY (id_note) REFERENCES note(id),
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Add the key version to a table created by an older version.
+    /// Revisions were deleted when the key of their note changed, existing ones use its current key.
+    pub async fn migrate(conn: &mut Conn) {
+        let missing: Option<u32> = conn.query_first("SELECT COUNT(*) FROM information_schema.tables
+                WHERE table_schema = DATABASE() AND table_name = 'note_revision'
+                AND NOT EXISTS (SELECT 1 FROM information_schema.columns
+                    WHERE table_schema = DATABASE() AND table_name = 'note_revision' AND column_name = 'key_version')")
+            .await
+            .unwrap();
+
+        if missing != Some(1) {
+            return;
+        }
+
+        conn.query_drop("ALTER TABLE note_revision ADD COLUMN key_version INT UNSIGNED NULL AFTER archived_at")
+            .await
+            .unwrap();
+
+        //Databases from before sharing have no note key, all their revisions use the master encryption key
+        let keys: Option<u32> = conn.query_first("SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'note_key'")
+            .await
+            .unwrap();
+
+        if keys == Some(1) {
+            conn.query_drop("UPDATE note_revision JOIN note_key ON note_key.id_note = note_revision.id_note SET note_revision.key_version = note_key.version")
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Copy the stored version of notes about to be replaced by `notes`, with the version of their key.
+    /// A note sent again with the same nonce is the same version, retrying an upload doesn't add a revision.
+    pub async fn archive(conn: &mut impl Queryable, notes: &[Note], archived_at: i64) {
+        conn.exec_batch(
+            "INSERT INTO note_revision (id_note, id_user, title, content, nonce, updated_at, archived_at, key_version)
+            SELECT note.id, note.id_user, note.title, note.content, note.nonce, note.updated_at, :archived_at, note_key.version FROM note
+            LEFT JOIN note_key ON note_key.id_note = note.id
+            WHERE note.id = :id AND note.nonce != :nonce",
+            notes.iter().map(|note| params!(
+                "archived_at" => archived_at,
+                "id" => &note.id,
+                "nonce" => &note.nonce
+            )),
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Revisions of a note without their content, most recent first
+    pub async fn select_all(conn: &mut Conn, id_note: u64) -> Vec<shared::NoteRevision> {
+        conn.exec_map(
+            "SELECT id, id_note, updated_at, archived_at, LENGTH(content), key_version FROM note_revision
+            WHERE id_note = :id_note
+            ORDER BY id DESC",
+            params!(
+                "id_note" => id_note
+            ),
+            |(id, id_note, updated_at, archived_at, size, key_version)| shared::NoteRevision { id, id_note, updated_at, archived_at, size, key_version },
+        )
+        .await
+        .unwrap()
+    }
+
+    pub async fn select(conn: &mut Conn, id: u64, id_note: u64) -> Option<Self> {
+        conn.exec_first(
+            "SELECT * FROM note_revision WHERE id = :id AND id_note = :id_note",
+            params!(
+                "id" => id,
+                "id_note" => id_note
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Delete the revisions archived before `archived_before` and those beyond the `max_per_note` most recent of their note
+    pub async fn delete_expired(conn: &mut Conn, archived_before: i64, max_per_note: u64) -> u64 {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM note_revision WHERE archived_at < :archived_before",
+            params!(
+                "archived_before" => archived_before
+            ),
+        )
+        .await
+        .unwrap();
+
+        let mut deleted = tx.affected_rows();
+
+        tx.exec_drop(
+            "DELETE note_revision FROM note_revision
+            JOIN (
+                SELECT id, ROW_NUMBER() OVER (PARTITION BY id_note ORDER BY id DESC) AS position FROM note_revision
+            ) ranked ON ranked.id = note_revision.id
+            WHERE ranked.position > :max_per_note",
+            params!(
+                "max_per_note" => max_per_note
+            ),
+        )
+        .await
+        .unwrap();
+
+        deleted += tx.affected_rows();
+
+        tx.commit().await.unwrap();
+
+        deleted
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize)]
 pub struct User {
     pub id: Option<u32>,
     pub username: String,
@@ -135,6 +672,33 @@ pub struct User {
     pub salt_recovery_data: String,
     pub salt_server_auth: String,
     pub salt_server_recovery: String,
+    pub salt_server_mek: String,
+    pub stored_mek_hash: String,
+    pub kdf_password: KdfParams,
+    pub kdf_recovery_auth: KdfParams,
+    pub kdf_recovery_data: KdfParams,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("stored_password_hash", &REDACTED)
+            .field("stored_recovery_hash", &REDACTED)
+            .field("salt_auth", &self.salt_auth)
+            .field("salt_data", &self.salt_data)
+            .field("salt_recovery_auth", &self.salt_recovery_auth)
+            .field("salt_recovery_data", &self.salt_recovery_data)
+            .field("salt_server_auth", &self.salt_server_auth)
+            .field("salt_server_recovery", &self.salt_server_recovery)
+            .field("salt_server_mek", &self.salt_server_mek)
+            .field("stored_mek_hash", &REDACTED)
+            .field("kdf_password", &self.kdf_password)
+            .field("kdf_recovery_auth", &self.kdf_recovery_auth)
+            .field("kdf_recovery_data", &self.kdf_recovery_data)
+            .finish_non_exhaustive()
+    }
 }
 
 impl FromRow for User {
@@ -154,10 +718,22 @@ impl FromRow for User {
             salt_recovery_data: row.get(11).ok_or(FromRowError(row.clone()))?,
             salt_server_auth: row.get(12).ok_or(FromRowError(row.clone()))?,
             salt_server_recovery: row.get(13).ok_or(FromRowError(row.clone()))?,
+            salt_server_mek: row.get(14).ok_or(FromRowError(row.clone()))?,
+            stored_mek_hash: row.get(15).ok_or(FromRowError(row.clone()))?,
+            kdf_password: kdf_from_row(&row, 16)?,
+            kdf_recovery_auth: kdf_from_row(&row, 17)?,
+            kdf_recovery_data: kdf_from_row(&row, 18)?,
         })
     }
 }
 
+/// Key derivation parameters are stored as text, see `KdfParams` Display
+fn kdf_from_row(row: &Row, index: usize) -> Result<KdfParams, FromRowError> {
+    row.get::<String, _>(index)
+        .and_then(|kdf| kdf.parse().ok())
+        .ok_or(FromRowError(row.clone()))
+}
+
 impl From<shared::User> for User {
     fn from(user: shared::User) -> Self {
         User {
@@ -175,12 +751,101 @@ impl From<shared::User> for User {
             salt_recovery_data: user.salt_recovery_data,
             salt_server_auth: user.salt_server_auth,
             salt_server_recovery: user.salt_server_recovery,
+            salt_server_mek: user.salt_server_mek,
+            stored_mek_hash: user.stored_mek_hash,
+            kdf_password: user.kdf_password,
+            kdf_recovery_auth: user.kdf_recovery_auth,
+            kdf_recovery_data: user.kdf_recovery_data,
         }
     }
 }
 
 impl User {
-    //TODO: pub async fn create(&self, conn: &mut Conn) {}
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS user (
+                id INT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                username VARCHAR(255) NOT NULL UNIQUE,
+                stored_password_hash TEXT NOT NULL,
+                stored_recovery_hash TEXT NOT NULL,
+                encrypted_mek_password BLOB NOT NULL,
+                mek_password_nonce BLOB NOT NULL,
+                encrypted_mek_recovery BLOB NOT NULL,
+                mek_recovery_nonce BLOB NOT NULL,
+                salt_auth TEXT NOT NULL,
+                salt_data TEXT NOT NULL,
+                salt_recovery_auth TEXT NOT NULL,
+                salt_recovery_data TEXT NOT NULL,
+                salt_server_auth TEXT NOT NULL,
+                salt_server_recovery TEXT NOT NULL,
+                salt_server_mek TEXT NOT NULL,
+                stored_mek_hash TEXT NOT NULL,
+                kdf_password VARCHAR(64) NOT NULL,
+                kdf_recovery_auth VARCHAR(64) NOT NULL,
+                kdf_recovery_data VARCHAR(64) NOT NULL
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Add the columns missing from a table created by an older version
+    pub async fn migrate(conn: &mut Conn) {
+        //Accounts created before the parameters were stored used the defaults
+        let default_kdf = format!("VARCHAR(64) NOT NULL DEFAULT '{}'", KdfParams::default());
+
+        //Accounts created before the master encryption key hash have none, data recovery is refused until their next key rotation
+        for (column, definition, after) in [
+            ("salt_server_mek", "TEXT NOT NULL DEFAULT ''", "salt_server_recovery"),
+            ("stored_mek_hash", "TEXT NOT NULL DEFAULT ''", "salt_server_mek"),
+            ("kdf_password", default_kdf.as_str(), "stored_mek_hash"),
+            ("kdf_recovery_auth", default_kdf.as_str(), "kdf_password"),
+            ("kdf_recovery_data", default_kdf.as_str(), "kdf_recovery_auth"),
+        ] {
+            conn.query_drop(format!("ALTER TABLE IF EXISTS user ADD COLUMN IF NOT EXISTS {column} {definition} AFTER {after}"))
+                .await
+                .unwrap();
+        }
+
+        let exists: Option<u32> = conn.query_first("SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'user'")
+            .await
+            .unwrap();
+
+        if exists == Some(0) {
+            return;
+        }
+
+        //Master encryption key hashes were stored as sent by the client before: PHC strings, digests are hex so it's only done once
+        conn.query_drop("UPDATE user SET stored_mek_hash = SHA2(stored_mek_hash, 256) WHERE stored_mek_hash LIKE '$%'")
+            .await
+            .unwrap();
+
+        //Usernames weren't unique before, only the first account with a name could log in.
+        //The others get a name no signup can take, so the index can be added and an admin can tell them apart.
+        let duplicates: Vec<(u32, String)> = conn.query(
+            "SELECT u.id, u.username FROM user u WHERE EXISTS (SELECT 1 FROM user f WHERE f.username = u.username AND f.id < u.id)"
+        )
+        .await
+        .unwrap();
+
+        for (id, username) in duplicates {
+            conn.exec_drop(
+                "UPDATE user SET username = :username WHERE id = :id",
+                params!(
+                    "username" => format!("{username}~{id}"),
+                    "id" => id
+                ),
+            )
+            .await
+            .unwrap();
+
+            warn!(id, username, "duplicate username renamed to {username}~{id}");
+        }
+
+        conn.query_drop("ALTER TABLE user ADD UNIQUE INDEX IF NOT EXISTS username (username)")
+            .await
+            .unwrap();
+    }
 
     pub async fn select(conn: &mut Conn, username: String) -> Option<Self> {
         conn.exec_first(
@@ -193,11 +858,14 @@ impl User {
         .unwrap()
     }
 
-    pub async fn insert(&self, conn: &mut Conn) {
-        conn.exec_drop("INSERT INTO user (username, stored_password_hash, stored_recovery_hash, encrypted_mek_password, mek_password_nonce,
-                encrypted_mek_recovery, mek_recovery_nonce, salt_auth, salt_data, salt_recovery_auth, salt_recovery_data, salt_server_auth, salt_server_recovery) 
+    /// Return the id of the new user, None if the username is already taken
+    pub async fn insert(&self, conn: &mut Conn) -> Option<u32> {
+        let result = conn.exec_drop("INSERT INTO user (username, stored_password_hash, stored_recovery_hash, encrypted_mek_password, mek_password_nonce,
+                encrypted_mek_recovery, mek_recovery_nonce, salt_auth, salt_data, salt_recovery_auth, salt_recovery_data, salt_server_auth, salt_server_recovery,
+                salt_server_mek, stored_mek_hash, kdf_password, kdf_recovery_auth, kdf_recovery_data) 
             VALUES (:username, :stored_password_hash, :stored_recovery_hash, :encrypted_mek_password, :mek_password_nonce, :encrypted_mek_recovery, :mek_recovery_nonce, :salt_auth, 
-                :salt_data, :salt_recovery_auth, :salt_recovery_data, :salt_server_auth, :salt_server_recovery)", 
+                :salt_data, :salt_recovery_auth, :salt_recovery_data, :salt_server_auth, :salt_server_recovery, :salt_server_mek, :stored_mek_hash,
+                :kdf_password, :kdf_recovery_auth, :kdf_recovery_data)", 
             params!(
                 "username" => &self.username,
                 "stored_password_hash" => &self.stored_password_hash,
@@ -212,34 +880,206 @@ impl User {
                 "salt_recovery_data" => &self.salt_recovery_data,
                 "salt_server_auth" => &self.salt_server_auth,
                 "salt_server_recovery" => &self.salt_server_recovery,
-            )).await.unwrap();
-    }
-}
+                "salt_server_mek" => &self.salt_server_mek,
+                "stored_mek_hash" => &self.stored_mek_hash,
+                "kdf_password" => self.kdf_password.to_string(),
+                "kdf_recovery_auth" => self.kdf_recovery_auth.to_string(),
+                "kdf_recovery_data" => self.kdf_recovery_data.to_string(),
+            )).await;
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct UserToken {
-    pub id: Option<u32>,
-    pub id_user: u32,
-    pub token: Vec<u8>,
-}
+        if matches!(&result, Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY) {
+            return None;
+        }
 
-impl FromRow for UserToken {
-    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
-        Ok(UserToken {
-            id: row.get(0).ok_or(FromRowError(row.clone()))?,
-            id_user: row.get(1).ok_or(FromRowError(row.clone()))?,
-            token: row.get(2).ok_or(FromRowError(row.clone()))?,
-        })
+        result.unwrap();
+
+        Some(conn.last_insert_id().unwrap() as u32)
     }
-}
 
-impl UserToken {
-    //TODO: pub async fn create(&self, conn: &mut Conn) {}
+    /// Delete the user and every data the server has about it in one transaction.
+    /// Return the number of deleted notes and tokens.
+    pub async fn delete(&self, conn: &mut Conn) -> (u64, u64) {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
 
-    pub async fn insert(&self, conn: &mut Conn) {
-        conn.exec_drop(
-            "INSERT INTO user_token (id_user, token) 
-            VALUES (:id_user, :token)",
+        for table in ["note_rotation", "revision_rotation"] {
+            tx.exec_drop(
+                format!("DELETE {table} FROM {table} JOIN mek_rotation ON mek_rotation.id = {table}.id_rotation WHERE mek_rotation.id_user = :id_user"),
+                params!(
+                    "id_user" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.exec_drop(
+            "DELETE attachment_chunk FROM attachment_chunk
+            JOIN attachment ON attachment.id = attachment_chunk.id_attachment
+            WHERE attachment.id_user = :id_user",
+            params!(
+                "id_user" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        //Shares of the notes of the user and notes shared with the user
+        for table in ["note_share", "note_key"] {
+            tx.exec_drop(
+                format!("DELETE {table} FROM {table} JOIN note ON note.id = {table}.id_note WHERE note.id_user = :id_user"),
+                params!(
+                    "id_user" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        for table in ["attachment", "chunk", "user_quota", "note_revision", "user_identity", "note_share"] {
+            tx.exec_drop(
+                format!("DELETE FROM {table} WHERE id_user = :id_user"),
+                params!(
+                    "id_user" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.exec_drop(
+            "DELETE FROM note WHERE id_user = :id_user",
+            params!(
+                "id_user" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        let deleted_notes = tx.affected_rows();
+
+        tx.exec_drop(
+            "DELETE FROM user_token WHERE id_user = :id_user",
+            params!(
+                "id_user" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        let deleted_tokens = tx.affected_rows();
+
+        //Tables referencing the user, rows must be deleted before the user
+        for table in ["mek_rotation", "user_totp", "totp_backup_code", "login_attempt", "opaque_record", "opaque_login"] {
+            tx.exec_drop(
+                format!("DELETE FROM {table} WHERE id_user = :id_user"),
+                params!(
+                    "id_user" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.exec_drop(
+            "DELETE FROM user WHERE id = :id",
+            params!(
+                "id" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        (deleted_notes, deleted_tokens)
+    }
+
+    /// Replace everything derived from the password, used when the key derivation parameters are raised
+    /// Accounts using OPAQUE keep an empty password hash, they can't log in with it again; their `record` is replaced in the same transaction
+    pub async fn upgrade_kdf(&self, conn: &mut Conn, upgrade: &shared::KdfUpgrade, record: Option<&[u8]>) {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "UPDATE user
+            SET kdf_password = :kdf_password, salt_auth = :salt_auth, salt_server_auth = :salt_server_auth,
+                stored_password_hash = IF(stored_password_hash = '', '', :stored_password_hash),
+                salt_data = :salt_data, encrypted_mek_password = :encrypted_mek_password, mek_password_nonce = :mek_password_nonce
+            WHERE id = :id",
+            params!(
+                "kdf_password" => upgrade.kdf_password.to_string(),
+                "salt_auth" => &upgrade.salt_auth,
+                "salt_server_auth" => &upgrade.salt_server_auth,
+                "stored_password_hash" => &upgrade.stored_password_hash,
+                "salt_data" => &upgrade.salt_data,
+                "encrypted_mek_password" => &upgrade.encrypted_mek_password,
+                "mek_password_nonce" => &upgrade.mek_password_nonce,
+                "id" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        if let Some(record) = record {
+            tx.exec_drop(
+                "UPDATE opaque_record SET record = :record WHERE id_user = :id_user",
+                params!(
+                    "record" => record,
+                    "id_user" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.commit().await.unwrap();
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UserToken {
+    pub id: Option<u32>,
+    pub id_user: u32,
+    pub token: Vec<u8>,
+}
+
+impl fmt::Debug for UserToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserToken")
+            .field("id", &self.id)
+            .field("id_user", &self.id_user)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+impl FromRow for UserToken {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(UserToken {
+            id: row.get(0).ok_or(FromRowError(row.clone()))?,
+            id_user: row.get(1).ok_or(FromRowError(row.clone()))?,
+            token: row.get(2).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl UserToken {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS user_token (
+                id INT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_user INT UNSIGNED NOT NULL,
+                token BLOB NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn insert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO user_token (id_user, token) 
+            VALUES (:id_user, :token)",
             params!(
                 "id_user" => &self.id_user,
                 "token" => &self.token,
@@ -260,3 +1100,1531 @@ impl UserToken {
         .unwrap()
     }
 }
+
+/// A master encryption key rotation started by a client.
+/// Notes re-encrypted with the new key are staged in `note_rotation` until the rotation is committed.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MekRotation {
+    pub id: Option<u64>,
+    pub id_user: u32,
+    pub committed: bool,
+}
+
+impl FromRow for MekRotation {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(MekRotation {
+            id: row.get(0).ok_or(FromRowError(row.clone()))?,
+            id_user: row.get(1).ok_or(FromRowError(row.clone()))?,
+            committed: row.get(2).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl MekRotation {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS mek_rotation (
+                id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_user INT UNSIGNED NOT NULL,
+                committed BOOLEAN NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Insert a new rotation, any unfinished rotation of the user is discarded
+    pub async fn insert(&self, conn: &mut Conn) -> u64 {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        for table in ["note_rotation", "revision_rotation"] {
+            tx.exec_drop(
+                format!("DELETE {table} FROM {table} JOIN mek_rotation ON mek_rotation.id = {table}.id_rotation WHERE mek_rotation.id_user = :id_user"),
+                params!(
+                    "id_user" => &self.id_user
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.exec_drop(
+            "DELETE FROM mek_rotation WHERE id_user = :id_user AND committed = FALSE",
+            params!(
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "INSERT INTO mek_rotation (id_user, committed) VALUES (:id_user, :committed)",
+            params!(
+                "id_user" => &self.id_user,
+                "committed" => &self.committed
+            ),
+        )
+        .await
+        .unwrap();
+
+        let id = tx.last_insert_id().unwrap();
+
+        tx.commit().await.unwrap();
+
+        id
+    }
+
+    pub async fn select(conn: &mut Conn, id: u64, id_user: u32) -> Option<Self> {
+        conn.exec_first(
+            "SELECT * FROM mek_rotation WHERE id = :id AND id_user = :id_user",
+            params!(
+                "id" => id,
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Count the notes of the user that would not be rotated by this rotation.
+    /// A note is missing if it has not been staged or if it has been modified since it was staged.
+    pub async fn count_missing_notes(&self, conn: &mut Conn) -> u64 {
+        conn.exec_first(
+            "SELECT COUNT(*) FROM note
+            LEFT JOIN note_rotation ON note_rotation.id_note = note.id AND note_rotation.id_rotation = :id_rotation
+            WHERE note.id_user = :id_user
+                AND (note_rotation.id_note IS NULL OR note.updated_at > note_rotation.updated_at)",
+            params!(
+                "id_rotation" => &self.id,
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Atomically swap every staged note, store the new wrapped master encryption key
+    /// and revoke every token of the user except `kept_token`
+    pub async fn commit(&mut self, conn: &mut Conn, commit: &shared::MekRotationCommit, kept_token: &[u8], updated_at: i64) {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "UPDATE note
+            JOIN note_rotation ON note_rotation.id_note = note.id
+            SET note.content = note_rotation.content, note.nonce = note_rotation.nonce, note.updated_at = :updated_at
+            WHERE note_rotation.id_rotation = :id_rotation AND note.id_user = :id_user",
+            params!(
+                "updated_at" => updated_at,
+                "id_rotation" => &self.id,
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "UPDATE user
+            SET salt_data = :salt_data, encrypted_mek_password = :encrypted_mek_password, mek_password_nonce = :mek_password_nonce,
+                salt_recovery_data = :salt_recovery_data, encrypted_mek_recovery = :encrypted_mek_recovery, mek_recovery_nonce = :mek_recovery_nonce,
+                salt_server_mek = :salt_server_mek, stored_mek_hash = :stored_mek_hash, kdf_recovery_data = :kdf_recovery_data
+            WHERE id = :id_user",
+            params!(
+                "salt_data" => &commit.salt_data,
+                "encrypted_mek_password" => &commit.encrypted_mek_password,
+                "mek_password_nonce" => &commit.mek_password_nonce,
+                "salt_recovery_data" => &commit.salt_recovery_data,
+                "encrypted_mek_recovery" => &commit.encrypted_mek_recovery,
+                "mek_recovery_nonce" => &commit.mek_recovery_nonce,
+                "salt_server_mek" => &commit.salt_server_mek,
+                "stored_mek_hash" => &commit.stored_mek_hash,
+                "kdf_recovery_data" => commit.kdf_recovery_data.to_string(),
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        //Other devices must login again to get the new master encryption key
+        tx.exec_drop(
+            "DELETE FROM user_token WHERE id_user = :id_user AND token != :token",
+            params!(
+                "id_user" => &self.id_user,
+                "token" => kept_token
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "UPDATE note_revision
+            JOIN revision_rotation ON revision_rotation.id_revision = note_revision.id
+            SET note_revision.content = revision_rotation.content, note_revision.nonce = revision_rotation.nonce
+            WHERE revision_rotation.id_rotation = :id_rotation AND note_revision.id_user = :id_user",
+            params!(
+                "id_rotation" => &self.id,
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        //Revisions encrypted with the old key that haven't been sent again can't be decrypted anymore.
+        //Revisions of shared notes are encrypted with the key of their note, which is only wrapped again.
+        tx.exec_drop(
+            "DELETE note_revision FROM note_revision
+            LEFT JOIN revision_rotation ON revision_rotation.id_revision = note_revision.id AND revision_rotation.id_rotation = :id_rotation
+            WHERE note_revision.id_user = :id_user AND revision_rotation.id_revision IS NULL AND note_revision.key_version IS NULL",
+            params!(
+                "id_rotation" => &self.id,
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        for table in ["note_rotation", "revision_rotation"] {
+            tx.exec_drop(
+                format!("DELETE FROM {table} WHERE id_rotation = :id_rotation"),
+                params!(
+                    "id_rotation" => &self.id
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        //Attachment keys are small enough to be sent with the commit instead of being staged
+        if let Some(keys) = commit.attachment_keys.as_ref().filter(|keys| !keys.is_empty()) {
+            tx.exec_batch(
+                "UPDATE attachment SET encrypted_key = :encrypted_key, key_nonce = :key_nonce WHERE id = :id AND id_user = :id_user",
+                keys.iter().map(|key| params!(
+                    "encrypted_key" => &key.encrypted_key,
+                    "key_nonce" => &key.key_nonce,
+                    "id" => key.id_server,
+                    "id_user" => &self.id_user
+                )),
+            )
+            .await
+            .unwrap();
+        }
+
+        if let Some(keys) = commit.note_keys.as_ref().filter(|keys| !keys.is_empty()) {
+            tx.exec_batch(
+                "UPDATE note_key
+                JOIN note ON note.id = note_key.id_note
+                SET note_key.encrypted_key = :encrypted_key, note_key.key_nonce = :key_nonce
+                WHERE note_key.id_note = :id_note AND note.id_user = :id_user",
+                keys.iter().map(|key| params!(
+                    "encrypted_key" => &key.encrypted_key,
+                    "key_nonce" => &key.key_nonce,
+                    "id_note" => key.id_server,
+                    "id_user" => &self.id_user
+                )),
+            )
+            .await
+            .unwrap();
+        }
+
+        if let Some(keys) = &commit.identity_keys {
+            tx.exec_drop(
+                "UPDATE user_identity SET encrypted_keys = :encrypted_keys, keys_nonce = :keys_nonce WHERE id_user = :id_user",
+                params!(
+                    "encrypted_keys" => &keys.encrypted_keys,
+                    "keys_nonce" => &keys.keys_nonce,
+                    "id_user" => &self.id_user
+                ),
+            )
+            .await
+            .unwrap();
+        }
+
+        tx.exec_drop(
+            "UPDATE mek_rotation SET committed = TRUE WHERE id = :id",
+            params!(
+                "id" => &self.id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        self.committed = true;
+    }
+}
+
+/// A note re-encrypted with the new master encryption key, waiting for its rotation to be committed
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NoteRotation {
+    pub id_rotation: u64,
+    pub id_note: u64,
+    pub content: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub updated_at: i64,
+}
+
+impl NoteRotation {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS note_rotation (
+                id_rotation BIGINT UNSIGNED NOT NULL,
+                id_note BIGINT UNSIGNED NOT NULL,
+                content LONGBLOB,
+                nonce BLOB,
+                updated_at BIGINT NOT NULL,
+                PRIMARY KEY (id_rotation, id_note),
+                FOREIGN KEY (id_rotation) REFERENCES mek_rotation(id),
+                FOREIGN KEY (id_note) REFERENCES note(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Insert or replace the staged note so an interrupted upload can be sent again
+    pub async fn upsert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO note_rotation (id_rotation, id_note, content, nonce, updated_at)
+            VALUES (:id_rotation, :id_note, :content, :nonce, :updated_at)
+            ON DUPLICATE KEY UPDATE content = VALUES(content), nonce = VALUES(nonce), updated_at = VALUES(updated_at)",
+            params!(
+                "id_rotation" => &self.id_rotation,
+                "id_note" => &self.id_note,
+                "content" => &self.content,
+                "nonce" => &self.nonce,
+                "updated_at" => &self.updated_at
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// A revision re-encrypted with the new master encryption key, waiting for its rotation to be committed.
+/// Revisions can expire before the commit, they are not referenced by a foreign key.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RevisionRotation {
+    pub id_rotation: u64,
+    pub id_revision: u64,
+    pub content: Vec<u8>,
+    pub nonce: Vec<u8>,
+}
+
+impl RevisionRotation {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS revision_rotation (
+                id_rotation BIGINT UNSIGNED NOT NULL,
+                id_revision BIGINT UNSIGNED NOT NULL,
+                content LONGBLOB,
+                nonce BLOB,
+                PRIMARY KEY (id_rotation, id_revision),
+                FOREIGN KEY (id_rotation) REFERENCES mek_rotation(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Insert or replace the staged revision so an interrupted upload can be sent again
+    pub async fn upsert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO revision_rotation (id_rotation, id_revision, content, nonce)
+            VALUES (:id_rotation, :id_revision, :content, :nonce)
+            ON DUPLICATE KEY UPDATE content = VALUES(content), nonce = VALUES(nonce)",
+            params!(
+                "id_rotation" => &self.id_rotation,
+                "id_revision" => &self.id_revision,
+                "content" => &self.content,
+                "nonce" => &self.nonce
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// TOTP second factor of a user, only required at login once `confirmed`
+#[derive(Deserialize, Serialize)]
+pub struct UserTotp {
+    pub id_user: u32,
+    pub secret: Vec<u8>,
+    pub confirmed: bool,
+    pub last_step: u64, //Time step of the last accepted code, a code can't be used twice
+    pub failed_attempts: u32,
+    pub locked_until: i64,
+}
+
+impl fmt::Debug for UserTotp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserTotp")
+            .field("id_user", &self.id_user)
+            .field("secret", &REDACTED)
+            .field("confirmed", &self.confirmed)
+            .field("last_step", &self.last_step)
+            .field("failed_attempts", &self.failed_attempts)
+            .field("locked_until", &self.locked_until)
+            .finish()
+    }
+}
+
+impl FromRow for UserTotp {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(UserTotp {
+            id_user: row.get(0).ok_or(FromRowError(row.clone()))?,
+            secret: row.get(1).ok_or(FromRowError(row.clone()))?,
+            confirmed: row.get(2).ok_or(FromRowError(row.clone()))?,
+            last_step: row.get(3).ok_or(FromRowError(row.clone()))?,
+            failed_attempts: row.get(4).ok_or(FromRowError(row.clone()))?,
+            locked_until: row.get(5).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl UserTotp {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS user_totp (
+                id_user INT UNSIGNED PRIMARY KEY,
+                secret BLOB NOT NULL,
+                confirmed BOOLEAN NOT NULL,
+                last_step BIGINT UNSIGNED NOT NULL,
+                failed_attempts INT UNSIGNED NOT NULL,
+                locked_until BIGINT NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn select(conn: &mut Conn, id_user: u32) -> Option<Self> {
+        conn.exec_first(
+            "SELECT * FROM user_totp WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Insert or replace the pending enrollment of the user
+    pub async fn upsert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO user_totp (id_user, secret, confirmed, last_step, failed_attempts, locked_until)
+            VALUES (:id_user, :secret, :confirmed, :last_step, :failed_attempts, :locked_until)
+            ON DUPLICATE KEY UPDATE secret = VALUES(secret), confirmed = VALUES(confirmed), last_step = VALUES(last_step),
+                failed_attempts = VALUES(failed_attempts), locked_until = VALUES(locked_until)",
+            params!(
+                "id_user" => &self.id_user,
+                "secret" => &self.secret,
+                "confirmed" => &self.confirmed,
+                "last_step" => &self.last_step,
+                "failed_attempts" => &self.failed_attempts,
+                "locked_until" => &self.locked_until
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn update(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "UPDATE user_totp
+            SET confirmed = :confirmed, last_step = :last_step, failed_attempts = :failed_attempts, locked_until = :locked_until
+            WHERE id_user = :id_user",
+            params!(
+                "confirmed" => &self.confirmed,
+                "last_step" => &self.last_step,
+                "failed_attempts" => &self.failed_attempts,
+                "locked_until" => &self.locked_until,
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Single-use code accepted instead of a TOTP code, only its sha256 is stored
+#[derive(Deserialize, Serialize)]
+pub struct TotpBackupCode {
+    pub id: Option<u32>,
+    pub id_user: u32,
+    pub code_hash: String,
+}
+
+impl TotpBackupCode {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS totp_backup_code (
+                id INT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_user INT UNSIGNED NOT NULL,
+                code_hash CHAR(64) NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Replace every backup code of the user
+    pub async fn replace_all(conn: &mut Conn, id_user: u32, code_hashes: &[String]) {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM totp_backup_code WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_batch(
+            "INSERT INTO totp_backup_code (id_user, code_hash) VALUES (:id_user, :code_hash)",
+            code_hashes.iter().map(|code_hash| params!(
+                "id_user" => id_user,
+                "code_hash" => code_hash
+            )),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+    }
+
+    /// Delete the backup code, return false if the user doesn't have it
+    pub async fn consume(conn: &mut Conn, id_user: u32, code_hash: &str) -> bool {
+        conn.exec_drop(
+            "DELETE FROM totp_backup_code WHERE id_user = :id_user AND code_hash = :code_hash LIMIT 1",
+            params!(
+                "id_user" => id_user,
+                "code_hash" => code_hash
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.affected_rows() > 0
+    }
+}
+
+/// Failed logins in a row of a user, the account is locked until `locked_until` once there are too many
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LoginAttempt {
+    pub id_user: u32,
+    pub failed_attempts: u32,
+    pub locked_until: i64,
+}
+
+impl FromRow for LoginAttempt {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(LoginAttempt {
+            id_user: row.get(0).ok_or(FromRowError(row.clone()))?,
+            failed_attempts: row.get(1).ok_or(FromRowError(row.clone()))?,
+            locked_until: row.get(2).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl LoginAttempt {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS login_attempt (
+                id_user INT UNSIGNED PRIMARY KEY,
+                failed_attempts INT UNSIGNED NOT NULL,
+                locked_until BIGINT NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Attempts of the user, without failures if there is no row
+    pub async fn select(conn: &mut Conn, id_user: u32) -> Self {
+        let attempt: Option<Self> = conn.exec_first(
+            "SELECT * FROM login_attempt WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        attempt.unwrap_or(LoginAttempt {
+            id_user,
+            failed_attempts: 0,
+            locked_until: 0,
+        })
+    }
+
+    pub async fn upsert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO login_attempt (id_user, failed_attempts, locked_until)
+            VALUES (:id_user, :failed_attempts, :locked_until)
+            ON DUPLICATE KEY UPDATE failed_attempts = VALUES(failed_attempts), locked_until = VALUES(locked_until)",
+            params!(
+                "id_user" => &self.id_user,
+                "failed_attempts" => &self.failed_attempts,
+                "locked_until" => &self.locked_until
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn delete(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "DELETE FROM login_attempt WHERE id_user = :id_user",
+            params!(
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Invite code minted by an admin, required to create an account when the registration policy is `invite`
+#[derive(Deserialize, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+
+impl FromRow for Invite {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(Invite {
+            code: row.get(0).ok_or(FromRowError(row.clone()))?,
+            max_uses: row.get(1).ok_or(FromRowError(row.clone()))?,
+            uses: row.get(2).ok_or(FromRowError(row.clone()))?,
+            expires_at: row.get(3).ok_or(FromRowError(row.clone()))?,
+            created_at: row.get(4).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl From<Invite> for shared::Invite {
+    fn from(invite: Invite) -> Self {
+        shared::Invite {
+            code: invite.code,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            expires_at: invite.expires_at,
+            created_at: invite.created_at,
+        }
+    }
+}
+
+impl Invite {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS invite (
+                code VARCHAR(64) PRIMARY KEY,
+                max_uses INT UNSIGNED NOT NULL,
+                uses INT UNSIGNED NOT NULL,
+                expires_at BIGINT,
+                created_at BIGINT NOT NULL
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn insert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO invite (code, max_uses, uses, expires_at, created_at)
+            VALUES (:code, :max_uses, :uses, :expires_at, :created_at)",
+            params!(
+                "code" => &self.code,
+                "max_uses" => &self.max_uses,
+                "uses" => &self.uses,
+                "expires_at" => &self.expires_at,
+                "created_at" => &self.created_at
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn select_all(conn: &mut Conn) -> Vec<Self> {
+        conn.query("SELECT * FROM invite ORDER BY created_at")
+            .await
+            .unwrap()
+    }
+
+    /// Return false if there was no such invite
+    pub async fn delete(conn: &mut Conn, code: &str) -> bool {
+        conn.exec_drop(
+            "DELETE FROM invite WHERE code = :code",
+            params!(
+                "code" => code
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.affected_rows() > 0
+    }
+
+    /// Count a use of the invite, return false if it doesn't exist, is expired or has no use left
+    pub async fn consume(conn: &mut Conn, code: &str, now: i64) -> bool {
+        conn.exec_drop(
+            "UPDATE invite SET uses = uses + 1
+            WHERE code = :code AND uses < max_uses AND (expires_at IS NULL OR expires_at > :now)",
+            params!(
+                "code" => code,
+                "now" => now
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.affected_rows() > 0
+    }
+
+    /// Give back a use taken by `consume` when the account could not be created
+    pub async fn release(conn: &mut Conn, code: &str) {
+        conn.exec_drop(
+            "UPDATE invite SET uses = uses - 1 WHERE code = :code AND uses > 0",
+            params!(
+                "code" => code
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Attachment of a note, its chunks are referenced in order by `attachment_chunk`
+#[derive(Debug)]
+pub struct Attachment {
+    pub id: Option<u64>,
+    pub id_user: u32,
+    pub id_note: u64,
+    pub encrypted_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+    pub metadata: Vec<u8>,
+    pub metadata_nonce: Vec<u8>,
+    pub chunks: Vec<String>,
+}
+
+impl Attachment {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS attachment (
+                id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_user INT UNSIGNED NOT NULL,
+                id_note BIGINT UNSIGNED NOT NULL,
+                encrypted_key BLOB NOT NULL,
+                key_nonce BLOB NOT NULL,
+                metadata BLOB NOT NULL,
+                metadata_nonce BLOB NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id),
+                FOREIGN KEY (id_note) REFERENCES note(id)
+            )",
+        )
+        .await
+        .unwrap();
+
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS attachment_chunk (
+                id_attachment BIGINT UNSIGNED NOT NULL,
+                position INT UNSIGNED NOT NULL,
+                hash CHAR(64) NOT NULL,
+                PRIMARY KEY (id_attachment, position),
+                FOREIGN KEY (id_attachment) REFERENCES attachment(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Number of attachments of a note
+    pub async fn count_from_note(conn: &mut Conn, id_note: u64) -> u64 {
+        conn.exec_first(
+            "SELECT COUNT(*) FROM attachment WHERE id_note = :id_note",
+            params!(
+                "id_note" => id_note
+            ),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+    }
+
+    /// Insert the attachment and the references to its chunks, return its id
+    pub async fn insert(&self, conn: &mut Conn) -> u64 {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "INSERT INTO attachment (id_user, id_note, encrypted_key, key_nonce, metadata, metadata_nonce)
+            VALUES (:id_user, :id_note, :encrypted_key, :key_nonce, :metadata, :metadata_nonce)",
+            params!(
+                "id_user" => &self.id_user,
+                "id_note" => &self.id_note,
+                "encrypted_key" => &self.encrypted_key,
+                "key_nonce" => &self.key_nonce,
+                "metadata" => &self.metadata,
+                "metadata_nonce" => &self.metadata_nonce
+            ),
+        )
+        .await
+        .unwrap();
+
+        let id = tx.last_insert_id().unwrap();
+
+        tx.exec_batch(
+            "INSERT INTO attachment_chunk (id_attachment, position, hash) VALUES (:id_attachment, :position, :hash)",
+            self.chunks.iter().enumerate().map(|(position, hash)| params!(
+                "id_attachment" => id,
+                "position" => position as u32,
+                "hash" => hash
+            )),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        id
+    }
+
+    /// Attachments of a user, only the ones of `id_note` if set
+    pub async fn select_all(conn: &mut Conn, id_user: u32, id_note: Option<u64>) -> Vec<Self> {
+        let mut attachments: Vec<Self> = conn.exec_map(
+            "SELECT id, id_note, encrypted_key, key_nonce, metadata, metadata_nonce FROM attachment
+            WHERE id_user = :id_user AND (:id_note IS NULL OR id_note = :id_note)",
+            params!(
+                "id_user" => id_user,
+                "id_note" => id_note
+            ),
+            |(id, id_note, encrypted_key, key_nonce, metadata, metadata_nonce)| Attachment {
+                id: Some(id),
+                id_user,
+                id_note,
+                encrypted_key,
+                key_nonce,
+                metadata,
+                metadata_nonce,
+                chunks: Vec::new(),
+            },
+        )
+        .await
+        .unwrap();
+
+        if attachments.is_empty() {
+            return attachments;
+        }
+
+        let ids: Vec<Value> = attachments.iter().filter_map(|attachment| attachment.id).map(Value::from).collect();
+        let mut chunks: HashMap<u64, Vec<String>> = HashMap::new();
+
+        conn.exec_map(
+            format!("SELECT id_attachment, hash FROM attachment_chunk WHERE id_attachment IN ({}) ORDER BY id_attachment, position", placeholders(ids.len())),
+            ids,
+            |(id_attachment, hash): (u64, String)| (id_attachment, hash),
+        )
+        .await
+        .unwrap()
+        .into_iter()
+        .for_each(|(id_attachment, hash)| chunks.entry(id_attachment).or_default().push(hash));
+
+        for attachment in attachments.iter_mut() {
+            attachment.chunks = chunks.remove(&attachment.id.unwrap()).unwrap_or_default();
+        }
+
+        attachments
+    }
+
+    pub async fn select_ids(conn: &mut Conn, id_user: u32) -> Vec<u64> {
+        conn.exec(
+            "SELECT id FROM attachment WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Delete the attachment if it belongs to the user, its chunks are left to the garbage collection
+    pub async fn delete(conn: &mut Conn, id: u64, id_user: u32) -> bool {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        tx.exec_drop(
+            "DELETE attachment_chunk FROM attachment_chunk
+            JOIN attachment ON attachment.id = attachment_chunk.id_attachment
+            WHERE attachment.id = :id AND attachment.id_user = :id_user",
+            params!(
+                "id" => id,
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM attachment WHERE id = :id AND id_user = :id_user",
+            params!(
+                "id" => id,
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        let deleted = tx.affected_rows() > 0;
+
+        tx.commit().await.unwrap();
+
+        deleted
+    }
+}
+
+impl From<shared::Attachment> for Attachment {
+    fn from(attachment: shared::Attachment) -> Self {
+        Attachment {
+            id: attachment.id,
+            id_user: 0,
+            id_note: attachment.id_note,
+            encrypted_key: attachment.encrypted_key,
+            key_nonce: attachment.key_nonce,
+            metadata: attachment.metadata,
+            metadata_nonce: attachment.metadata_nonce,
+            chunks: attachment.chunks,
+        }
+    }
+}
+
+impl From<Attachment> for shared::Attachment {
+    fn from(attachment: Attachment) -> Self {
+        shared::Attachment {
+            id: attachment.id,
+            id_note: attachment.id_note,
+            encrypted_key: attachment.encrypted_key,
+            key_nonce: attachment.key_nonce,
+            metadata: attachment.metadata,
+            metadata_nonce: attachment.metadata_nonce,
+            chunks: attachment.chunks,
+        }
+    }
+}
+
+/// Encrypted chunk of an attachment, stored once per user and addressed by the sha256 of its bytes
+pub struct Chunk;
+
+impl Chunk {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS chunk (
+                id_user INT UNSIGNED NOT NULL,
+                hash CHAR(64) NOT NULL,
+                data LONGBLOB NOT NULL,
+                created_at BIGINT NOT NULL,
+                PRIMARY KEY (id_user, hash),
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// A chunk already stored is kept as is, its hash guarantees it has the same bytes
+    pub async fn insert(conn: &mut Conn, id_user: u32, hash: &str, data: &[u8], created_at: i64) {
+        conn.exec_drop(
+            "INSERT IGNORE INTO chunk (id_user, hash, data, created_at) VALUES (:id_user, :hash, :data, :created_at)",
+            params!(
+                "id_user" => id_user,
+                "hash" => hash,
+                "data" => data,
+                "created_at" => created_at
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn exists(conn: &mut Conn, id_user: u32, hash: &str) -> bool {
+        let found: Option<u8> = conn.exec_first(
+            "SELECT 1 FROM chunk WHERE id_user = :id_user AND hash = :hash",
+            params!(
+                "id_user" => id_user,
+                "hash" => hash
+            ),
+        )
+        .await
+        .unwrap();
+
+        found.is_some()
+    }
+
+    pub async fn select(conn: &mut Conn, id_user: u32, hash: &str) -> Option<Vec<u8>> {
+        conn.exec_first(
+            "SELECT data FROM chunk WHERE id_user = :id_user AND hash = :hash",
+            params!(
+                "id_user" => id_user,
+                "hash" => hash
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Number of distinct chunks among `hashes` that the user has not uploaded
+    pub async fn count_missing(conn: &mut Conn, id_user: u32, hashes: &[String]) -> usize {
+        let mut hashes: Vec<&String> = hashes.iter().collect();
+        hashes.sort();
+        hashes.dedup();
+
+        if hashes.is_empty() {
+            return 0;
+        }
+
+        let found: usize = conn.exec_first(
+            format!("SELECT COUNT(*) FROM chunk WHERE id_user = ? AND hash IN ({})", placeholders(hashes.len())),
+            [Value::from(id_user)].into_iter().chain(hashes.iter().map(|hash| Value::from(hash.as_str()))).collect::<Vec<_>>(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        hashes.len() - found
+    }
+
+    /// Delete the chunks no attachment references anymore.
+    /// Chunks uploaded after `created_before` are kept, they may belong to an attachment still being uploaded.
+    pub async fn delete_unreferenced(conn: &mut Conn, created_before: i64) -> u64 {
+        conn.exec_drop(
+            "DELETE chunk FROM chunk
+            LEFT JOIN (
+                SELECT DISTINCT attachment.id_user, attachment_chunk.hash FROM attachment_chunk
+                JOIN attachment ON attachment.id = attachment_chunk.id_attachment
+            ) used ON used.id_user = chunk.id_user AND used.hash = chunk.hash
+            WHERE used.hash IS NULL AND chunk.created_at < :created_before",
+            params!(
+                "created_before" => created_before
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.affected_rows()
+    }
+}
+
+/// Limits of a user set by an admin, a None column keeps the limit of the instance
+#[derive(Debug)]
+pub struct UserQuota {
+    pub id_user: u32,
+    pub max_bytes: Option<u64>,
+    pub max_notes: Option<u64>,
+    pub max_note_size: Option<u64>,
+}
+
+impl FromRow for UserQuota {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(UserQuota {
+            id_user: row.get(0).ok_or(FromRowError(row.clone()))?,
+            max_bytes: row.get(1).ok_or(FromRowError(row.clone()))?,
+            max_notes: row.get(2).ok_or(FromRowError(row.clone()))?,
+            max_note_size: row.get(3).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl UserQuota {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS user_quota (
+                id_user INT UNSIGNED PRIMARY KEY,
+                max_bytes BIGINT UNSIGNED NULL,
+                max_notes BIGINT UNSIGNED NULL,
+                max_note_size BIGINT UNSIGNED NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn select(conn: &mut Conn, id_user: u32) -> Option<Self> {
+        conn.exec_first(
+            "SELECT * FROM user_quota WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    pub async fn upsert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT INTO user_quota (id_user, max_bytes, max_notes, max_note_size)
+            VALUES (:id_user, :max_bytes, :max_notes, :max_note_size)
+            ON DUPLICATE KEY UPDATE max_bytes = VALUES(max_bytes), max_notes = VALUES(max_notes), max_note_size = VALUES(max_note_size)",
+            params!(
+                "id_user" => &self.id_user,
+                "max_bytes" => &self.max_bytes,
+                "max_notes" => &self.max_notes,
+                "max_note_size" => &self.max_note_size
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn delete(conn: &mut Conn, id_user: u32) {
+        conn.exec_drop(
+            "DELETE FROM user_quota WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// Identity keys of a user, the private keys are encrypted with its master encryption key
+#[derive(Debug)]
+pub struct UserIdentity {
+    pub id_user: u32,
+    pub x25519_public: Vec<u8>,
+    pub ed25519_public: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub encrypted_keys: Vec<u8>,
+    pub keys_nonce: Vec<u8>,
+}
+
+impl FromRow for UserIdentity {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(UserIdentity {
+            id_user: row.get(0).ok_or(FromRowError(row.clone()))?,
+            x25519_public: row.get(1).ok_or(FromRowError(row.clone()))?,
+            ed25519_public: row.get(2).ok_or(FromRowError(row.clone()))?,
+            signature: row.get(3).ok_or(FromRowError(row.clone()))?,
+            encrypted_keys: row.get(4).ok_or(FromRowError(row.clone()))?,
+            keys_nonce: row.get(5).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl From<UserIdentity> for shared::Identity {
+    fn from(identity: UserIdentity) -> Self {
+        shared::Identity {
+            x25519_public: identity.x25519_public,
+            ed25519_public: identity.ed25519_public,
+            signature: identity.signature,
+            encrypted_keys: identity.encrypted_keys,
+            keys_nonce: identity.keys_nonce
+        }
+    }
+}
+
+impl UserIdentity {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS user_identity (
+                id_user INT UNSIGNED PRIMARY KEY,
+                x25519_public BLOB NOT NULL,
+                ed25519_public BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                encrypted_keys BLOB NOT NULL,
+                keys_nonce BLOB NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Insert the identity, false if the user already has one since its keys can't be replaced
+    pub async fn insert(&self, conn: &mut Conn) -> bool {
+        let result = conn.exec_drop(
+            "INSERT INTO user_identity (id_user, x25519_public, ed25519_public, signature, encrypted_keys, keys_nonce)
+            VALUES (:id_user, :x25519_public, :ed25519_public, :signature, :encrypted_keys, :keys_nonce)",
+            params!(
+                "id_user" => &self.id_user,
+                "x25519_public" => &self.x25519_public,
+                "ed25519_public" => &self.ed25519_public,
+                "signature" => &self.signature,
+                "encrypted_keys" => &self.encrypted_keys,
+                "keys_nonce" => &self.keys_nonce
+            ),
+        )
+        .await;
+
+        if matches!(&result, Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY) {
+            return false;
+        }
+
+        result.unwrap();
+
+        true
+    }
+
+    pub async fn select(conn: &mut Conn, id_user: u32) -> Option<Self> {
+        conn.exec_first(
+            "SELECT * FROM user_identity WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+}
+
+/// Key of a shared note, wrapped with the master encryption key of its owner
+#[derive(Debug)]
+pub struct NoteKey {
+    pub id_note: u64,
+    pub version: u32,
+    pub encrypted_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+}
+
+impl NoteKey {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS note_key (
+                id_note BIGINT UNSIGNED PRIMARY KEY,
+                version INT UNSIGNED NOT NULL,
+                encrypted_key BLOB NOT NULL,
+                key_nonce BLOB NOT NULL,
+                FOREIGN KEY (id_note) REFERENCES note(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Notes of the user having a key
+    pub async fn select_ids(conn: &mut Conn, id_user: u32) -> Vec<u64> {
+        conn.exec(
+            "SELECT note_key.id_note FROM note_key JOIN note ON note.id = note_key.id_note WHERE note.id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Keys of the notes sent to a user, wrapped with its master encryption key for its own notes
+    /// and for the user with the identity of the owner for notes shared with it
+    pub async fn select_for(conn: &mut Conn, id_user: u32, notes: &[Note]) -> HashMap<u64, shared::NoteKey> {
+        let (owned, shared): (Vec<&Note>, Vec<&Note>) = notes.iter().partition(|note| note.id_user == Some(id_user));
+        let mut keys = HashMap::new();
+
+        if !owned.is_empty() {
+            let ids: Vec<Value> = owned.iter().map(|note| Value::from(note.id)).collect();
+
+            let owned_keys = conn.exec_map(
+                format!("SELECT id_note, version, encrypted_key, key_nonce FROM note_key WHERE id_note IN ({})", placeholders(ids.len())),
+                ids,
+                |(id_note, version, encrypted_key, key_nonce)| (id_note, shared::NoteKey {
+                    version,
+                    encrypted_key,
+                    key_nonce,
+                    owner: None,
+                    permission: shared::SharePermission::Write
+                }),
+            )
+            .await
+            .unwrap();
+
+            keys.extend(owned_keys);
+        }
+
+        if !shared.is_empty() {
+            let ids: Vec<Value> = shared.iter().map(|note| Value::from(note.id)).collect();
+
+            let shared_keys = conn.exec_map(
+                format!("SELECT note_share.id_note, note_key.version, note_share.encrypted_key, note_share.key_nonce, note_share.permission,
+                        user.username, user_identity.x25519_public, user_identity.ed25519_public, user_identity.signature
+                    FROM note_share
+                    JOIN note_key ON note_key.id_note = note_share.id_note
+                    JOIN note ON note.id = note_share.id_note
+                    JOIN user ON user.id = note.id_user
+                    JOIN user_identity ON user_identity.id_user = note.id_user
+                    WHERE note_share.id_user = ? AND note_share.id_note IN ({})", placeholders(ids.len())),
+                [Value::from(id_user)].into_iter().chain(ids).collect::<Vec<_>>(),
+                |(id_note, version, encrypted_key, key_nonce, permission, username, x25519_public, ed25519_public, signature)| {
+                    let permission: String = permission;
+
+                    (id_note, shared::NoteKey {
+                        version,
+                        encrypted_key,
+                        key_nonce,
+                        owner: Some(shared::PublicIdentity { username, x25519_public, ed25519_public, signature }),
+                        permission: permission.parse().unwrap()
+                    })
+                },
+            )
+            .await
+            .unwrap();
+
+            keys.extend(shared_keys);
+        }
+
+        keys
+    }
+}
+
+/// Note key wrapped for a user the note is shared with, with a key agreed between the identities of the owner and the user
+#[derive(Debug)]
+pub struct NoteShare {
+    pub id_note: u64,
+    pub id_user: u32,
+    pub permission: shared::SharePermission,
+    pub encrypted_key: Vec<u8>,
+    pub key_nonce: Vec<u8>,
+}
+
+impl NoteShare {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS note_share (
+                id_note BIGINT UNSIGNED NOT NULL,
+                id_user INT UNSIGNED NOT NULL,
+                permission VARCHAR(8) NOT NULL,
+                encrypted_key BLOB NOT NULL,
+                key_nonce BLOB NOT NULL,
+                PRIMARY KEY (id_note, id_user),
+                INDEX (id_user),
+                FOREIGN KEY (id_note) REFERENCES note(id),
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    /// Users a note is shared with
+    pub async fn select_all(conn: &mut Conn, id_note: u64) -> Vec<shared::NoteShare> {
+        conn.exec_map(
+            "SELECT user.username, note_share.permission FROM note_share
+            JOIN user ON user.id = note_share.id_user
+            WHERE note_share.id_note = :id_note
+            ORDER BY user.username",
+            params!(
+                "id_note" => id_note
+            ),
+            |(username, permission): (String, String)| shared::NoteShare { username, permission: permission.parse().unwrap() },
+        )
+        .await
+        .unwrap()
+    }
+}
+
+/// OPAQUE keys of the server, a single row
+pub struct OpaqueSetup {
+    pub setup: Vec<u8>,
+}
+
+impl OpaqueSetup {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS opaque_setup (
+                id TINYINT UNSIGNED PRIMARY KEY,
+                setup BLOB NOT NULL
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn select(conn: &mut Conn) -> Option<Self> {
+        conn.query_first("SELECT setup FROM opaque_setup WHERE id = 1")
+            .await
+            .unwrap()
+            .map(|setup| OpaqueSetup { setup })
+    }
+
+    /// Keys already stored are kept
+    pub async fn insert(&self, conn: &mut Conn) {
+        conn.exec_drop(
+            "INSERT IGNORE INTO opaque_setup (id, setup) VALUES (1, :setup)",
+            params!(
+                "setup" => &self.setup
+            ),
+        )
+        .await
+        .unwrap();
+    }
+}
+
+/// OPAQUE record of the password of a user, it can't be used to log in
+pub struct OpaqueRecord {
+    pub id_user: u32,
+    pub record: Vec<u8>,
+}
+
+impl fmt::Debug for OpaqueRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpaqueRecord")
+            .field("id_user", &self.id_user)
+            .field("record", &REDACTED)
+            .finish()
+    }
+}
+
+impl OpaqueRecord {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS opaque_record (
+                id_user INT UNSIGNED PRIMARY KEY,
+                record BLOB NOT NULL,
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn select(conn: &mut Conn, id_user: u32) -> Option<Self> {
+        conn.exec_first(
+            "SELECT record FROM opaque_record WHERE id_user = :id_user",
+            params!(
+                "id_user" => id_user
+            ),
+        )
+        .await
+        .unwrap()
+        .map(|record| OpaqueRecord { id_user, record })
+    }
+
+    /// Store the record and forget the password hash in one transaction, false if the user already has a record
+    pub async fn insert(&self, conn: &mut Conn) -> bool {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        let result = tx.exec_drop(
+            "INSERT INTO opaque_record (id_user, record) VALUES (:id_user, :record)",
+            params!(
+                "id_user" => &self.id_user,
+                "record" => &self.record
+            ),
+        )
+        .await;
+
+        if matches!(&result, Err(mysql_async::Error::Server(e)) if e.code == ER_DUP_ENTRY) {
+            return false;
+        }
+
+        result.unwrap();
+
+        tx.exec_drop(
+            "UPDATE user SET stored_password_hash = '' WHERE id = :id_user",
+            params!(
+                "id_user" => &self.id_user
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        true
+    }
+}
+
+/// OPAQUE login started and not finished yet, `id_user` is None for unknown usernames
+pub struct OpaqueLogin {
+    pub id_user: Option<u32>,
+    pub state: Vec<u8>,
+    pub created_at: i64,
+}
+
+impl FromRow for OpaqueLogin {
+    fn from_row_opt(row: Row) -> Result<Self, FromRowError> {
+        Ok(OpaqueLogin {
+            id_user: row.get(0).ok_or(FromRowError(row.clone()))?,
+            state: row.get(1).ok_or(FromRowError(row.clone()))?,
+            created_at: row.get(2).ok_or(FromRowError(row.clone()))?,
+        })
+    }
+}
+
+impl OpaqueLogin {
+    pub async fn create(conn: &mut Conn) {
+        conn.query_drop(
+            "CREATE TABLE IF NOT EXISTS opaque_login (
+                id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+                id_user INT UNSIGNED NULL,
+                state BLOB NOT NULL,
+                created_at BIGINT NOT NULL,
+                INDEX (created_at),
+                FOREIGN KEY (id_user) REFERENCES user(id)
+            )",
+        )
+        .await
+        .unwrap();
+    }
+
+    pub async fn insert(&self, conn: &mut Conn) -> u64 {
+        conn.exec_drop(
+            "INSERT INTO opaque_login (id_user, state, created_at) VALUES (:id_user, :state, :created_at)",
+            params!(
+                "id_user" => &self.id_user,
+                "state" => &self.state,
+                "created_at" => &self.created_at
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.last_insert_id().unwrap()
+    }
+
+    /// Select and delete the login, it can only be finished once
+    pub async fn take(conn: &mut Conn, id: u64) -> Option<Self> {
+        let mut tx = conn.start_transaction(TxOpts::default()).await.unwrap();
+
+        let login: Option<Self> = tx.exec_first(
+            "SELECT id_user, state, created_at FROM opaque_login WHERE id = :id FOR UPDATE",
+            params!(
+                "id" => id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.exec_drop(
+            "DELETE FROM opaque_login WHERE id = :id",
+            params!(
+                "id" => id
+            ),
+        )
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+
+        login
+    }
+
+    /// Delete the logins started before `created_before`, return how many were deleted
+    pub async fn delete_expired(conn: &mut Conn, created_before: i64) -> u64 {
+        conn.exec_drop(
+            "DELETE FROM opaque_login WHERE created_at < :created_before",
+            params!(
+                "created_before" => created_before
+            ),
+        )
+        .await
+        .unwrap();
+
+        conn.affected_rows()
+    }
+}
@@ -0,0 +1,100 @@
+use std::env;
+
+use rand_core::{OsRng, TryRngCore};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, TOTP};
+
+const STEP: u64 = 30;
+const DIGITS: usize = 6;
+const SECRET_SIZE: usize = 20; //160 bits, recommended by RFC 4226
+const SKEW: u64 = 1; //Codes of the previous and next step are accepted, clocks of phones drift
+
+pub const BACKUP_CODES: usize = 10;
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+pub const LOCK_SECONDS: i64 = 5 * 60;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_SIZE];
+    OsRng.try_fill_bytes(&mut secret).unwrap();
+
+    secret
+}
+
+/// Issuer shown by authenticator apps can be set with TOTP_ISSUER
+pub fn totp(secret: Vec<u8>, username: &str) -> TOTP {
+    let issuer = env::var("TOTP_ISSUER").unwrap_or_else(|_| "Notto".to_string());
+
+    TOTP::new_unchecked(Algorithm::SHA1, DIGITS, 0, STEP, secret, Some(issuer), username.to_string())
+}
+
+/// Return the time step of the code if it is valid and newer than `last_step`, so a code can't be replayed
+pub fn verify(totp: &TOTP, code: &str, last_step: u64, now: u64) -> Option<u64> {
+    let current = now / STEP;
+
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| totp.check(code, step * STEP))
+}
+
+/// Backup codes are 10 hex characters, typed by hand when the phone is lost
+pub fn generate_backup_codes() -> Vec<String> {
+    (0..BACKUP_CODES).map(|_| {
+        let mut code = [0u8; 5];
+        OsRng.try_fill_bytes(&mut code).unwrap();
+
+        hex::encode(code)
+    }).collect()
+}
+
+/// Spaces, dashes and case of the typed code are ignored
+pub fn hash_backup_code(code: &str) -> String {
+    let code: String = code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    hex::encode(Sha256::digest(code.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000_000 * STEP;
+
+    fn test_totp() -> TOTP {
+        totp(vec![7; SECRET_SIZE], "alice")
+    }
+
+    #[test]
+    fn verify_refuses_replayed_codes() {
+        let totp = test_totp();
+        let code = totp.generate(NOW);
+
+        assert_eq!(verify(&totp, &code, 0, NOW), Some(NOW / STEP));
+
+        //Once its step is used, or a later one, the code is refused
+        assert_eq!(verify(&totp, &code, NOW / STEP, NOW), None);
+        assert_eq!(verify(&totp, &code, NOW / STEP + 1, NOW), None);
+    }
+
+    #[test]
+    fn verify_accepts_skew() {
+        let totp = test_totp();
+
+        assert_eq!(verify(&totp, &totp.generate(NOW - STEP), 0, NOW), Some(NOW / STEP - 1));
+        assert_eq!(verify(&totp, &totp.generate(NOW + STEP), 0, NOW), Some(NOW / STEP + 1));
+
+        assert_eq!(verify(&totp, &totp.generate(NOW - 2 * STEP), 0, NOW), None);
+        assert_eq!(verify(&totp, &totp.generate(NOW + 2 * STEP), 0, NOW), None);
+    }
+
+    #[test]
+    fn backup_code_normalization() {
+        let hash = hash_backup_code("a1b2c3d4e5");
+
+        assert_eq!(hash_backup_code("A1B2C-3D4E5"), hash);
+        assert_eq!(hash_backup_code(" a1b2c 3d4e5 "), hash);
+        assert_ne!(hash_backup_code("a1b2c3d4e6"), hash);
+    }
+}
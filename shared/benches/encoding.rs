@@ -0,0 +1,62 @@
+//! Time to encode and decode a batch of large notes with each encoding, run with `cargo bench -p shared`.
+//! Sizes are checked by the tests of `encoding`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use shared::{Encoding, Note, SentNotes};
+
+const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Cbor, Encoding::MessagePack];
+
+/// Notes of `size` bytes of ciphertext, the content doesn't change the encoded size
+fn sent_notes(count: usize, size: usize) -> SentNotes {
+    let notes = (0..count)
+        .map(|id| Note {
+            id: id as u32,
+            id_server: Some(id as u64),
+            title: String::new(),
+            content: (0..size).map(|i| (i * 31 + id) as u8).collect(),
+            nonce: vec![7; 12],
+            updated_at: 1_700_000_000,
+            key: None,
+            idempotency_key: None,
+        })
+        .collect();
+
+    SentNotes {
+        notes,
+        token: vec![1; 32],
+        username: "alice".to_string(),
+    }
+}
+
+fn encoding(c: &mut Criterion) {
+    for size in [64 * 1024, 1024 * 1024] {
+        let notes = sent_notes(8, size);
+
+        let mut group = c.benchmark_group(format!("encode {} KiB notes", size / 1024));
+        group.throughput(Throughput::Bytes((8 * size) as u64));
+
+        for encoding in ENCODINGS {
+            group.bench_with_input(BenchmarkId::from_parameter(encoding.content_type()), &notes, |b, notes| {
+                b.iter(|| encoding.encode(notes).unwrap())
+            });
+        }
+
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("decode {} KiB notes", size / 1024));
+        group.throughput(Throughput::Bytes((8 * size) as u64));
+
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(&notes).unwrap();
+
+            group.bench_with_input(BenchmarkId::from_parameter(encoding.content_type()), &bytes, |b, bytes| {
+                b.iter(|| encoding.decode::<SentNotes>(bytes).unwrap())
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, encoding);
+criterion_main!(benches);
@@ -0,0 +1,175 @@
+use std::{error::Error, fmt};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Encoding of a request or response body, selected with the `Content-Type` and `Accept` headers.
+/// JSON is the default and is kept for debugging, CBOR and MessagePack send bytes as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Encoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+            Encoding::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// First known encoding of a `Content-Type` or `Accept` header, parameters like `q` are ignored
+    pub fn from_header(value: &str) -> Option<Self> {
+        value.split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or("").trim())
+            .find_map(|media_type| match media_type {
+                "application/json" => Some(Encoding::Json),
+                "application/cbor" => Some(Encoding::Cbor),
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Encoding::MessagePack),
+                _ => None
+            })
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(EncodingError::new),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(EncodingError::new)?;
+
+                Ok(bytes)
+            },
+            //Structs are encoded as maps so fields can be added without breaking older peers
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(EncodingError::new),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(EncodingError::new),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(EncodingError::new),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(EncodingError::new),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EncodingError(String);
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed body: {}", self.0)
+    }
+}
+
+impl Error for EncodingError {}
+
+impl EncodingError {
+    fn new(err: impl fmt::Display) -> Self {
+        EncodingError(err.to_string())
+    }
+}
+
+/// Serde helper for byte fields: base64 in JSON, raw bytes in binary encodings.
+/// Arrays of numbers, sent by clients older than base64, are still accepted.
+pub mod bytes {
+    use std::fmt;
+
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserializer, Serializer, de::{self, SeqAccess, Visitor}};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&STANDARD.encode(bytes)),
+            false => serializer.serialize_bytes(bytes),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "bytes, a base64 string or an array of bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            STANDARD.decode(v).map_err(E::custom)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Note, SentNotes};
+
+    fn sent_notes(size: usize) -> SentNotes {
+        SentNotes {
+            notes: vec![Note {
+                id: 1,
+                id_server: Some(1),
+                title: String::new(),
+                content: (0..size).map(|i| (i * 31) as u8).collect(),
+                nonce: vec![7; 12],
+                updated_at: 1_700_000_000,
+                key: None,
+                idempotency_key: None,
+            }],
+            token: vec![1; 32],
+            username: "alice".to_string(),
+        }
+    }
+
+    #[test]
+    fn large_note_sizes() {
+        let size = 1024 * 1024;
+        let notes = sent_notes(size);
+
+        //Base64 adds a third to the ciphertext in JSON, binary encodings only add the field names
+        let json = Encoding::Json.encode(&notes).unwrap();
+        assert!(json.len() > size * 4 / 3);
+
+        for encoding in [Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&notes).unwrap();
+            assert!(bytes.len() < size + 1024, "{encoding:?} is {} bytes", bytes.len());
+
+            let decoded: SentNotes = encoding.decode(&bytes).unwrap();
+            assert_eq!(decoded.notes[0].content, notes.notes[0].content);
+        }
+    }
+
+    #[test]
+    fn json_byte_arrays() {
+        //Clients older than base64 sent bytes as arrays of numbers
+        let note: Note = Encoding::Json.decode(br#"{"id":1,"id_server":null,"title":"","content":[1,2,3],"nonce":"AAEC","updated_at":0,"key":null}"#).unwrap();
+
+        assert_eq!(note.content, vec![1, 2, 3]);
+        assert_eq!(note.nonce, vec![0, 1, 2]);
+    }
+}
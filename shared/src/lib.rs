@@ -1,7 +1,230 @@
-use chrono::NaiveDateTime;
+use std::{error::Error, fmt, str::FromStr};
+
 use serde::{Deserialize, Serialize};
 
+pub mod encoding;
+pub mod opaque;
+
+pub use encoding::Encoding;
+
+/// Version of the API between the client and the server, sent by both in the `PROTOCOL_HEADER` header.
+///
+/// Compatibility rules:
+/// - Inside a version, fields can only be added and must be `Option`, so a missing field is read as `None` by newer peers
+///   and an unknown field is ignored by older ones.
+/// - Byte fields of notes are base64 strings in JSON, arrays of numbers are still read.
+/// - Removing, renaming or changing the type of a field, or changing what a route does, needs a new version.
+///   Its routes are served under a new `/vN` prefix next to the old ones, and `PROTOCOL_MIN_VERSION` is raised
+///   once the old routes are removed.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version still served
+pub const PROTOCOL_MIN_VERSION: u32 = 1;
+pub const PROTOCOL_HEADER: &str = "x-notto-protocol";
+
+pub fn is_supported_protocol(version: u32) -> bool {
+    (PROTOCOL_MIN_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Shown instead of secrets (tokens, password hashes) when a type is logged
+pub const REDACTED: &str = "[REDACTED]";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KdfAlgorithm {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+/// Argon2 cost parameters used with a salt, stored next to it so they can be raised without locking users out.
+/// The default is what `Argon2::default()` used before parameters were stored.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    pub memory: u32, //KiB
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// true if any cost is lower than the one of `other`, the algorithm is not compared
+    pub fn is_weaker_than(&self, other: &KdfParams) -> bool {
+        self.memory < other.memory || self.iterations < other.iterations || self.parallelism < other.parallelism
+    }
+}
+
+/// Stored in databases as `argon2id$m=19456,t=2,p=1`
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let algorithm = match self.algorithm {
+            KdfAlgorithm::Argon2d => "argon2d",
+            KdfAlgorithm::Argon2i => "argon2i",
+            KdfAlgorithm::Argon2id => "argon2id",
+        };
+
+        write!(f, "{algorithm}$m={},t={},p={}", self.memory, self.iterations, self.parallelism)
+    }
+}
+
+#[derive(Debug)]
+pub struct KdfParamsError;
+
+impl fmt::Display for KdfParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed key derivation parameters")
+    }
+}
+
+impl Error for KdfParamsError {}
+
+impl FromStr for KdfParams {
+    type Err = KdfParamsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, costs) = s.split_once('$').ok_or(KdfParamsError)?;
+
+        let algorithm = match algorithm {
+            "argon2d" => KdfAlgorithm::Argon2d,
+            "argon2i" => KdfAlgorithm::Argon2i,
+            "argon2id" => KdfAlgorithm::Argon2id,
+            _ => return Err(KdfParamsError)
+        };
+
+        let mut params = KdfParams { algorithm, memory: 0, iterations: 0, parallelism: 0 };
+
+        for cost in costs.split(',') {
+            let (name, value) = cost.split_once('=').ok_or(KdfParamsError)?;
+            let value = value.parse().map_err(|_| KdfParamsError)?;
+
+            match name {
+                "m" => params.memory = value,
+                "t" => params.iterations = value,
+                "p" => params.parallelism = value,
+                _ => return Err(KdfParamsError)
+            }
+        }
+
+        match params.memory > 0 && params.iterations > 0 && params.parallelism > 0 {
+            true => Ok(params),
+            false => Err(KdfParamsError)
+        }
+    }
+}
+
+/// Who can create an account on an instance
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationPolicy {
+    Open,
+    Invite, //An invite code minted by an admin is required
+    Closed,
+}
+
+impl FromStr for RegistrationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationPolicy::Open),
+            "invite" => Ok(RegistrationPolicy::Invite),
+            "closed" => Ok(RegistrationPolicy::Closed),
+            _ => Err(format!("Unknown registration policy {s}"))
+        }
+    }
+}
+
+/// Metadata of an instance, fetched by the client before sending credentials
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceInfo {
+    pub version: String,
+    pub protocol_min: u32,
+    pub protocol_max: u32,
+    pub registration: RegistrationPolicy,
+    pub min_kdf: KdfParams,
+    pub max_note_size: u64, //Bytes of encrypted content
+}
+
+impl InstanceInfo {
+    pub fn is_compatible(&self) -> bool {
+        (self.protocol_min..=self.protocol_max).contains(&PROTOCOL_VERSION)
+    }
+}
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter,
+}
+
+impl UsernameError {
+    /// Stable identifier of the error, also sent by the server in `ApiError`
+    pub fn code(&self) -> &'static str {
+        match self {
+            UsernameError::TooShort => "username_too_short",
+            UsernameError::TooLong => "username_too_long",
+            UsernameError::InvalidCharacter => "username_invalid_character",
+        }
+    }
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::TooShort => write!(f, "Username must be at least {USERNAME_MIN_LENGTH} characters"),
+            UsernameError::TooLong => write!(f, "Username must be at most {USERNAME_MAX_LENGTH} characters"),
+            UsernameError::InvalidCharacter => write!(f, "Username can only contain letters, digits, '.', '-' and '_'"),
+        }
+    }
+}
+
+impl Error for UsernameError {}
+
+/// Trim and lowercase the username, then check it against the rules shared by the client and the server
+pub fn normalize_username(username: &str) -> Result<String, UsernameError> {
+    let username = username.trim().to_ascii_lowercase();
+
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')) {
+        return Err(UsernameError::InvalidCharacter);
+    }
+
+    match username.len() {
+        len if len < USERNAME_MIN_LENGTH => Err(UsernameError::TooShort),
+        len if len > USERNAME_MAX_LENGTH => Err(UsernameError::TooLong),
+        _ => Ok(username)
+    }
+}
+
+/// Body of the server errors the client can react to, `code` is stable
 #[derive(Deserialize, Serialize, Debug)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ApiError {}
+
+#[derive(Deserialize, Serialize)]
 pub struct User {
     pub id: Option<u32>,
     pub username: String,
@@ -17,6 +240,130 @@ pub struct User {
     pub salt_recovery_data: String,
     pub salt_server_auth: String,
     pub salt_server_recovery: String,
+    pub salt_server_mek: String,
+    pub stored_mek_hash: String, //Proves the knowledge of the master encryption key when recovering with the data recovery key
+    pub kdf_password: KdfParams, //Used with salt_auth, salt_server_auth and salt_data
+    pub kdf_recovery_auth: KdfParams, //Used with salt_recovery_auth and salt_server_recovery
+    pub kdf_recovery_data: KdfParams, //Used with salt_recovery_data and salt_server_mek
+    pub invite_code: Option<String>, //Only used when creating the account
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("stored_password_hash", &REDACTED)
+            .field("stored_recovery_hash", &REDACTED)
+            .field("encrypted_mek_password", &self.encrypted_mek_password)
+            .field("mek_password_nonce", &self.mek_password_nonce)
+            .field("encrypted_mek_recovery", &self.encrypted_mek_recovery)
+            .field("mek_recovery_nonce", &self.mek_recovery_nonce)
+            .field("salt_auth", &self.salt_auth)
+            .field("salt_data", &self.salt_data)
+            .field("salt_recovery_auth", &self.salt_recovery_auth)
+            .field("salt_recovery_data", &self.salt_recovery_data)
+            .field("salt_server_auth", &self.salt_server_auth)
+            .field("salt_server_recovery", &self.salt_server_recovery)
+            .field("salt_server_mek", &self.salt_server_mek)
+            .field("stored_mek_hash", &REDACTED)
+            .field("kdf_password", &self.kdf_password)
+            .field("kdf_recovery_auth", &self.kdf_recovery_auth)
+            .field("kdf_recovery_data", &self.kdf_recovery_data)
+            .field("invite_code", &self.invite_code.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct InviteParams {
+    pub max_uses: u32,
+    pub expires_at: Option<i64>,
+}
+
+/// Storage limits of a user, None for no limit
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct Quota {
+    pub max_bytes: Option<u64>, //Encrypted notes and attachment chunks
+    pub max_notes: Option<u64>,
+    pub max_note_size: u64, //Bytes of encrypted content of one note
+}
+
+/// Limits set by an admin for one user, None keeps the limit of the instance
+#[derive(Deserialize, Serialize, Debug)]
+pub struct QuotaOverride {
+    pub max_bytes: Option<u64>,
+    pub max_notes: Option<u64>,
+    pub max_note_size: Option<u64>,
+}
+
+/// Share of a quota above which clients warn the user
+pub const QUOTA_WARNING_RATIO: f64 = 0.9;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct Usage {
+    pub bytes: u64,
+    pub notes: u64,
+    pub quota: Quota,
+}
+
+impl Usage {
+    /// true when the bytes or the notes are above `QUOTA_WARNING_RATIO` of their limit
+    pub fn is_near_quota(&self) -> bool {
+        let near = |used: u64, max: Option<u64>| max.is_some_and(|max| used as f64 >= max as f64 * QUOTA_WARNING_RATIO);
+
+        near(self.bytes, self.quota.max_bytes) || near(self.notes, self.quota.max_notes)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UsageParams {
+    pub username: String,
+    pub token: String,
+}
+
+impl fmt::Debug for UsageParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UsageParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires_at: Option<i64>,
+    pub created_at: i64,
+}
+
+impl fmt::Debug for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Invite")
+            .field("code", &REDACTED)
+            .field("max_uses", &self.max_uses)
+            .field("uses", &self.uses)
+            .field("expires_at", &self.expires_at)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+/// Answer of a successful account creation, the client is logged in with the token
+#[derive(Deserialize, Serialize)]
+pub struct Signup {
+    pub token: Vec<u8>,
+}
+
+impl fmt::Debug for Signup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signup")
+            .field("token", &REDACTED)
+            .finish()
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -24,38 +371,349 @@ pub struct Note {
     pub id: u32,
     pub id_server: Option<u64>,
     pub title: String,
+    #[serde(with = "encoding::bytes")]
     pub content: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
     pub nonce: Vec<u8>,
     pub updated_at: i64,
+    pub key: Option<NoteKey>, //Set by the server on shared notes, their content is encrypted with this key instead of the master encryption key
+    pub idempotency_key: Option<String>, //UUID set by the client on notes without a server id, a batch sent again updates the notes it inserted
 }
 
+/// What the recipient of a shared note can do with it
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SharePermission {
+    Read,
+    Write, //The recipient can send new versions of the note
+}
+
+/// Stored in databases as `read` or `write`
+impl fmt::Display for SharePermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SharePermission::Read => write!(f, "read"),
+            SharePermission::Write => write!(f, "write"),
+        }
+    }
+}
+
+impl FromStr for SharePermission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(SharePermission::Read),
+            "write" => Ok(SharePermission::Write),
+            _ => Err(format!("Unknown share permission {s}"))
+        }
+    }
+}
+
+/// Key of a shared note, a new one is generated every time the shares of the note change
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NoteKey {
+    pub version: u32,
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+    pub owner: Option<PublicIdentity>, //None for the owner, the key is wrapped with its master encryption key. Otherwise the key is wrapped with a key agreed between the owner and the recipient.
+    pub permission: SharePermission,
+}
+
+/// Recipient of a shared note
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NoteShare {
+    pub username: String,
+    pub permission: SharePermission,
+}
+
+/// Note key wrapped for a recipient
 #[derive(Deserialize, Serialize, Debug)]
+pub struct ShareGrant {
+    pub username: String,
+    pub permission: SharePermission,
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+}
+
+/// Note encrypted with a new key and the recipients it is shared with, they replace the previous key and recipients
+#[derive(Deserialize, Serialize)]
+pub struct NoteRekey {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub title: String,
+    #[serde(with = "encoding::bytes")]
+    pub content: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub nonce: Vec<u8>,
+    pub updated_at: i64, //updated_at of the note the client re-encrypted, used to detect concurrent edits
+    pub version: u32, //Must be greater than the version of the current key
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>, //Wrapped with the master encryption key of the owner
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+    pub grants: Vec<ShareGrant>,
+    pub revisions: Option<Vec<RotatedRevision>>, //Revisions of the note encrypted with the new key, the others are deleted
+}
+
+impl fmt::Debug for NoteRekey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoteRekey")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("title", &self.title)
+            .field("updated_at", &self.updated_at)
+            .field("version", &self.version)
+            .field("grants", &self.grants)
+            .field("revisions", &self.revisions.as_ref().map(Vec::len))
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NoteShareParams {
+    pub username: String,
+    pub token: String,
+}
+
+impl fmt::Debug for NoteShareParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoteShareParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct SelectNoteParams {
     pub username: String,
     pub token: String,
-    pub updated_at: i64
+    pub updated_at: i64,
+    pub after_id: Option<u64>, //Server id of the last note received with `updated_at`, used to continue a page
+    pub limit: Option<u32>, //Notes per page, capped by the server
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl fmt::Debug for SelectNoteParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SelectNoteParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("updated_at", &self.updated_at)
+            .field("after_id", &self.after_id)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+/// Position of a note in the notes of a user, ordered by `updated_at` then server id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NoteCursor {
+    pub updated_at: i64,
+    pub id: u64,
+}
+
+/// Page of notes, `next` is the cursor to ask the following page or None on the last one
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NotePage {
+    pub notes: Vec<Note>,
+    pub next: Option<NoteCursor>,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct SentNotes {
     pub notes: Vec<Note>,
     pub token: Vec<u8>,
     pub username: String,
 }
 
+impl fmt::Debug for SentNotes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SentNotes")
+            .field("notes", &self.notes)
+            .field("token", &REDACTED)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+/// Result of a sent note, a rejected note is left untouched on the server
 #[derive(Deserialize, Serialize, Debug)]
 pub enum NoteStatus {
     Ok,
-    Conflict,
+    Conflict, //The note is more recent on the server
+    Forbidden, //The note doesn't exist or belongs to another user
+    TooLarge, //The content is larger than the `max_note_size` of the user
+    QuotaExceeded, //Storing the note would exceed the bytes or the notes allowed to the user
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SentNotesResult {
     pub id_client: u32,
-    pub id_server: u64, 
+    pub id_server: u64, //0 when a new note has been rejected
     pub status: NoteStatus
 }
 
+/// Previous version of a note kept by the server when the note is updated
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NoteRevision {
+    pub id: u64,
+    pub id_note: u64, //Server id of the note
+    pub updated_at: i64, //Date of the version
+    pub archived_at: i64, //Date it has been replaced
+    pub size: u64, //Bytes of encrypted content
+    pub key_version: Option<u32>, //Version of the note key it is encrypted with, None for the master encryption key of the owner
+}
+
+/// Encrypted content of a revision, encrypted with the master encryption key or with the key of the note given by `key_version`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NoteRevisionContent {
+    pub id: u64,
+    pub id_note: u64,
+    pub title: String,
+    #[serde(with = "encoding::bytes")]
+    pub content: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub nonce: Vec<u8>,
+    pub updated_at: i64,
+    pub key_version: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NoteRevisionParams {
+    pub username: String,
+    pub token: String,
+}
+
+impl fmt::Debug for NoteRevisionParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoteRevisionParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// Size of the plain text chunks an attachment is split into before encryption
+pub const ATTACHMENT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Largest encrypted chunk accepted, the nonce and the authentication tag are added to the plain text
+pub const ATTACHMENT_CHUNK_MAX_SIZE: usize = ATTACHMENT_CHUNK_SIZE + 12 + 16;
+
+/// File attached to a note. Its chunks are uploaded apart and addressed by the sha256 of their encrypted bytes.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Attachment {
+    pub id: Option<u64>, //None until stored by the server
+    pub id_note: u64, //Server id of the note
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>, //Attachment key, wrapped with the master encryption key
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub metadata: Vec<u8>, //Name, media type and size, encrypted with the attachment key
+    #[serde(with = "encoding::bytes")]
+    pub metadata_nonce: Vec<u8>,
+    pub chunks: Vec<String>, //Hex sha256 of every encrypted chunk, in order
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SentAttachment {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub attachment: Attachment,
+}
+
+impl fmt::Debug for SentAttachment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SentAttachment")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("attachment", &self.attachment)
+            .finish()
+    }
+}
+
+/// Query of the attachment routes, `id_note` filters the listed attachments
+#[derive(Deserialize, Serialize)]
+pub struct AttachmentParams {
+    pub username: String,
+    pub token: String,
+    pub id_note: Option<u64>,
+}
+
+impl fmt::Debug for AttachmentParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttachmentParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("id_note", &self.id_note)
+            .finish()
+    }
+}
+
+/// Public and encrypted private keys of an account, X25519 to agree on keys with other users and Ed25519 to sign.
+/// The X25519 public key is signed with the Ed25519 key so both are bound to the same identity.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Identity {
+    #[serde(with = "encoding::bytes")]
+    pub x25519_public: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub ed25519_public: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub signature: Vec<u8>, //Ed25519 signature of the X25519 public key
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_keys: Vec<u8>, //X25519 then Ed25519 private keys, encrypted with the master encryption key
+    #[serde(with = "encoding::bytes")]
+    pub keys_nonce: Vec<u8>,
+}
+
+/// Public keys of another user
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PublicIdentity {
+    pub username: String,
+    #[serde(with = "encoding::bytes")]
+    pub x25519_public: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub ed25519_public: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub signature: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SentIdentity {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub identity: Identity,
+}
+
+impl fmt::Debug for SentIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SentIdentity")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct IdentityParams {
+    pub username: String,
+    pub token: String,
+}
+
+impl fmt::Debug for IdentityParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct LoginRequestParams {
     pub username: String,
@@ -65,18 +723,461 @@ pub struct LoginRequestParams {
 pub struct LoginRequest {
     pub salt_auth: String,
     pub salt_server_auth: String,
+    pub kdf_password: KdfParams,
+    pub opaque: Option<bool>, //true when the account logs in with OPAQUE, None from servers without it
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize)]
 pub struct LoginParams {
     pub username: String,
     pub login_hash: String,
+    pub totp_code: Option<String>, //TOTP code or backup code, required when two-factor authentication is enabled
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl fmt::Debug for LoginParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginParams")
+            .field("username", &self.username)
+            .field("login_hash", &REDACTED)
+            .field("totp_code", &self.totp_code.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct Login {
     pub salt_data: String,
     pub encrypted_mek_password: Vec<u8>,
     pub mek_password_nonce: Vec<u8>,
     pub token: Vec<u8>,
+    pub kdf_password: KdfParams,
+    pub min_kdf: KdfParams, //Minimum required by the instance, the password must be derived again if kdf_password is weaker
+}
+
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("salt_data", &self.salt_data)
+            .field("encrypted_mek_password", &self.encrypted_mek_password)
+            .field("mek_password_nonce", &self.mek_password_nonce)
+            .field("token", &REDACTED)
+            .field("kdf_password", &self.kdf_password)
+            .field("min_kdf", &self.min_kdf)
+            .finish()
+    }
+}
+
+/// First message of an OPAQUE registration, sent once logged in with the login hash
+#[derive(Deserialize, Serialize)]
+pub struct OpaqueRegistrationStart {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub registration_request: Vec<u8>,
+}
+
+impl fmt::Debug for OpaqueRegistrationStart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpaqueRegistrationStart")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpaqueRegistrationResponse {
+    pub registration_response: Vec<u8>,
+}
+
+/// Record of the password, it replaces the stored password hash so the login hash can't be used anymore
+#[derive(Deserialize, Serialize)]
+pub struct OpaqueRegistrationFinish {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub login_hash: String,
+    pub registration_upload: Vec<u8>,
+}
+
+impl fmt::Debug for OpaqueRegistrationFinish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpaqueRegistrationFinish")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("login_hash", &REDACTED)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpaqueLoginStart {
+    pub username: String,
+    pub credential_request: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpaqueLoginResponse {
+    pub id_login: u64, //Login kept by the server until it is finished
+    pub credential_response: Vec<u8>,
+}
+
+/// Last message of an OPAQUE login, it proves the password
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpaqueProof {
+    pub id_login: u64,
+    pub credential_finalization: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OpaqueLoginFinish {
+    pub username: String,
+    pub proof: OpaqueProof,
+    pub totp_code: Option<String>, //TOTP code or backup code, required when two-factor authentication is enabled
+}
+
+impl fmt::Debug for OpaqueLoginFinish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpaqueLoginFinish")
+            .field("username", &self.username)
+            .field("proof", &self.proof)
+            .field("totp_code", &self.totp_code.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+/// Answer of a finished OPAQUE login, the token is derived from the session key with `opaque::session_token`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct OpaqueLogin {
+    pub salt_data: String,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+    pub kdf_password: KdfParams,
+    pub min_kdf: KdfParams,
+}
+
+impl OpaqueLogin {
+    pub fn into_login(self, token: Vec<u8>) -> Login {
+        Login {
+            salt_data: self.salt_data,
+            encrypted_mek_password: self.encrypted_mek_password,
+            mek_password_nonce: self.mek_password_nonce,
+            token,
+            kdf_password: self.kdf_password,
+            min_kdf: self.min_kdf,
+        }
+    }
+}
+
+/// Password derived again with stronger parameters, every password salt is renewed
+#[derive(Deserialize, Serialize)]
+pub struct KdfUpgrade {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub login_hash: String, //Hash of the password with the current parameters, it replaces every password salt. Empty with `opaque`
+    pub opaque: Option<OpaqueProof>, //Proof of the password of accounts using OPAQUE
+    pub registration_upload: Option<Vec<u8>>, //OPAQUE record of the password stretched with `kdf_password`, required with `opaque`
+    pub kdf_password: KdfParams,
+    pub salt_auth: String,
+    pub salt_server_auth: String,
+    pub stored_password_hash: String,
+    pub salt_data: String,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+}
+
+impl fmt::Debug for KdfUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KdfUpgrade")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("login_hash", &REDACTED)
+            .field("opaque", &self.opaque)
+            .field("registration_upload", &self.registration_upload)
+            .field("kdf_password", &self.kdf_password)
+            .field("salt_auth", &self.salt_auth)
+            .field("salt_server_auth", &self.salt_server_auth)
+            .field("stored_password_hash", &REDACTED)
+            .field("salt_data", &self.salt_data)
+            .field("encrypted_mek_password", &self.encrypted_mek_password)
+            .field("mek_password_nonce", &self.mek_password_nonce)
+            .finish()
+    }
+}
+
+/// The password must be proven again to delete the account
+#[derive(Deserialize, Serialize)]
+pub struct DeleteUserParams {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub login_hash: String, //Empty with `opaque`
+    pub opaque: Option<OpaqueProof>, //Proof of the password of accounts using OPAQUE
+}
+
+impl fmt::Debug for DeleteUserParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeleteUserParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("login_hash", &REDACTED)
+            .field("opaque", &self.opaque)
+            .finish()
+    }
+}
+
+/// Proof of an account deletion, kept by the user for data-protection requests
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DeletionReceipt {
+    pub username: String,
+    pub deleted_at: i64,
+    pub deleted_notes: u64,
+    pub deleted_tokens: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TotpEnrollParams {
+    pub username: String,
+    pub token: Vec<u8>,
+}
+
+impl fmt::Debug for TotpEnrollParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpEnrollParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .finish()
+    }
+}
+
+/// TOTP secret generated by the server, not used for login until a code is confirmed
+#[derive(Deserialize, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String, //Base32, for manual entry
+    pub otpauth_uri: String, //Shown as a QR code
+}
+
+impl fmt::Debug for TotpEnrollment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpEnrollment")
+            .field("secret", &REDACTED)
+            .field("otpauth_uri", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TotpConfirmParams {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub code: String,
+}
+
+impl fmt::Debug for TotpConfirmParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpConfirmParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("code", &REDACTED)
+            .finish()
+    }
+}
+
+/// Single-use codes accepted instead of a TOTP code, only sent once when the enrollment is confirmed
+#[derive(Deserialize, Serialize)]
+pub struct TotpBackupCodes {
+    pub codes: Vec<String>,
+}
+
+impl fmt::Debug for TotpBackupCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TotpBackupCodes")
+            .field("codes", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MekRotationParams {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub login_hash: String, //The new key is wrapped with the password, it is checked before the rotation starts. Empty with `opaque`
+    pub opaque: Option<OpaqueProof>, //Proof of the password of accounts using OPAQUE
+}
+
+impl fmt::Debug for MekRotationParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MekRotationParams")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("login_hash", &REDACTED)
+            .field("opaque", &self.opaque)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct MekRotation {
+    pub id: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotatedNote {
+    pub id_server: u64,
+    #[serde(with = "encoding::bytes")]
+    pub content: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub nonce: Vec<u8>,
+    pub updated_at: i64, //updated_at of the note the client re-encrypted, used to detect concurrent edits
+    pub revisions: Option<Vec<RotatedRevision>>, //Revisions of the note encrypted with the master encryption key
+}
+
+/// A revision of a note encrypted again with a new key
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotatedRevision {
+    pub id: u64,
+    #[serde(with = "encoding::bytes")]
+    pub content: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RotatedNotes {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub id_rotation: u64,
+    pub notes: Vec<RotatedNote>,
+}
+
+impl fmt::Debug for RotatedNotes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotatedNotes")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("id_rotation", &self.id_rotation)
+            .field("notes", &self.notes)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MekRotationCommit {
+    pub username: String,
+    pub token: Vec<u8>,
+    pub id_rotation: u64,
+    pub salt_data: String,
+    pub encrypted_mek_password: Vec<u8>,
+    pub mek_password_nonce: Vec<u8>,
+    pub salt_recovery_data: String,
+    pub encrypted_mek_recovery: Vec<u8>,
+    pub mek_recovery_nonce: Vec<u8>,
+    pub salt_server_mek: String,
+    pub stored_mek_hash: String,
+    pub kdf_recovery_data: KdfParams,
+    pub attachment_keys: Option<Vec<RotatedAttachmentKey>>, //Every attachment key of the user, wrapped with the new key
+    pub identity_keys: Option<RotatedIdentityKeys>, //Private keys of the identity, encrypted with the new key
+    pub note_keys: Option<Vec<RotatedNoteKey>>, //Keys of every shared note of the user, wrapped with the new key
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotatedIdentityKeys {
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_keys: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub keys_nonce: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotatedNoteKey {
+    pub id_server: u64,
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RotatedAttachmentKey {
+    pub id_server: u64,
+    #[serde(with = "encoding::bytes")]
+    pub encrypted_key: Vec<u8>,
+    #[serde(with = "encoding::bytes")]
+    pub key_nonce: Vec<u8>,
+}
+
+impl fmt::Debug for MekRotationCommit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MekRotationCommit")
+            .field("username", &self.username)
+            .field("token", &REDACTED)
+            .field("id_rotation", &self.id_rotation)
+            .field("salt_data", &self.salt_data)
+            .field("encrypted_mek_password", &self.encrypted_mek_password)
+            .field("mek_password_nonce", &self.mek_password_nonce)
+            .field("salt_recovery_data", &self.salt_recovery_data)
+            .field("encrypted_mek_recovery", &self.encrypted_mek_recovery)
+            .field("mek_recovery_nonce", &self.mek_recovery_nonce)
+            .field("salt_server_mek", &self.salt_server_mek)
+            .field("stored_mek_hash", &REDACTED)
+            .field("kdf_recovery_data", &self.kdf_recovery_data)
+            .field("attachment_keys", &self.attachment_keys)
+            .field("identity_keys", &self.identity_keys)
+            .field("note_keys", &self.note_keys)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct DataRecoveryRequest {
+    pub salt_recovery_data: String,
+    pub encrypted_mek_recovery: Vec<u8>,
+    pub mek_recovery_nonce: Vec<u8>,
+    pub salt_server_mek: String,
+    pub kdf_recovery_data: KdfParams,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DataRecoveryParams {
+    pub username: String,
+    pub mek_hash: String,
+    pub totp_code: Option<String>, //TOTP code or backup code, required when two-factor authentication is enabled
+}
+
+impl fmt::Debug for DataRecoveryParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataRecoveryParams")
+            .field("username", &self.username)
+            .field("mek_hash", &REDACTED)
+            .field("totp_code", &self.totp_code.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kdf_params_round_trip() {
+        let kdf = KdfParams { algorithm: KdfAlgorithm::Argon2i, memory: 65536, iterations: 3, parallelism: 4 };
+
+        assert_eq!(kdf.to_string(), "argon2i$m=65536,t=3,p=4");
+        assert_eq!(kdf.to_string().parse::<KdfParams>().unwrap(), kdf);
+        assert_eq!(KdfParams::default().to_string().parse::<KdfParams>().unwrap(), KdfParams::default());
+    }
+
+    #[test]
+    fn malformed_kdf_params() {
+        for kdf in ["", "argon2id", "scrypt$m=1,t=1,p=1", "argon2id$m=1,t=1", "argon2id$m=1,t=0,p=1", "argon2id$m=1,t=1,p=1,x=1", "argon2id$m=-1,t=1,p=1"] {
+            assert!(kdf.parse::<KdfParams>().is_err(), "{kdf} should be refused");
+        }
+    }
+
+    #[test]
+    fn weaker_kdf_params() {
+        let min = KdfParams::default();
+
+        assert!(!min.is_weaker_than(&min));
+        assert!(KdfParams { iterations: 1, ..min.clone() }.is_weaker_than(&min));
+        assert!(KdfParams { memory: 1024, iterations: 10, ..min.clone() }.is_weaker_than(&min));
+        assert!(!KdfParams { memory: min.memory * 2, ..min.clone() }.is_weaker_than(&min));
+    }
 }
@@ -0,0 +1,30 @@
+//! OPAQUE login: the server only stores a record that can't be used to log in, and the password never leaves the client.
+//! Both sides must use the same cipher suite and context.
+
+use opaque_ke::{Ristretto255, TripleDh, argon2::Argon2};
+use sha2::{Digest, Sha256, Sha512};
+
+pub use opaque_ke;
+/// Random generator of the `rand_core` version used by `opaque_ke`
+pub use rand_core::OsRng;
+
+/// Primitives of the protocol, changing them invalidates every record
+pub struct Suite;
+
+impl opaque_ke::CipherSuite for Suite {
+    type OprfCs = Ristretto255;
+    type KeyExchange = TripleDh<Ristretto255, Sha512>;
+    type Ksf = Argon2<'static>; //Configured by the client with the `kdf_password` of the account
+}
+
+/// Bound to the key exchange so a login can't be replayed against another protocol
+pub const CONTEXT: &[u8] = b"notto opaque login v1";
+
+/// Token of the session opened by an OPAQUE login, derived from the session key by both sides so it's never sent
+pub fn session_token(session_key: &[u8]) -> Vec<u8> {
+    Sha256::new()
+        .chain_update(b"notto session token")
+        .chain_update(session_key)
+        .finalize()
+        .to_vec()
+}